@@ -0,0 +1,421 @@
+//! Export of a checkpoint and its parent chain to a tar archive, and import
+//! into another store.
+//!
+//! The archive holds `hcriu-export.toml`, which lists the chain, and one
+//! directory per checkpoint with its metadata, manifest, signature, logs and
+//! images. Images are written so the archive needs nothing from the store:
+//! encrypted images are decrypted and chunked ones rebuilt, the manifest and
+//! signature are redone for those. `.tar.zst` archives are zstd compressed.
+//!
+//! Import unpacks into a staging dir of the store, checks the chain against
+//! its manifests and signatures, then moves the checkpoints in oldest first.
+//! A checkpoint already in the store is reused, an id taken by a different
+//! one gets a new id. Where the checkpoint came from is kept in its metadata.
+//!
+//! `dump --stream` and `restore --stream` use the same format for a single
+//! checkpoint, piped instead of kept in a store.
+
+use crate::crypt::ENCRYPTION_FILE;
+use crate::error::{Error, IoContext, Result};
+use crate::manifest::Manifest;
+use crate::pack::{self, Packing};
+use crate::store::{CheckpointStore, STAGING_PREFIX};
+use crate::utils::{CheckpointMeta, CheckpointStatus, Provenance};
+use crate::{compress, fsck, revision, sign};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const EXPORT_FILE: &str = "hcriu-export.toml";
+
+/// Bumped when archives change in a way older versions can't import.
+const FORMAT: u32 = 1;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+struct ExportInfo {
+  format: u32,
+  /// the exported checkpoint, first of `checkpoints`
+  checkpoint_id: String,
+  /// the chain, from the exported checkpoint to its oldest parent
+  checkpoints: Vec<String>,
+  host: String,
+  exported_at: String,
+}
+
+/// Write the checkpoint `checkpoint_id` names, see [`crate::revision`], and
+/// its parents to `output`, compressed when its name ends in `.zst`.
+pub fn handle_export(store: &CheckpointStore, checkpoint_id: &str, output: &Path) -> Result<()> {
+  let meta = revision::resolve(store, checkpoint_id)?;
+  if meta.status != CheckpointStatus::Complete {
+    return Err(Error::Incomplete(meta.checkpoint_id));
+  }
+  let chain = store.parent_chain(&meta.checkpoint_id)?;
+  // a chain with a missing parent can't be restored on the other side either
+  if let Some(parent_id) = chain.last().and_then(|c| c.parent_id.clone()) {
+    return Err(Error::CheckpointNotFound(parent_id));
+  }
+
+  let mut needed = 0;
+  for checkpoint in &chain {
+    needed += pack::logical_size(&store.checkpoint_dir(&checkpoint.checkpoint_id))?;
+  }
+  let encrypted = store.root().join(ENCRYPTION_FILE).exists();
+  let scratch = pack::scratch_dir("hcriu-export-", needed, encrypted)?;
+  for checkpoint in &chain {
+    let source = store.checkpoint_dir(&checkpoint.checkpoint_id);
+    let target = scratch.path().join(&checkpoint.checkpoint_id);
+    let changed = pack::copy_portable(store, &source, &target)?;
+    for entry in std::fs::read_dir(&source).with_path(&source)? {
+      let path = entry.with_path(&source)?.path();
+      if path.is_file() {
+        let dest = target.join(path.file_name().unwrap_or_default());
+        std::fs::copy(&path, &dest).with_path(&dest)?;
+      }
+    }
+    if changed {
+      Manifest::build(&target)?.save(&target)?;
+      sign::resign(store, &target, checkpoint)?;
+    }
+  }
+
+  let checkpoints = chain.iter().map(|c| c.checkpoint_id.clone()).collect();
+  write_archive(scratch.path(), checkpoints, output).inspect_err(|_| {
+    let _ = std::fs::remove_file(output);
+  })?;
+  println!(
+    "Exported checkpoint {} with {} parents to {}",
+    meta.checkpoint_id,
+    chain.len() - 1,
+    output.display()
+  );
+  if encrypted {
+    eprintln!("The archive holds the images decrypted, keep it somewhere safe");
+  }
+  Ok(())
+}
+
+/// Bring the checkpoints of an archive written by `hcriu export` into the
+/// store.
+pub fn handle_import(store: &CheckpointStore, archive: &Path) -> Result<()> {
+  let _lock = store.lock()?;
+  // on the same filesystem so checkpoints are moved in by rename, and left
+  // for fsck like a staging dir should import die half way
+  let scratch = tempfile::Builder::new()
+    .prefix(&format!("{}import-", STAGING_PREFIX))
+    .tempdir_in(store.root())
+    .with_path(store.root())?;
+  let file = File::open(archive).with_path(archive)?;
+  unpack(file, scratch.path(), archive)?;
+  let (info, chain) = check_archive(archive, scratch.path(), true)?;
+
+  let imported_at = Utc::now().to_string();
+  let mut new_ids = HashMap::new();
+  for meta in chain.iter().rev() {
+    let old_id = meta.checkpoint_id.clone();
+    let source = scratch.path().join(&old_id);
+    let new_id = match store.get(&old_id) {
+      Ok(existing) if same_dump(&existing, meta) => {
+        println!("Checkpoint {} is already in the store", old_id);
+        new_ids.insert(old_id.clone(), old_id);
+        continue;
+      }
+      Ok(_) => fresh_id(&old_id, &imported_at),
+      Err(Error::CheckpointNotFound(_)) => old_id.clone(),
+      Err(e) => return Err(e),
+    };
+
+    let mut imported = meta.clone();
+    imported.checkpoint_id = new_id.clone();
+    imported.parent_id = meta.parent_id.as_ref().map(|p| new_ids[p].clone());
+    imported.pinned = false;
+    imported.provenance = Some(Provenance {
+      original_id: old_id.clone(),
+      host: info.host.clone(),
+      exported_at: info.exported_at.clone(),
+      imported_at: imported_at.clone(),
+      archive: archive
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned(),
+    });
+    let relinked = imported.parent_id != meta.parent_id;
+    if let (Some(old), Some(new)) = (&meta.parent_id, &imported.parent_id)
+      && relinked
+    {
+      relink_parents(&source, old, new)?;
+    }
+
+    // images are packed the way this store packs new dumps
+    let packing = Packing::for_dump(store, &meta.options)?;
+    let repacked = !packing.is_plain();
+    pack::pack_checkpoint(&source, &packing)?;
+    // the manifest records the targets of the links as well
+    if repacked || relinked {
+      Manifest::build(&source)?.save(&source)?;
+    }
+    if repacked || relinked || imported.checkpoint_id != old_id {
+      sign::resign(store, &source, &imported)?;
+    }
+    imported.save(&source.join("meta.toml"))?;
+
+    let target = store.checkpoint_dir(&new_id);
+    std::fs::rename(&source, &target).with_path(&source)?;
+    if new_id == old_id {
+      println!("Imported checkpoint {}", new_id);
+    } else {
+      println!(
+        "Imported checkpoint {} as {}, the id was taken",
+        old_id, new_id
+      );
+    }
+    new_ids.insert(old_id, new_id);
+  }
+  Ok(())
+}
+
+/// Write the checkpoints in `dir`, newest first, as an archive to `writer`.
+/// `output` names the archive in errors.
+pub(crate) fn write_checkpoints<W: Write>(
+  dir: &Path,
+  checkpoints: Vec<String>,
+  output: &Path,
+  writer: W,
+) -> Result<W> {
+  let info = ExportInfo {
+    format: FORMAT,
+    checkpoint_id: checkpoints.first().cloned().unwrap_or_default(),
+    checkpoints,
+    host: hostname(),
+    exported_at: Utc::now().to_string(),
+  };
+  let info_path = dir.join(EXPORT_FILE);
+  let text = toml::to_string(&info).map_err(|e| Error::CorruptMeta {
+    path: info_path.clone(),
+    message: e.to_string(),
+  })?;
+  std::fs::write(&info_path, text).with_path(&info_path)?;
+
+  let mut builder = tar::Builder::new(writer);
+  // `parent` links stay links, they are relative to the archive
+  builder.follow_symlinks(false);
+  builder
+    .append_path_with_name(&info_path, EXPORT_FILE)
+    .with_path(output)?;
+  for checkpoint_id in &info.checkpoints {
+    builder
+      .append_dir_all(checkpoint_id, dir.join(checkpoint_id))
+      .with_path(output)?;
+  }
+  builder.into_inner().with_path(output)
+}
+
+/// Unpack the archive read from `input` into `dir` and check it, see
+/// [`check_archive`], the images against their manifests and signatures
+/// only with `verify`. `source` names the archive in errors. Returns the
+/// chain, newest first.
+pub(crate) fn read_checkpoints(
+  input: impl Read,
+  dir: &Path,
+  source: &Path,
+  verify: bool,
+) -> Result<Vec<CheckpointMeta>> {
+  unpack(input, dir, source)?;
+  Ok(check_archive(source, dir, verify)?.1)
+}
+
+fn write_archive(dir: &Path, checkpoints: Vec<String>, output: &Path) -> Result<()> {
+  let file = File::create(output).with_path(output)?;
+  if output.extension().is_some_and(|ext| ext == "zst") {
+    let encoder = zstd::Encoder::new(file, compress::DEFAULT_LEVEL).with_path(output)?;
+    write_checkpoints(dir, checkpoints, output, encoder)?
+      .finish()
+      .with_path(output)?;
+  } else {
+    write_checkpoints(dir, checkpoints, output, BufWriter::new(file))?
+      .flush()
+      .with_path(output)?;
+  }
+  Ok(())
+}
+
+/// Unpack an archive into `dir`, zstd compressed or not. Entries can't land
+/// outside `dir`.
+fn unpack(input: impl Read, dir: &Path, source: &Path) -> Result<()> {
+  let mut input = BufReader::new(input);
+  let compressed = input
+    .fill_buf()
+    .is_ok_and(|head| head.starts_with(&ZSTD_MAGIC));
+  if compressed {
+    let decoder = zstd::Decoder::with_buffer(input).with_path(source)?;
+    tar::Archive::new(decoder).unpack(dir).with_path(source)
+  } else {
+    tar::Archive::new(input).unpack(dir).with_path(source)
+  }
+}
+
+/// Check an unpacked archive: the chain is complete and nothing else is in
+/// it and `parent` links stay within the chain. With `verify`, images have
+/// to match their manifests and signatures have to match too. Returns the
+/// chain, newest first.
+fn check_archive(
+  archive: &Path,
+  dir: &Path,
+  verify: bool,
+) -> Result<(ExportInfo, Vec<CheckpointMeta>)> {
+  let invalid = |message: String| Error::InvalidArchive {
+    path: archive.to_path_buf(),
+    message,
+  };
+  let info_path = dir.join(EXPORT_FILE);
+  let text = std::fs::read_to_string(&info_path)
+    .map_err(|_| invalid(format!("{} is missing", EXPORT_FILE)))?;
+  let info: ExportInfo = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+  if info.format != FORMAT {
+    return Err(invalid(format!("unsupported format {}", info.format)));
+  }
+  if info.checkpoints.first() != Some(&info.checkpoint_id) {
+    return Err(invalid("the exported checkpoint is not first".to_string()));
+  }
+
+  for entry in std::fs::read_dir(dir).with_path(dir)? {
+    let name = entry
+      .with_path(dir)?
+      .file_name()
+      .to_string_lossy()
+      .into_owned();
+    if name != EXPORT_FILE && !info.checkpoints.contains(&name) {
+      return Err(invalid(format!("unexpected entry {}", name)));
+    }
+  }
+
+  let root = dir.canonicalize().with_path(dir)?;
+  let mut chain = Vec::new();
+  for (i, checkpoint_id) in info.checkpoints.iter().enumerate() {
+    let checkpoint_dir = dir.join(checkpoint_id);
+    if !fsck::is_checkpoint_id(checkpoint_id) || !checkpoint_dir.join("image").is_dir() {
+      return Err(invalid(format!("{} is not a checkpoint", checkpoint_id)));
+    }
+    let meta = CheckpointMeta::load(&checkpoint_dir.join("meta.toml"))?;
+    if meta.checkpoint_id != *checkpoint_id || meta.status != CheckpointStatus::Complete {
+      return Err(invalid(format!(
+        "metadata of {} does not match",
+        checkpoint_id
+      )));
+    }
+    if meta.parent_id.as_ref() != info.checkpoints.get(i + 1) {
+      return Err(invalid(format!(
+        "parent chain of {} is broken",
+        checkpoint_id
+      )));
+    }
+    check_links(&checkpoint_dir, &root).map_err(|path| {
+      invalid(format!(
+        "{} points outside the checkpoint chain",
+        path.display()
+      ))
+    })?;
+
+    if verify {
+      verify_images(&checkpoint_dir, checkpoint_id)?;
+      // before anything is rewritten and signed again
+      sign::verify_dir(&checkpoint_dir, &meta)?;
+    }
+    chain.push(meta);
+  }
+  Ok((info, chain))
+}
+
+/// Fail when the images of an unpacked checkpoint differ from its manifest.
+fn verify_images(checkpoint_dir: &Path, checkpoint_id: &str) -> Result<()> {
+  match Manifest::load(checkpoint_dir)? {
+    Some(manifest) => {
+      let mismatches = manifest.verify(checkpoint_dir)?;
+      if !mismatches.is_empty() {
+        return Err(Error::Tampered {
+          checkpoint_id: checkpoint_id.to_string(),
+          mismatches: mismatches.iter().map(|m| m.to_string()).collect(),
+        });
+      }
+    }
+    None => eprintln!(
+      "Checkpoint {} has no manifest, its images are not verified",
+      checkpoint_id
+    ),
+  }
+  Ok(())
+}
+
+/// Fail with the first symlink that is not a `parent` link resolving under
+/// `root`.
+fn check_links(dir: &Path, root: &Path) -> std::result::Result<(), PathBuf> {
+  let entries = std::fs::read_dir(dir).map_err(|_| dir.to_path_buf())?;
+  for entry in entries {
+    let path = entry.map_err(|_| dir.to_path_buf())?.path();
+    let file_type = path
+      .symlink_metadata()
+      .map_err(|_| path.clone())?
+      .file_type();
+    if file_type.is_dir() {
+      check_links(&path, root)?;
+    } else if file_type.is_symlink() {
+      let inside = path.file_name().is_some_and(|name| name == "parent")
+        && path
+          .canonicalize()
+          .is_ok_and(|target| target.starts_with(root));
+      if !inside {
+        return Err(path);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Point the `parent` links of a checkpoint at its parent's new id.
+fn relink_parents(checkpoint_dir: &Path, old_id: &str, new_id: &str) -> Result<()> {
+  for dir in crate::utils::IMAGE_DIRS {
+    let mut dirs = vec![checkpoint_dir.join(dir)];
+    while let Some(dir) = dirs.pop() {
+      let Ok(entries) = std::fs::read_dir(&dir) else {
+        continue;
+      };
+      for entry in entries {
+        let path = entry.with_path(&dir)?.path();
+        if path.is_symlink() {
+          let target = std::fs::read_link(&path).with_path(&path)?;
+          let target = target.to_string_lossy().replace(old_id, new_id);
+          std::fs::remove_file(&path).with_path(&path)?;
+          std::os::unix::fs::symlink(target, &path).with_path(&path)?;
+        } else if path.is_dir() {
+          dirs.push(path);
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Both are the same dump, ids are derived from these fields so an equal id
+/// with other fields is a different checkpoint.
+fn same_dump(a: &CheckpointMeta, b: &CheckpointMeta) -> bool {
+  a.pid == b.pid && a.cmd == b.cmd && a.dump_time == b.dump_time && a.parent_id == b.parent_id
+}
+
+fn fresh_id(checkpoint_id: &str, imported_at: &str) -> String {
+  format!(
+    "{:x}",
+    Sha256::digest(format!("{}{}", checkpoint_id, imported_at))
+  )
+}
+
+fn hostname() -> String {
+  nix::unistd::gethostname()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_else(|_| "unknown".to_string())
+}
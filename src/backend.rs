@@ -0,0 +1,677 @@
+//! The ways hcriu can drive CRIU.
+//!
+//! Dump and restore describe what they want as a request and hand it to a
+//! [`CriuBackend`]: the `criu` command line tool, the rust-criu RPC client, or
+//! a fake that writes synthetic images and needs neither root nor CRIU.
+
+use crate::error::{Error, IoContext, Result};
+use crate::image::{
+  DumpStats, IMG_COMMON_MAGIC, PAGEMAP_MAGIC, PE_PARENT, PE_PRESENT, Pagemap, PagemapEntry,
+};
+use crate::options::{DumpOptions, RestoreOptions};
+use clap::ValueEnum;
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::unistd::setsid;
+use rust_criu::Criu;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::BorrowedFd;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+/// Socket CRIU's lazy-pages daemon creates in its work dir.
+const LAZY_PAGES_SOCKET: &str = "lazy-pages.socket";
+/// How long the daemon may take to get ready.
+const LAZY_PAGES_STARTUP: Duration = Duration::from_secs(10);
+
+/// One pre-dump or dump of a process tree.
+#[derive(Debug, Clone)]
+pub struct DumpRequest<'a> {
+  pub pid: i32,
+  /// checkpoint dir, log files are relative to it
+  pub work_dir: &'a Path,
+  pub image_dir: &'a Path,
+  /// parent images, relative to `image_dir`
+  pub parent_img: Option<&'a str>,
+  pub log_file: &'a str,
+  pub leave_running: bool,
+  pub track_mem: bool,
+  /// send the pages to this page server instead of writing them
+  pub page_server: Option<SocketAddr>,
+  pub options: &'a DumpOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct RestoreRequest<'a> {
+  pub work_dir: &'a Path,
+  pub image_dir: &'a Path,
+  pub options: &'a RestoreOptions,
+  /// resume the process at once, the pages are left to a lazy-pages daemon
+  /// started in the same `work_dir`
+  pub lazy_pages: bool,
+  /// run CRIU in a new session on this terminal instead of the one of
+  /// hcriu, a restored shell job gets it
+  pub terminal: Option<BorrowedFd<'a>>,
+}
+
+/// Serve the pages in `image_dir` to a lazy restore.
+#[derive(Debug, Clone)]
+pub struct LazyPagesRequest<'a> {
+  pub work_dir: &'a Path,
+  pub image_dir: &'a Path,
+  pub log_file: &'a str,
+  pub log_level: u32,
+}
+
+/// A running lazy-pages daemon, killed on drop unless waited for.
+#[derive(Debug)]
+pub struct LazyPages {
+  child: Option<Child>,
+  log: PathBuf,
+}
+
+impl LazyPages {
+  /// Wait until the daemon served every page and exited.
+  pub fn wait(mut self) -> Result<()> {
+    let Some(mut child) = self.child.take() else {
+      return Ok(());
+    };
+    let status = child.wait().with_path(&self.log)?;
+    if status.success() {
+      Ok(())
+    } else {
+      Err(Error::Criu {
+        action: "serve lazy pages",
+        message: format!("{}, see {}", status, self.log.display()),
+      })
+    }
+  }
+}
+
+impl Drop for LazyPages {
+  fn drop(&mut self) {
+    // with no restore to serve it would wait forever
+    if let Some(child) = &mut self.child {
+      let _ = child.kill();
+      let _ = child.wait();
+    }
+  }
+}
+
+/// Receive the pages of one dump sent with [`DumpRequest::page_server`].
+#[derive(Debug, Clone)]
+pub struct PageServerRequest<'a> {
+  pub work_dir: &'a Path,
+  pub image_dir: &'a Path,
+  pub log_file: &'a str,
+  /// connection from the dumping side, the pages arrive on it
+  pub socket: &'a TcpStream,
+}
+
+pub trait CriuBackend {
+  /// Copy memory while the process keeps running.
+  fn pre_dump(&mut self, request: &DumpRequest) -> Result<()>;
+
+  fn dump(&mut self, request: &DumpRequest) -> Result<()>;
+
+  fn restore(&mut self, request: &RestoreRequest) -> Result<()>;
+
+  /// Write the pages a dump sends over `request.socket`, until it is done.
+  fn page_server(&mut self, request: &PageServerRequest) -> Result<()>;
+
+  /// Start a lazy-pages daemon, it is ready for the restore once this
+  /// returns.
+  fn lazy_pages(&mut self, request: &LazyPagesRequest) -> Result<LazyPages>;
+}
+
+/// How to drive CRIU. The command line tool is the default, it takes every
+/// option hcriu passes, RPC only the ones the pinned rust-criu has setters
+/// for.
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+  #[default]
+  Cli,
+  Rpc,
+  /// synthetic images without CRIU or root, for tests
+  Fake,
+}
+
+impl Backend {
+  /// The backend running the `criu` at `criu_path`, which the fake ignores.
+  pub fn open(self, criu_path: String) -> Result<Box<dyn CriuBackend>> {
+    Ok(match self {
+      Backend::Cli => Box::new(CliBackend::new(criu_path)),
+      Backend::Rpc => Box::new(RpcBackend::new(criu_path)?),
+      Backend::Fake => Box::new(FakeBackend::new()),
+    })
+  }
+}
+
+/// Talk to CRIU through its RPC interface with rust-criu.
+///
+/// Only what the pinned rust-criu has setters for goes over RPC. Incremental
+/// dumps, pre-dumps, timeouts, ghost limits, page servers and lazy pages are
+/// refused, the CLI backend passes them to `criu` as flags.
+pub struct RpcBackend {
+  criu: Criu,
+}
+
+impl RpcBackend {
+  pub fn new(criu_path: String) -> Result<Self> {
+    let criu = Criu::new_with_criu_path(criu_path).map_err(|e| Error::criu("start criu", e))?;
+    Ok(RpcBackend { criu })
+  }
+
+  fn prepare_dump(&mut self, request: &DumpRequest) -> Result<(File, File)> {
+    let options = request.options;
+    let unsupported = [
+      (request.track_mem, "memory tracking"),
+      (request.parent_img.is_some(), "incremental dumps"),
+      (options.timeout.is_some(), "--timeout"),
+      (options.ghost_limit.is_some(), "--ghost-limit"),
+      (request.page_server.is_some(), "dumps to a page server"),
+    ];
+    if let Some((_, feature)) = unsupported.iter().find(|(requested, _)| *requested) {
+      return Err(unsupported_over_rpc("dump", feature));
+    }
+
+    let work_fd = File::open(request.work_dir).with_path(request.work_dir)?;
+    let image_fd = File::open(request.image_dir).with_path(request.image_dir)?;
+    let criu = &mut self.criu;
+    criu.set_work_dir_fd(work_fd.as_raw_fd());
+    criu.set_images_dir_fd(image_fd.as_raw_fd());
+    criu.set_log_file(request.log_file.to_string());
+    criu.set_log_level(options.log_level);
+    criu.set_pid(request.pid);
+    criu.set_leave_running(request.leave_running);
+    criu.set_shell_job(options.shell_job);
+    criu.set_ext_unix_sk(options.ext_unix_sk);
+    criu.set_tcp_established(options.tcp_established);
+    criu.set_file_locks(options.file_locks);
+    criu.set_manage_cgroups(options.manage_cgroups);
+    // the fds must stay open until criu has run
+    Ok((work_fd, image_fd))
+  }
+}
+
+fn unsupported_over_rpc(action: &'static str, feature: &str) -> Error {
+  Error::Criu {
+    action,
+    message: format!("{} not supported over RPC, use --backend cli", feature),
+  }
+}
+
+impl CriuBackend for RpcBackend {
+  fn pre_dump(&mut self, _request: &DumpRequest) -> Result<()> {
+    Err(unsupported_over_rpc("pre-dump", "pre-dumps"))
+  }
+
+  fn dump(&mut self, request: &DumpRequest) -> Result<()> {
+    let _fds = self.prepare_dump(request)?;
+    self.criu.dump().map_err(|e| Error::criu("dump", e))
+  }
+
+  fn restore(&mut self, request: &RestoreRequest) -> Result<()> {
+    // the RPC service runs in the session of hcriu, on its terminal
+    if request.terminal.is_some() {
+      return Err(unsupported_over_rpc("restore", "detached restore"));
+    }
+    if request.lazy_pages {
+      return Err(unsupported_over_rpc("restore", "lazy restore"));
+    }
+    let work_fd = File::open(request.work_dir).with_path(request.work_dir)?;
+    let image_fd = File::open(request.image_dir).with_path(request.image_dir)?;
+    let options = request.options;
+    let criu = &mut self.criu;
+    criu.set_work_dir_fd(work_fd.as_raw_fd());
+    criu.set_images_dir_fd(image_fd.as_raw_fd());
+    criu.set_log_file(options.log_file.clone());
+    criu.set_log_level(options.log_level);
+    criu.set_shell_job(options.shell_job);
+    criu.set_ext_unix_sk(options.ext_unix_sk);
+    criu.set_tcp_established(options.tcp_established);
+    criu.set_file_locks(options.file_locks);
+    criu.set_manage_cgroups(options.manage_cgroups);
+    criu.restore().map_err(|e| Error::criu("restore", e))
+  }
+
+  fn page_server(&mut self, _request: &PageServerRequest) -> Result<()> {
+    // the RPC page server listens itself, it can't be handed a connection
+    Err(unsupported_over_rpc("page-server", "page server"))
+  }
+
+  fn lazy_pages(&mut self, _request: &LazyPagesRequest) -> Result<LazyPages> {
+    Err(unsupported_over_rpc(
+      "serve lazy pages",
+      "lazy-pages daemon",
+    ))
+  }
+}
+
+/// Run the `criu` binary directly, one process per request.
+pub struct CliBackend {
+  criu_path: PathBuf,
+}
+
+impl CliBackend {
+  pub fn new(criu_path: impl Into<PathBuf>) -> Self {
+    CliBackend {
+      criu_path: criu_path.into(),
+    }
+  }
+
+  fn run(&self, action: &'static str, args: Vec<String>, work_dir: &Path, log: &str) -> Result<()> {
+    let mut command = Command::new(&self.criu_path);
+    command.arg(action).args(args);
+    self.wait(command, action, work_dir, log)
+  }
+
+  fn wait(
+    &self,
+    mut command: Command,
+    action: &'static str,
+    work_dir: &Path,
+    log: &str,
+  ) -> Result<()> {
+    let status = command.status().with_path(&self.criu_path)?;
+    if status.success() {
+      Ok(())
+    } else {
+      Err(Error::Criu {
+        action,
+        message: format!("{}, see {}", status, work_dir.join(log).display()),
+      })
+    }
+  }
+
+  fn dump_args(request: &DumpRequest) -> Vec<String> {
+    let options = request.options;
+    let mut args = vec![
+      "-t".to_string(),
+      request.pid.to_string(),
+      "-D".to_string(),
+      request.image_dir.display().to_string(),
+      "-W".to_string(),
+      request.work_dir.display().to_string(),
+      "-o".to_string(),
+      request.log_file.to_string(),
+      format!("-v{}", options.log_level),
+    ];
+    let flags = [
+      (request.leave_running, "--leave-running"),
+      (request.track_mem, "--track-mem"),
+      (options.shell_job, "--shell-job"),
+      (options.ext_unix_sk, "--ext-unix-sk"),
+      (options.tcp_established, "--tcp-established"),
+      (options.file_locks, "--file-locks"),
+      (options.manage_cgroups, "--manage-cgroups"),
+    ];
+    args.extend(
+      flags
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, flag)| flag.to_string()),
+    );
+    if let Some(parent_img) = request.parent_img {
+      args.push("--prev-images-dir".to_string());
+      args.push(parent_img.to_string());
+    }
+    if let Some(timeout) = options.timeout {
+      args.push("--timeout".to_string());
+      args.push(timeout.to_string());
+    }
+    if let Some(ghost_limit) = options.ghost_limit {
+      args.push("--ghost-limit".to_string());
+      args.push(ghost_limit.to_string());
+    }
+    if let Some(address) = request.page_server {
+      args.push("--page-server".to_string());
+      args.push("--address".to_string());
+      args.push(address.ip().to_string());
+      args.push("--port".to_string());
+      args.push(address.port().to_string());
+    }
+    args
+  }
+}
+
+impl CriuBackend for CliBackend {
+  fn pre_dump(&mut self, request: &DumpRequest) -> Result<()> {
+    let args = Self::dump_args(request);
+    self.run("pre-dump", args, request.work_dir, request.log_file)
+  }
+
+  fn dump(&mut self, request: &DumpRequest) -> Result<()> {
+    let args = Self::dump_args(request);
+    self.run("dump", args, request.work_dir, request.log_file)
+  }
+
+  fn restore(&mut self, request: &RestoreRequest) -> Result<()> {
+    let options = request.options;
+    let mut args = vec![
+      "-D".to_string(),
+      request.image_dir.display().to_string(),
+      "-W".to_string(),
+      request.work_dir.display().to_string(),
+      "-o".to_string(),
+      options.log_file.clone(),
+      format!("-v{}", options.log_level),
+      // like the RPC restore, give control back once the process runs
+      "--restore-detached".to_string(),
+    ];
+    let flags = [
+      (request.lazy_pages, "--lazy-pages"),
+      (options.shell_job, "--shell-job"),
+      (options.ext_unix_sk, "--ext-unix-sk"),
+      (options.tcp_established, "--tcp-established"),
+      (options.file_locks, "--file-locks"),
+      (options.manage_cgroups, "--manage-cgroups"),
+    ];
+    args.extend(
+      flags
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, flag)| flag.to_string()),
+    );
+    let Some(terminal) = request.terminal else {
+      return self.run("restore", args, request.work_dir, &options.log_file);
+    };
+
+    let mut command = Command::new(&self.criu_path);
+    command.arg("restore").args(args);
+    let stdio = || terminal.try_clone_to_owned().with_path(request.work_dir);
+    command.stdin(stdio()?).stdout(stdio()?).stderr(stdio()?);
+    // SAFETY: setsid and ioctl are async-signal-safe
+    unsafe {
+      command.pre_exec(|| {
+        setsid()?;
+        // the terminal on stdin becomes the controlling one of the session
+        if nix::libc::ioctl(0, nix::libc::TIOCSCTTY, 0) < 0 {
+          return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+      });
+    }
+    self.wait(command, "restore", request.work_dir, &options.log_file)
+  }
+
+  fn page_server(&mut self, request: &PageServerRequest) -> Result<()> {
+    // criu inherits the connection, sockets are opened close-on-exec
+    let fd = request.socket.as_raw_fd();
+    fcntl(request.socket, FcntlArg::F_SETFD(FdFlag::empty()))
+      .map_err(|e| Error::criu("page-server", Box::new(e)))?;
+    let args = vec![
+      "-D".to_string(),
+      request.image_dir.display().to_string(),
+      "-W".to_string(),
+      request.work_dir.display().to_string(),
+      "-o".to_string(),
+      request.log_file.to_string(),
+      "--ps-socket".to_string(),
+      fd.to_string(),
+    ];
+    self.run("page-server", args, request.work_dir, request.log_file)
+  }
+
+  fn lazy_pages(&mut self, request: &LazyPagesRequest) -> Result<LazyPages> {
+    spawn_lazy_pages(&self.criu_path, request)
+  }
+}
+
+/// Start `criu lazy-pages` and wait for the socket the restore connects to.
+fn spawn_lazy_pages(criu_path: &Path, request: &LazyPagesRequest) -> Result<LazyPages> {
+  let socket = request.work_dir.join(LAZY_PAGES_SOCKET);
+  let child = Command::new(criu_path)
+    .arg("lazy-pages")
+    .arg("-D")
+    .arg(request.image_dir)
+    .arg("-W")
+    .arg(request.work_dir)
+    .arg("-o")
+    .arg(request.log_file)
+    .arg(format!("-v{}", request.log_level))
+    .spawn()
+    .with_path(criu_path)?;
+  let log = request.work_dir.join(request.log_file);
+  let mut daemon = LazyPages {
+    child: Some(child),
+    log,
+  };
+  let started = Instant::now();
+  while !socket.exists() {
+    let exited = daemon.child.as_mut().map(|child| child.try_wait());
+    if let Some(Ok(Some(status))) = exited {
+      daemon.child = None;
+      return Err(Error::Criu {
+        action: "serve lazy pages",
+        message: format!("{}, see {}", status, daemon.log.display()),
+      });
+    }
+    if started.elapsed() > LAZY_PAGES_STARTUP {
+      return Err(Error::Criu {
+        action: "serve lazy pages",
+        message: format!("no socket after {:?}", LAZY_PAGES_STARTUP),
+      });
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  Ok(daemon)
+}
+
+/// What a [`FakeBackend`] was asked to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FakeCall {
+  PreDump { pid: i32, image_dir: PathBuf },
+  Dump { pid: i32, image_dir: PathBuf },
+  Restore { image_dir: PathBuf },
+  PageServer { image_dir: PathBuf },
+  LazyPages { image_dir: PathBuf },
+}
+
+/// Pretend to be CRIU, for tests and unprivileged CI.
+///
+/// Dumps write a small but well formed image set: a pagemap of `pages` pages,
+/// the matching pages file, a `stats-dump` and the log file. With a parent the
+/// first half of the pages is marked as living in the parent, as an
+/// incremental dump would. With a page server the pages file is sent to it
+/// as is, where the fake page server writes it.
+#[derive(Debug)]
+pub struct FakeBackend {
+  pub calls: Vec<FakeCall>,
+  /// pages in every dumped pagemap
+  pub pages: u32,
+  /// make every request fail, to exercise error paths
+  pub fail: bool,
+}
+
+impl Default for FakeBackend {
+  fn default() -> Self {
+    FakeBackend {
+      calls: Vec::new(),
+      pages: 8,
+      fail: false,
+    }
+  }
+}
+
+impl FakeBackend {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Fail if asked to, leaving a log behind like CRIU would.
+  fn check(&self, action: &'static str, log: &Path) -> Result<()> {
+    if self.fail {
+      std::fs::write(log, format!("fake {} failed\n", action)).with_path(log)?;
+      Err(Error::Criu {
+        action,
+        message: "fake backend set to fail".to_string(),
+      })
+    } else {
+      Ok(())
+    }
+  }
+
+  fn write_images(&self, request: &DumpRequest) -> Result<()> {
+    let page_size = procfs::page_size();
+    let image_dir = request.image_dir;
+    let in_parent = if request.parent_img.is_some() {
+      self.pages / 2
+    } else {
+      0
+    };
+
+    let mut entries = Vec::new();
+    let vaddr = 0x10000;
+    if in_parent > 0 {
+      entries.push(PagemapEntry {
+        vaddr,
+        nr_pages: in_parent,
+        in_parent: None,
+        flags: Some(PE_PARENT),
+      });
+    }
+    let written = self.pages - in_parent;
+    if written > 0 {
+      entries.push(PagemapEntry {
+        vaddr: vaddr + in_parent as u64 * page_size,
+        nr_pages: written,
+        in_parent: None,
+        flags: Some(PE_PRESENT),
+      });
+    }
+    let pagemap = Pagemap {
+      image_magic: Some(IMG_COMMON_MAGIC),
+      magic: PAGEMAP_MAGIC,
+      pages_id: 1,
+      entries,
+    };
+    let pagemap_path = image_dir.join(format!("pagemap-{}.img", request.pid));
+    std::fs::write(&pagemap_path, pagemap.encode()).with_path(&pagemap_path)?;
+
+    // every page is filled with the pid, so restored data can be checked
+    let pages = vec![request.pid as u8; (written as u64 * page_size) as usize];
+    if let Some(address) = request.page_server {
+      let sent = TcpStream::connect(address).and_then(|mut socket| socket.write_all(&pages));
+      sent.map_err(|e| Error::Criu {
+        action: "dump",
+        message: format!("page server {}: {}", address, e),
+      })?;
+    } else {
+      let pages_path = image_dir.join("pages-1.img");
+      std::fs::write(&pages_path, pages).with_path(&pages_path)?;
+    }
+
+    if let Some(parent_img) = request.parent_img {
+      let link = image_dir.join("parent");
+      std::os::unix::fs::symlink(parent_img, &link).with_path(&link)?;
+    }
+
+    let stats = DumpStats {
+      pages_scanned: self.pages as u64,
+      pages_skipped_parent: in_parent as u64,
+      pages_written: written as u64,
+      ..Default::default()
+    };
+    stats.write(image_dir).with_path(image_dir)?;
+
+    let log = request.work_dir.join(request.log_file);
+    std::fs::write(&log, "fake dump\n").with_path(&log)
+  }
+}
+
+impl CriuBackend for FakeBackend {
+  fn pre_dump(&mut self, request: &DumpRequest) -> Result<()> {
+    self.check("pre-dump", &request.work_dir.join(request.log_file))?;
+    self.write_images(request)?;
+    self.calls.push(FakeCall::PreDump {
+      pid: request.pid,
+      image_dir: request.image_dir.to_path_buf(),
+    });
+    Ok(())
+  }
+
+  fn dump(&mut self, request: &DumpRequest) -> Result<()> {
+    self.check("dump", &request.work_dir.join(request.log_file))?;
+    self.write_images(request)?;
+    self.calls.push(FakeCall::Dump {
+      pid: request.pid,
+      image_dir: request.image_dir.to_path_buf(),
+    });
+    Ok(())
+  }
+
+  fn restore(&mut self, request: &RestoreRequest) -> Result<()> {
+    self.check("restore", &request.work_dir.join(&request.options.log_file))?;
+    let stats = request.image_dir.join("stats-dump");
+    if !stats.exists() {
+      return Err(Error::Criu {
+        action: "restore",
+        message: format!("no images in {}", request.image_dir.display()),
+      });
+    }
+    // like CRIU, fail when the pages a pagemap points to are not there
+    for entry in std::fs::read_dir(request.image_dir).with_path(request.image_dir)? {
+      let path = entry.with_path(request.image_dir)?.path();
+      let name = path.file_name().unwrap_or_default().to_string_lossy();
+      if !name.starts_with("pagemap-") || !name.ends_with(".img") {
+        continue;
+      }
+      let pagemap = Pagemap::parse(&std::fs::read(&path).with_path(&path)?).with_path(&path)?;
+      let pages = request
+        .image_dir
+        .join(format!("pages-{}.img", pagemap.pages_id));
+      if !pages.is_file() {
+        return Err(Error::Criu {
+          action: "restore",
+          message: format!("can't open {}", pages.display()),
+        });
+      }
+    }
+    let log = request.work_dir.join(&request.options.log_file);
+    std::fs::write(&log, "fake restore\n").with_path(&log)?;
+    if let Some(terminal) = request.terminal {
+      // what a restored shell job would print on its terminal
+      let tty = Path::new("/dev/tty");
+      File::from(terminal.try_clone_to_owned().with_path(tty)?)
+        .write_all(b"fake process\n")
+        .with_path(tty)?;
+    }
+    self.calls.push(FakeCall::Restore {
+      image_dir: request.image_dir.to_path_buf(),
+    });
+    Ok(())
+  }
+
+  fn page_server(&mut self, request: &PageServerRequest) -> Result<()> {
+    let log = request.work_dir.join(request.log_file);
+    self.check("page-server", &log)?;
+    let mut pages = Vec::new();
+    let mut socket = request.socket;
+    socket.read_to_end(&mut pages).map_err(|e| Error::Criu {
+      action: "page-server",
+      message: e.to_string(),
+    })?;
+    let pages_path = request.image_dir.join("pages-1.img");
+    std::fs::write(&pages_path, pages).with_path(&pages_path)?;
+    std::fs::write(&log, "fake page server\n").with_path(&log)?;
+    self.calls.push(FakeCall::PageServer {
+      image_dir: request.image_dir.to_path_buf(),
+    });
+    Ok(())
+  }
+
+  fn lazy_pages(&mut self, request: &LazyPagesRequest) -> Result<LazyPages> {
+    let log = request.work_dir.join(request.log_file);
+    self.check("serve lazy pages", &log)?;
+    std::fs::write(&log, "fake lazy-pages\n").with_path(&log)?;
+    self.calls.push(FakeCall::LazyPages {
+      image_dir: request.image_dir.to_path_buf(),
+    });
+    Ok(LazyPages { child: None, log })
+  }
+}
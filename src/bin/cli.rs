@@ -1,9 +1,17 @@
-use clap::{Parser, Subcommand, CommandFactory};
+use clap::{Args, Parser, Subcommand, CommandFactory};
 use humantime::Duration;
-use rust_criu::Criu;
 use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
 use which::which;
-use hcriu::{dump, list, merge, restore, utils, Sort};
+use hcriu::{
+  archive, dump, fsck, list, merge, page_server, pin, restore, sign, verify, Backend,
+  CheckpointStore, CriuBackend, DumpOptions, KeySource, RestoreOptions, RetentionPolicy, Sort,
+  Timezone,
+};
+use hcriu::detach::Detach;
+use hcriu::merge::MergeOptions;
+use std::path::PathBuf;
 
 
 #[derive(Debug, Parser)]
@@ -14,10 +22,20 @@ struct Cli {
   #[arg(long)]
   path: Option<String>,
 
+  /// How to drive CRIU, `rpc` only takes plain dumps and restores, `fake`
+  /// writes synthetic images without CRIU
+  #[arg(long, default_value = "cli")]
+  backend: Backend,
+
   /// Specify checkpoints directory, where store all checkpoints
   #[arg(short = 'd', long, default_value = "~/.hcriu/")]
   dir: String,
 
+  /// Derive the key of an encrypted store from this file instead of a
+  /// passphrase
+  #[arg(long)]
+  key_file: Option<PathBuf>,
+
   #[command(subcommand)]
   command: Option<Commands>,
 }
@@ -37,13 +55,50 @@ enum Commands {
     #[arg(short, long)]
     tag: Option<String>,
 
-    /// leave running processes before creation
-    #[arg(long, default_value = "false")]
-    leave_running: bool,
+    /// write the checkpoint as an archive to this file, `-` for stdout,
+    /// instead of keeping it in the store
+    #[arg(long, conflicts_with = "interval")]
+    stream: Option<PathBuf>,
+
+    /// send the checkpoint to `hcriu page-server` at this address instead,
+    /// the pages go straight to it
+    #[arg(long, conflicts_with_all = ["interval", "stream"])]
+    page_server: Option<String>,
+
+    #[command(flatten)]
+    options: DumpArgs,
   },
 
   /// Restore container from checkpoint
-  Restore { checkpoint_id: String },
+  Restore {
+    /// checkpoint id or prefix, tag, or revision (e.g., web~2, pid:1234@latest,
+    /// @{1 hour ago})
+    #[arg(required_unless_present = "stream")]
+    checkpoint_id: Option<String>,
+
+    /// restore the checkpoint of an archive read from this file, `-` for
+    /// stdin, as written by `dump --stream`
+    #[arg(long, conflicts_with = "checkpoint_id")]
+    stream: Option<PathBuf>,
+
+    /// read CRIU options from a TOML file instead of replaying the dump's
+    #[arg(long)]
+    options: Option<PathBuf>,
+
+    /// restore even if the images do not match their manifest
+    #[arg(long, default_value = "false")]
+    force: bool,
+
+    /// resume the process at once and serve its memory on demand, waiting
+    /// until every page was faulted in
+    #[arg(long, default_value = "false")]
+    lazy: bool,
+
+    /// give the process a new terminal instead of this one, its output goes
+    /// to a log, nowhere, or a log with a FIFO for input (not over rpc)
+    #[arg(long, conflicts_with = "lazy")]
+    detach: Option<Detach>,
+  },
 
   /// List all checkpoints
   List {
@@ -52,7 +107,23 @@ enum Commands {
     sort: Sort,
   },
 
+  /// Show a checkpoint and the chain it restores from
+  Inspect {
+    revision: String,
+  },
+
+  /// Delete a checkpoint, folding its pages into its incremental children
+  Delete {
+    revision: String,
+
+    /// delete a pinned checkpoint too
+    #[arg(long, default_value = "false")]
+    force: bool,
+  },
+
   /// Merge checkpoints, by default, it will keep the latest checkpoint
+  ///
+  /// Retention flags combine, a checkpoint is kept if any of them keeps it.
   Merge {
     /// tag filter for checkpoints to merge
     tag: String,
@@ -65,57 +136,374 @@ enum Commands {
     #[arg(short, long)]
     pid: Option<i32>,
 
-    /// keep daily checkpoints
+    #[command(flatten)]
+    retention: RetentionArgs,
+
+    /// fold merged parents into their incremental children instead of keeping them
+    #[arg(long, default_value = "false")]
+    squash: bool,
+
+    /// delete without asking for confirmation
+    #[arg(short, long, default_value = "false")]
+    yes: bool,
+
+    /// merge pinned checkpoints too
     #[arg(long, default_value = "false")]
-    keep_daily: bool,
+    force: bool,
+  },
+
+  /// Protect a checkpoint from merge, retention and quota eviction
+  Pin {
+    checkpoint_id: String,
+  },
+
+  /// Remove the protection of a pinned checkpoint
+  Unpin {
+    checkpoint_id: String,
+  },
+
+  /// Recheck the image hashes recorded when checkpoints were dumped
+  Verify {
+    #[arg(required_unless_present = "all")]
+    checkpoint_id: Option<String>,
+
+    /// verify every checkpoint
+    #[arg(long, default_value = "false", conflicts_with = "checkpoint_id")]
+    all: bool,
+  },
 
-    /// keep hourly checkpoints
+  /// Check the checkpoints directory for broken or half-written checkpoints
+  Fsck {
+    /// move broken entries to quarantine and rebuild missing metadata
     #[arg(long, default_value = "false")]
-    keep_hourly: bool,
+    repair: bool,
   },
+
+  /// Trust checkpoints signed with a public key, your own without one
+  Trust {
+    key: Option<String>,
+  },
+
+  /// Write a checkpoint and its parents to a tar archive
+  Export {
+    checkpoint_id: String,
+
+    /// archive to write, zstd compressed when it ends in `.zst`
+    #[arg(short, long)]
+    output: PathBuf,
+  },
+
+  /// Add the checkpoints of an archive written by `hcriu export`
+  Import {
+    archive: PathBuf,
+  },
+
+  /// Take dumps sent with `dump --page-server` into this store
+  PageServer {
+    /// address to listen on (e.g., 127.0.0.1:27000)
+    #[arg(long)]
+    listen: String,
+  },
+}
+
+#[derive(Debug, Args)]
+struct RetentionArgs {
+  /// keep the N latest checkpoints
+  #[arg(long)]
+  keep_last: Option<usize>,
+
+  /// keep the latest checkpoint of each of the last N hours
+  #[arg(long)]
+  keep_hourly: Option<usize>,
+
+  /// keep the latest checkpoint of each of the last N days
+  #[arg(long)]
+  keep_daily: Option<usize>,
+
+  /// keep the latest checkpoint of each of the last N weeks
+  #[arg(long)]
+  keep_weekly: Option<usize>,
+
+  /// keep the latest checkpoint of each of the last N months
+  #[arg(long)]
+  keep_monthly: Option<usize>,
+
+  /// keep every checkpoint within this duration of the latest (e.g., 2d, 12h)
+  #[arg(long)]
+  keep_within: Option<Duration>,
+
+  /// timezone hours, days, weeks and months are counted in
+  #[arg(long, default_value = "local")]
+  timezone: Timezone,
+}
+
+impl RetentionArgs {
+  fn to_policy(&self) -> RetentionPolicy {
+    RetentionPolicy {
+      keep_last: self.keep_last,
+      keep_hourly: self.keep_hourly,
+      keep_daily: self.keep_daily,
+      keep_weekly: self.keep_weekly,
+      keep_monthly: self.keep_monthly,
+      keep_within: self.keep_within.map(Into::into),
+      timezone: self.timezone,
+    }
+  }
+}
+
+#[derive(Debug, Args)]
+struct DumpArgs {
+  /// leave running processes before creation, `=false` to stop them
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  leave_running: Option<bool>,
+
+  /// number of pre-dump passes copying memory while the process runs
+  #[arg(long)]
+  pre_dump: Option<u32>,
+
+  /// the process is attached to a terminal session, on unless `=false`
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  shell_job: Option<bool>,
+
+  /// allow unix sockets connected outside the process tree, on unless `=false`
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  ext_unix_sk: Option<bool>,
+
+  /// dump established TCP connections
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  tcp_established: Option<bool>,
+
+  /// dump file locks
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  file_locks: Option<bool>,
+
+  /// dump and restore the cgroups of the process
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  manage_cgroups: Option<bool>,
+
+  /// CRIU log level, from 0 to 4
+  #[arg(long)]
+  log_level: Option<u32>,
+
+  /// seconds CRIU may spend collecting the process tree
+  #[arg(long)]
+  timeout: Option<u32>,
+
+  /// largest deleted file in bytes to copy into the images
+  #[arg(long)]
+  ghost_limit: Option<u32>,
+
+  /// compress the page images with zstd, at the given level or 3
+  #[arg(long, num_args = 0..=1, default_missing_value = "3")]
+  compress: Option<i32>,
+
+  /// sign the manifest and metadata with your signing key
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  sign: Option<bool>,
+
+  /// read CRIU options from a TOML file, flags given here take precedence,
+  /// `--flag=false` turns off one the file turns on
+  #[arg(long)]
+  options: Option<PathBuf>,
+}
+
+impl DumpArgs {
+  fn to_options(&self) -> hcriu::Result<DumpOptions> {
+    let mut options = match &self.options {
+      Some(path) => DumpOptions::load(path)?,
+      None => DumpOptions::new(),
+    };
+    let flags = [
+      (self.leave_running, &mut options.leave_running),
+      (self.shell_job, &mut options.shell_job),
+      (self.ext_unix_sk, &mut options.ext_unix_sk),
+      (self.tcp_established, &mut options.tcp_established),
+      (self.file_locks, &mut options.file_locks),
+      (self.manage_cgroups, &mut options.manage_cgroups),
+      (self.sign, &mut options.sign),
+    ];
+    for (flag, value) in flags {
+      if let Some(flag) = flag {
+        *value = flag;
+      }
+    }
+    if let Some(pre_dump) = self.pre_dump {
+      options = options.pre_dump(pre_dump);
+    }
+    if let Some(log_level) = self.log_level {
+      options = options.log_level(log_level);
+    }
+    if let Some(timeout) = self.timeout {
+      options = options.timeout(timeout);
+    }
+    if let Some(ghost_limit) = self.ghost_limit {
+      options = options.ghost_limit(ghost_limit);
+    }
+    if let Some(level) = self.compress {
+      options = options.compress(level);
+    }
+    Ok(options)
+  }
 }
 
 fn find_criu_path() -> Option<String> {
   which("criu").ok().map(|p| p.to_string_lossy().into_owned())
 }
 
-fn handle_command(criu: &mut Criu, cli: &Cli) -> Result<(), Box<dyn Error>> {
+fn handle_command(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  cli: &Cli,
+) -> Result<(), Box<dyn Error>> {
   match &cli.command {
+    Some(Commands::Dump {
+      pid,
+      tag,
+      page_server: Some(server),
+      options,
+      ..
+    }) => {
+      let options = options.to_options()?;
+      dump::handle_dump_remote(criu, *pid, tag.clone(), &options, server)?;
+      Ok(())
+    }
+    Some(Commands::Dump {
+      pid,
+      tag,
+      stream: Some(stream),
+      options,
+      ..
+    }) => {
+      let mut output: Box<dyn Write> = if stream.as_os_str() == "-" {
+        Box::new(io::stdout().lock())
+      } else {
+        Box::new(File::create(stream)?)
+      };
+      let options = options.to_options()?;
+      dump::handle_dump_stream(store, criu, *pid, tag.clone(), &options, &mut output)?;
+      Ok(())
+    }
     Some(Commands::Dump {
       pid,
       interval,
       tag,
-      leave_running,
+      options,
+      ..
     }) => {
-      dump::handle_dump(criu, *pid, interval.clone(), tag.clone(), *leave_running);
+      dump::handle_dump(
+        store,
+        criu,
+        *pid,
+        interval.clone(),
+        tag.clone(),
+        &options.to_options()?,
+      )?;
       Ok(())
     }
-    Some(Commands::Restore { checkpoint_id }) => {
-      restore::handle_restore(criu, checkpoint_id.clone());
+    Some(Commands::Restore {
+      checkpoint_id,
+      stream,
+      options,
+      force,
+      lazy,
+      detach,
+    }) => {
+      let options = match options {
+        Some(path) => Some(RestoreOptions::load(path)?),
+        None => None,
+      };
+      match (stream, checkpoint_id) {
+        (Some(stream), _) => {
+          let mut input: Box<dyn Read> = if stream.as_os_str() == "-" {
+            Box::new(io::stdin().lock())
+          } else {
+            Box::new(File::open(stream)?)
+          };
+          let options = options.as_ref();
+          restore::handle_restore_stream(
+            store, criu, &mut input, options, *force, *lazy, *detach,
+          )?;
+        }
+        (None, checkpoint_id) => {
+          restore::handle_restore(
+            store,
+            criu,
+            checkpoint_id.clone().unwrap_or_default(),
+            options.as_ref(),
+            *force,
+            *lazy,
+            *detach,
+          )?;
+        }
+      }
       Ok(())
     }
     Some(Commands::List { sort }) => {
-      list::handle_list(sort.to_owned());
+      list::handle_list(store, sort.to_owned())?;
+      Ok(())
+    }
+    Some(Commands::Inspect { revision }) => {
+      list::handle_inspect(store, revision)?;
+      Ok(())
+    }
+    Some(Commands::Delete { revision, force }) => {
+      merge::handle_delete(store, revision, *force)?;
       Ok(())
     }
     Some(Commands::Merge {
       tag,
       dry_run,
       pid,
-      keep_daily,
-      keep_hourly,
+      retention,
+      squash,
+      yes,
+      force,
     }) => {
-      merge::handle_merge(
-        tag.clone(),
-        *dry_run,
-        *pid,
-        *keep_daily,
-        *keep_hourly,
-      );
+      let options = MergeOptions::new()
+        .dry_run(*dry_run)
+        .squash(*squash)
+        .yes(*yes)
+        .force(*force);
+      merge::handle_merge(store, tag, *pid, &retention.to_policy(), &options)?;
+      Ok(())
+    }
+    Some(Commands::Pin { checkpoint_id }) => {
+      pin::handle_pin(store, checkpoint_id, true)?;
+      Ok(())
+    }
+    Some(Commands::Unpin { checkpoint_id }) => {
+      pin::handle_pin(store, checkpoint_id, false)?;
+      Ok(())
+    }
+    Some(Commands::Verify { checkpoint_id, .. }) => {
+      verify::handle_verify(store, checkpoint_id.as_deref())?;
+      Ok(())
+    }
+    Some(Commands::Fsck { repair }) => {
+      fsck::handle_fsck(store, *repair)?;
+      Ok(())
+    }
+    Some(Commands::Trust { key }) => {
+      sign::handle_trust(store, key.as_deref())?;
+      Ok(())
+    }
+    Some(Commands::Export {
+      checkpoint_id,
+      output,
+    }) => {
+      archive::handle_export(store, checkpoint_id, output)?;
+      Ok(())
+    }
+    Some(Commands::Import { archive }) => {
+      archive::handle_import(store, archive)?;
+      Ok(())
+    }
+    Some(Commands::PageServer { listen }) => {
+      page_server::handle_page_server(store, criu, listen)?;
       Ok(())
     }
     None => {
-      Cli::command().print_help().unwrap();
+      Cli::command().print_help()?;
       Ok(())
     }
   }
@@ -124,9 +512,10 @@ fn handle_command(criu: &mut Criu, cli: &Cli) -> Result<(), Box<dyn Error>> {
 fn main() {
   let cli = Cli::parse();
 
-  // Find CRIU path if not provided
+  // Find CRIU path if not provided, the fake backend needs none
   let path = match &cli.path {
     Some(path) => path.clone(),
+    None if cli.backend == Backend::Fake => String::new(),
     None => match find_criu_path() {
       Some(path) => path,
       None => {
@@ -135,13 +524,16 @@ fn main() {
       }
     },
   };
-
-  let mut criu = Criu::new_with_criu_path(path).unwrap();
-  utils::set_hcriu_dir(cli.dir.clone().into());
-  let dir = utils::get_hcriu_dir();
-  if !dir.exists() {
-    std::fs::create_dir_all(dir).unwrap();
+  let mut criu = cli.backend.open(path).unwrap_or_else(|e| exit_with(e));
+  let mut store = CheckpointStore::create(&cli.dir).unwrap_or_else(|e| exit_with(e));
+  if let Some(key_file) = &cli.key_file {
+    store = store.with_key(KeySource::File(key_file.clone()));
   }
 
-  handle_command(&mut criu, &cli).unwrap();
+  handle_command(&store, criu.as_mut(), &cli).unwrap_or_else(|e| exit_with(e));
+}
+
+fn exit_with(e: impl std::fmt::Display) -> ! {
+  eprintln!("{}", e);
+  std::process::exit(1);
 }
@@ -12,11 +12,12 @@ use ratatui::widgets::{
   Block, Borders, Clear, List, ListItem, ListState, Paragraph, Scrollbar, ScrollbarOrientation,
   ScrollbarState,
 };
-use rust_criu::Criu;
 use which::which;
 
+use hcriu::{CheckpointStore, CliBackend, DumpOptions, merge};
+use hcriu::detach::Detach;
 use hcriu::restore::handle_restore;
-use hcriu::utils::{CheckpointMeta, get_all_checkpoints, get_hcriu_dir, set_hcriu_dir};
+use hcriu::utils::CheckpointMeta;
 use humantime;
 
 fn find_criu_path() -> Option<String> {
@@ -51,13 +52,20 @@ fn get_all_processes() -> Vec<ProcessInfo> {
 fn main() -> std::io::Result<()> {
   let mut terminal = ratatui::init();
   let widgets = WidgetsArea::new(&terminal.get_frame());
-  // Initialize app state
-  let mut app_state = AppState::new();
 
   // Initialize hcriu directory
   let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
-  let hcriu_dir = home_dir.join(".hcriu");
-  set_hcriu_dir(hcriu_dir);
+  let store = match CheckpointStore::create(home_dir.join(".hcriu")) {
+    Ok(store) => store,
+    Err(e) => {
+      ratatui::restore();
+      eprintln!("{}", e);
+      std::process::exit(1);
+    }
+  };
+
+  // Initialize app state
+  let mut app_state = AppState::new(store);
 
   // get criu path
   let path = match find_criu_path() {
@@ -69,7 +77,7 @@ fn main() -> std::io::Result<()> {
   };
   app_state.criu_path = path;
 
-  app_state.checkpoints = get_all_checkpoints();
+  app_state.refresh_checkpoints();
 
   // Initial process list load
   app_state.processes = get_all_processes();
@@ -90,7 +98,7 @@ fn main() -> std::io::Result<()> {
     // Update process list if interval has elapsed
     if app_state.last_update.elapsed() >= app_state.update_interval {
         app_state.processes = get_all_processes();
-        app_state.checkpoints = get_all_checkpoints();
+        app_state.refresh_checkpoints();
         app_state.processes_scrollbar_state = app_state.processes_scrollbar_state.content_length(app_state.checkpoints.len());
         app_state.last_update = Instant::now();
     }
@@ -165,11 +173,12 @@ fn draw_checkpoints(frame: &mut Frame, area: ratatui::layout::Rect, app_state: &
     .iter()
     .map(|checkpoint| {
       Line::from(format!(
-        "{} {} {} {}",
+        "{} {} {} {}{}",
         checkpoint.checkpoint_id[..7].to_string(),
         checkpoint.tag,
         checkpoint.pid,
         checkpoint.dump_time,
+        if checkpoint.pinned { " pinned" } else { "" },
       ))
     })
     .collect();
@@ -288,7 +297,10 @@ fn draw_tasks(frame: &mut Frame, area: ratatui::layout::Rect, app_state: &mut Ap
 }
 
 fn draw_status(frame: &mut Frame, area: ratatui::layout::Rect, app_state: &mut AppState) {
-  frame.render_widget(Block::default().borders(Borders::empty()), area);
+  frame.render_widget(
+    Paragraph::new(app_state.status.as_str()).style(Style::default().fg(Color::Red)),
+    area,
+  );
 }
 
 fn draw_popup(frame: &mut Frame, app_state: &mut AppState) {
@@ -480,6 +492,14 @@ fn handle_key_events(key: KeyCode, _modifiers: KeyModifiers, app_state: &mut App
 }
 
 fn handle_popup_action(app_state: &mut AppState) {
+  // a failed action is reported in the status bar instead of leaving the UI
+  app_state.status = match run_popup_action(app_state) {
+    Ok(()) => String::new(),
+    Err(e) => format!("Error: {}", e),
+  };
+}
+
+fn run_popup_action(app_state: &mut AppState) -> Result<(), Box<dyn std::error::Error>> {
   match app_state.popup_type {
     PopupType::Checkpoint => {
       if let Some(selected_checkpoint_idx) = app_state.checkpoints_seleted {
@@ -488,13 +508,23 @@ fn handle_popup_action(app_state: &mut AppState) {
 
           match app_state.popup_state.selected() {
             Some(0) => {
-              let mut criu = Criu::new_with_criu_path(app_state.criu_path.clone()).unwrap();
-              handle_restore(&mut criu, checkpoint.checkpoint_id.clone());
+              // detached, the process must not take over the terminal of the TUI,
+              // which RPC can't keep it from
+              let mut criu = CliBackend::new(app_state.criu_path.clone());
+              handle_restore(
+                &app_state.store,
+                &mut criu,
+                checkpoint.checkpoint_id.clone(),
+                None,
+                false,
+                false,
+                Some(Detach::Log),
+              )?;
             }
             Some(1) => {
-              // Delete checkpoint
-              let checkpoint_dir = get_hcriu_dir().join(checkpoint.checkpoint_id.clone());
-              std::fs::remove_dir_all(&checkpoint_dir).unwrap();
+              // Delete checkpoint, pinned ones have to be unpinned first and
+              // incremental children get its pages folded in
+              merge::handle_delete(&app_state.store, &checkpoint.checkpoint_id, false)?;
             }
             _ => {}
           }
@@ -505,21 +535,44 @@ fn handle_popup_action(app_state: &mut AppState) {
       if let Some(selected_process_idx) = app_state.processes_seleted {
         if selected_process_idx < app_state.processes.len() {
           let process = &app_state.processes[selected_process_idx];
-          let mut criu = Criu::new_with_criu_path(app_state.criu_path.clone()).unwrap();
+          let mut criu = CliBackend::new(app_state.criu_path.clone());
 
           match app_state.popup_state.selected() {
             Some(0) => {
               // Take a snapshot and stop
-              hcriu::dump::handle_dump(&mut criu, process.pid, None, None, false);
+              hcriu::dump::handle_dump(
+                &app_state.store,
+                &mut criu,
+                process.pid,
+                None,
+                None,
+                &DumpOptions::new(),
+              )?;
             }
             Some(1) => {
               // Take a snapshot and leave running
-              hcriu::dump::handle_dump(&mut criu, process.pid, None, None, true);
+              let options = DumpOptions::new().leave_running(true);
+              hcriu::dump::handle_dump(
+                &app_state.store,
+                &mut criu,
+                process.pid,
+                None,
+                None,
+                &options,
+              )?;
             }
             Some(2) => {
               // Take snapshots periodically
               let duration =  todo!();
-              hcriu::dump::handle_dump(&mut criu, process.pid, duration, None, true);
+              let options = DumpOptions::new().leave_running(true);
+              hcriu::dump::handle_dump(
+                &app_state.store,
+                &mut criu,
+                process.pid,
+                duration,
+                None,
+                &options,
+              )?;
 
             }
             _ => {}
@@ -528,6 +581,7 @@ fn handle_popup_action(app_state: &mut AppState) {
       }
     }
   }
+  Ok(())
 }
 
 // Process information structure
@@ -574,10 +628,14 @@ struct AppState {
 
   // criu
   criu_path: String,
+  store: CheckpointStore,
+
+  // last error, shown in the status bar
+  status: String,
 }
 
 impl AppState {
-  fn new() -> Self {
+  fn new(store: CheckpointStore) -> Self {
     Self {
       checkpoints: Vec::new(),
       checkpoints_seleted: None,
@@ -604,6 +662,16 @@ impl AppState {
       focused_border_style: Style::default().fg(Color::Green),
 
       criu_path: String::new(),
+      store,
+
+      status: String::new(),
+    }
+  }
+
+  fn refresh_checkpoints(&mut self) {
+    match self.store.list() {
+      Ok(checkpoints) => self.checkpoints = checkpoints,
+      Err(e) => self.status = format!("Error: {}", e),
     }
   }
 
@@ -0,0 +1,54 @@
+//! zstd compression of the raw page images.
+//!
+//! Only `pages-*.img` are compressed: they hold nearly all the bytes. A
+//! compressed image is stored as `pages-<n>.img.zst`, with its size in the
+//! frame header so listing does not have to decompress anything. See
+//! [`crate::pack`] for how CRIU gets to read them.
+
+use crate::error::{IoContext, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// zstd level used when none is configured.
+pub const DEFAULT_LEVEL: i32 = 3;
+
+pub(crate) const EXTENSION: &str = "zst";
+
+/// Images worth compressing.
+pub(crate) fn is_pages(path: &Path) -> bool {
+  path.file_name().is_some_and(|name| {
+    let name = name.to_string_lossy();
+    name.starts_with("pages-") && name.ends_with(".img")
+  })
+}
+
+pub(crate) fn compress_file(source: &Path, target: &Path, level: i32) -> Result<()> {
+  let mut input = File::open(source).with_path(source)?;
+  let len = input.metadata().with_path(source)?.len();
+  let output = File::create(target).with_path(target)?;
+  let mut encoder = zstd::Encoder::new(output, level).with_path(target)?;
+  encoder.include_contentsize(true).with_path(target)?;
+  encoder.set_pledged_src_size(Some(len)).with_path(target)?;
+  std::io::copy(&mut input, &mut encoder).with_path(target)?;
+  encoder.finish().with_path(target)?;
+  Ok(())
+}
+
+pub(crate) fn decompress_file(source: &Path, target: &Path) -> Result<()> {
+  let input = File::open(source).with_path(source)?;
+  let output = File::create(target).with_path(target)?;
+  zstd::stream::copy_decode(input, output).with_path(source)
+}
+
+/// Decompressed size of a `.zst` image, from its frame header.
+pub(crate) fn content_size(path: &Path) -> Result<u64> {
+  let mut header = Vec::new();
+  File::open(path)
+    .and_then(|file| file.take(18).read_to_end(&mut header))
+    .with_path(path)?;
+  match zstd::zstd_safe::get_frame_content_size(&header) {
+    Ok(Some(size)) => Ok(size),
+    _ => Ok(std::fs::metadata(path).with_path(path)?.len()),
+  }
+}
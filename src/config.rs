@@ -0,0 +1,213 @@
+//! Store-wide settings, read from `config.toml` in the store root.
+//!
+//! ```toml
+//! [[retention]]
+//! tag = "nightly-*"
+//! keep_hourly = 24
+//! keep_daily = 7
+//! keep_within = "2d"
+//!
+//! [quota]
+//! max_bytes = "50 GiB"
+//! min_free = "10 GiB"
+//!
+//! [[tag_quota]]
+//! tag = "nightly-*"
+//! max_count = 48
+//! on_exceed = "refuse"
+//!
+//! [compression]
+//! enabled = true
+//! level = 3
+//!
+//! [encryption]
+//! enabled = true
+//! key_file = "~/.config/hcriu/key"
+//!
+//! [dedup]
+//! enabled = true
+//!
+//! [signing]
+//! unsigned = "deny"
+//! ```
+
+use crate::error::Result;
+use crate::options::DumpOptions;
+use crate::retention::RetentionPolicy;
+use crate::{compress, options};
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct StoreConfig {
+  /// retention applied after each periodic dump, the first matching tag wins
+  pub retention: Vec<TagRetention>,
+  /// limits for the whole store
+  pub quota: Quota,
+  /// limits for the checkpoints of matching tags, every matching entry applies
+  pub tag_quota: Vec<TagQuota>,
+  pub compression: Compression,
+  pub encryption: Encryption,
+  pub dedup: Dedup,
+  pub signing: Signing,
+}
+
+/// Compression of the page images of every dump, see [`crate::compress`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct Compression {
+  pub enabled: bool,
+  /// zstd level, from 1 to 22
+  pub level: i32,
+}
+
+impl Default for Compression {
+  fn default() -> Self {
+    Compression {
+      enabled: false,
+      level: compress::DEFAULT_LEVEL,
+    }
+  }
+}
+
+/// Encryption of every image of new dumps, see [`crate::crypt`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct Encryption {
+  pub enabled: bool,
+  /// file the key is derived from, a passphrase is asked for without one
+  pub key_file: Option<PathBuf>,
+}
+
+/// Sharing of page chunks between checkpoints, see [`crate::dedup`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct Dedup {
+  pub enabled: bool,
+}
+
+/// Signatures made by `dump --sign` and checked on restore, see
+/// [`crate::sign`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct Signing {
+  /// what restore does with checkpoints no trusted key signed
+  pub unsigned: UnsignedPolicy,
+  /// the signing key, `~/.config/hcriu/signing.key` by default
+  pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsignedPolicy {
+  #[default]
+  Allow,
+  /// restore with a warning
+  Warn,
+  /// refuse to restore
+  Deny,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct TagRetention {
+  /// tag pattern, `*` matches any run of characters and `?` any one
+  pub tag: String,
+  /// fold dropped parents into the survivors, periodic dumps form a single
+  /// incremental chain that can't shrink otherwise
+  #[serde(default = "default_squash")]
+  pub squash: bool,
+  #[serde(flatten)]
+  pub policy: RetentionPolicy,
+}
+
+/// Limits checked before each dump, see [`crate::quota`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct Quota {
+  /// total size of the checkpoints, e.g. `"20 GiB"`
+  pub max_bytes: Option<ByteSize>,
+  pub max_count: Option<usize>,
+  /// free space to leave on the filesystem of the store
+  pub min_free: Option<ByteSize>,
+  pub on_exceed: QuotaAction,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+  /// delete the oldest checkpoints until the dump fits
+  #[default]
+  Evict,
+  /// fail the dump and leave the store alone
+  Refuse,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct TagQuota {
+  /// tag pattern, as for retention
+  pub tag: String,
+  #[serde(flatten)]
+  pub quota: Quota,
+}
+
+fn default_squash() -> bool {
+  true
+}
+
+impl StoreConfig {
+  /// Read the config, a missing file is an empty config.
+  pub fn load(path: &Path) -> Result<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    options::load_toml(path)
+  }
+
+  pub fn retention_for(&self, tag: &str) -> Option<&TagRetention> {
+    self.retention.iter().find(|r| tag_matches(&r.tag, tag))
+  }
+
+  /// zstd level for a dump, the dump's own option wins over the config.
+  pub fn compression_level(&self, options: &DumpOptions) -> Option<i32> {
+    options
+      .compress
+      .or(self.compression.enabled.then_some(self.compression.level))
+  }
+
+  pub fn tag_quotas_for(&self, tag: &str) -> Vec<&TagQuota> {
+    self
+      .tag_quota
+      .iter()
+      .filter(|q| tag_matches(&q.tag, tag))
+      .collect()
+  }
+}
+
+impl Quota {
+  pub fn is_empty(&self) -> bool {
+    self.max_bytes.is_none() && self.max_count.is_none() && self.min_free.is_none()
+  }
+}
+
+/// Match a tag against a pattern where `*` is any run of characters and `?`
+/// any one.
+pub fn tag_matches(pattern: &str, tag: &str) -> bool {
+  let pattern = pattern.chars().collect::<Vec<_>>();
+  let tag = tag.chars().collect::<Vec<_>>();
+  wildcard_match(&pattern, &tag)
+}
+
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+  match (pattern.first(), text.first()) {
+    (None, None) => true,
+    (Some('*'), _) => {
+      wildcard_match(&pattern[1..], text)
+        || (!text.is_empty() && wildcard_match(pattern, &text[1..]))
+    }
+    (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &text[1..]),
+    (Some(p), Some(t)) if p == t => wildcard_match(&pattern[1..], &text[1..]),
+    _ => false,
+  }
+}
@@ -0,0 +1,302 @@
+//! Authenticated encryption of image files at rest.
+//!
+//! Files are sealed with XChaCha20-Poly1305 in the STREAM construction, chunk
+//! by chunk, so images of any size never sit in memory at once. Every file
+//! gets its own random nonce, and its header, which carries the size of the
+//! image as CRIU wrote it, is authenticated along with every chunk.
+//!
+//! The key is derived with Argon2id from a key file or a passphrase, and a
+//! salt kept in `encryption.toml` in the store root next to a hash of the
+//! key, which tells a wrong key apart from damaged images. Losing that file
+//! loses the encrypted checkpoints.
+
+use crate::error::{Error, IoContext, Result};
+use argon2::Argon2;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{KeyInit, OsRng, Payload};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, IsTerminal, Read, Write};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+pub const ENCRYPTION_FILE: &str = "encryption.toml";
+
+/// Environment variable the passphrase is read from when no key is given.
+pub const PASSPHRASE_ENV: &str = "HCRIU_PASSPHRASE";
+
+pub(crate) const EXTENSION: &str = "enc";
+
+const MAGIC: &[u8; 8] = b"HCRIUENC";
+/// STREAM takes 5 bytes of the 24 byte XChaCha20 nonce for its counter
+const NONCE_LEN: usize = 19;
+const HEADER_LEN: usize = MAGIC.len() + 8 + NONCE_LEN;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// The key images of a store are encrypted with.
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl fmt::Debug for Key {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Key(..)")
+  }
+}
+
+/// Where the secret a key is derived from comes from.
+#[derive(Clone, PartialEq, Eq)]
+pub enum KeySource {
+  /// the whole content of the file is the secret
+  File(PathBuf),
+  Passphrase(String),
+  /// ask for a passphrase on the terminal
+  Prompt,
+}
+
+impl fmt::Debug for KeySource {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      KeySource::File(path) => write!(f, "File({})", path.display()),
+      KeySource::Passphrase(_) => write!(f, "Passphrase(..)"),
+      KeySource::Prompt => write!(f, "Prompt"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyCheck {
+  /// hex Argon2 salt
+  salt: String,
+  /// hex SHA-256 of the derived key
+  check: String,
+}
+
+impl Key {
+  /// Derive the key of the store at `root`. The first key used with a store
+  /// fixes its salt, later ones have to match it. Callers creating images
+  /// hold the store lock.
+  pub fn unlock(root: &Path, source: &KeySource) -> Result<Key> {
+    let secret = source.secret()?;
+    let path = root.join(ENCRYPTION_FILE);
+    let invalid = |message: String| Error::InvalidConfig {
+      path: path.clone(),
+      message,
+    };
+
+    match std::fs::read_to_string(&path) {
+      Ok(text) => return stored_key(&path, &text, &secret),
+      Err(e) if e.kind() == ErrorKind::NotFound => {}
+      Err(e) => return Err(e).with_path(&path),
+    }
+
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive(&secret, &salt).map_err(invalid)?;
+    let check = KeyCheck {
+      salt: hex::encode(salt),
+      check: key.check(),
+    };
+    let text = toml::to_string(&check).map_err(|e| invalid(e.to_string()))?;
+    // written aside and linked into place, so of two first dumps one fixes
+    // the salt and the other derives its key from that
+    let mut file = NamedTempFile::new_in(root).with_path(root)?;
+    file.write_all(text.as_bytes()).with_path(file.path())?;
+    file.as_file().sync_all().with_path(file.path())?;
+    match file.persist_noclobber(&path) {
+      Ok(_) => Ok(key),
+      Err(e) if e.error.kind() == ErrorKind::AlreadyExists => {
+        let text = std::fs::read_to_string(&path).with_path(&path)?;
+        stored_key(&path, &text, &secret)
+      }
+      Err(e) => Err(e.error).with_path(&path),
+    }
+  }
+
+  fn check(&self) -> String {
+    let hash = Sha256::new()
+      .chain_update(b"hcriu key check")
+      .chain_update(self.0)
+      .finalize();
+    hex::encode(hash)
+  }
+
+  fn cipher(&self) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(&self.0.into())
+  }
+}
+
+impl KeySource {
+  fn secret(&self) -> Result<Vec<u8>> {
+    match self {
+      KeySource::File(path) => std::fs::read(path).with_path(path),
+      KeySource::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+      KeySource::Prompt => read_passphrase().map(String::into_bytes),
+    }
+  }
+}
+
+/// The key `secret` derives with the salt in `text`, read from `path`.
+fn stored_key(path: &Path, text: &str, secret: &[u8]) -> Result<Key> {
+  let invalid = |message: String| Error::InvalidConfig {
+    path: path.to_path_buf(),
+    message,
+  };
+  let stored: KeyCheck = toml::from_str(text).map_err(|e| invalid(e.to_string()))?;
+  let salt = hex::decode(&stored.salt).map_err(|e| invalid(e.to_string()))?;
+  let key = derive(secret, &salt).map_err(invalid)?;
+  if key.check() != stored.check {
+    return Err(Error::WrongKey);
+  }
+  Ok(key)
+}
+
+fn derive(secret: &[u8], salt: &[u8]) -> Result<Key, String> {
+  let mut key = [0; 32];
+  Argon2::default()
+    .hash_password_into(secret, salt, &mut key)
+    .map_err(|e| e.to_string())?;
+  Ok(Key(key))
+}
+
+/// Read a passphrase on the terminal without echoing it.
+fn read_passphrase() -> Result<String> {
+  use nix::sys::termios::{self, LocalFlags, SetArg};
+
+  let stdin = std::io::stdin();
+  if !stdin.is_terminal() {
+    return Err(Error::KeyRequired);
+  }
+  let tty = Path::new("/dev/stdin");
+  let original = termios::tcgetattr(stdin.as_fd())
+    .map_err(std::io::Error::from)
+    .with_path(tty)?;
+  let mut silent = original.clone();
+  silent.local_flags.remove(LocalFlags::ECHO);
+  termios::tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &silent)
+    .map_err(std::io::Error::from)
+    .with_path(tty)?;
+
+  eprint!("Passphrase: ");
+  let mut passphrase = String::new();
+  let read = stdin.read_line(&mut passphrase);
+  // put echo back before anything can fail
+  let _ = termios::tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &original);
+  eprintln!();
+  read.with_path(tty)?;
+  Ok(passphrase.trim_end_matches('\n').to_string())
+}
+
+/// Seal `source` into `target`. `original_size` is the size of the image as
+/// CRIU wrote it, before any compression, it is kept readable in the header.
+pub(crate) fn encrypt_file(
+  key: &Key,
+  source: &Path,
+  target: &Path,
+  original_size: u64,
+) -> Result<()> {
+  let mut input = BufReader::new(File::open(source).with_path(source)?);
+  let mut remaining = std::fs::metadata(source).with_path(source)?.len();
+  let mut output = BufWriter::new(File::create(target).with_path(target)?);
+
+  let mut nonce = [0; NONCE_LEN];
+  OsRng.fill_bytes(&mut nonce);
+  let mut header = Vec::with_capacity(HEADER_LEN);
+  header.extend_from_slice(MAGIC);
+  header.extend_from_slice(&original_size.to_le_bytes());
+  header.extend_from_slice(&nonce);
+  output.write_all(&header).with_path(target)?;
+
+  // the last chunk is sealed apart, so even an empty or chunk-aligned
+  // file ends with one and truncation is caught
+  let mut encryptor = EncryptorBE32::from_aead(key.cipher(), &nonce.into());
+  let mut chunk = vec![0; CHUNK_LEN];
+  while remaining > CHUNK_LEN as u64 {
+    input.read_exact(&mut chunk).with_path(source)?;
+    let sealed = encryptor
+      .encrypt_next(Payload {
+        msg: &chunk,
+        aad: &header,
+      })
+      .map_err(|_| std::io::Error::other("encryption failed"))
+      .with_path(target)?;
+    output.write_all(&sealed).with_path(target)?;
+    remaining -= CHUNK_LEN as u64;
+  }
+  let last = &mut chunk[..remaining as usize];
+  input.read_exact(last).with_path(source)?;
+  let sealed = encryptor
+    .encrypt_last(Payload {
+      msg: last,
+      aad: &header,
+    })
+    .map_err(|_| std::io::Error::other("encryption failed"))
+    .with_path(target)?;
+  output.write_all(&sealed).with_path(target)?;
+  output.flush().with_path(target)
+}
+
+/// Open `source` into `target`, failing on any chunk that does not
+/// authenticate.
+pub(crate) fn decrypt_file(key: &Key, source: &Path, target: &Path) -> Result<()> {
+  let mut input = BufReader::new(File::open(source).with_path(source)?);
+  let len = std::fs::metadata(source).with_path(source)?.len();
+  let header = read_header(&mut input, source)?;
+  let mut remaining = len.saturating_sub(HEADER_LEN as u64);
+  let mut output = BufWriter::new(File::create(target).with_path(target)?);
+
+  let nonce: [u8; NONCE_LEN] = header[HEADER_LEN - NONCE_LEN..]
+    .try_into()
+    .map_err(|_| Error::Decrypt(source.to_path_buf()))?;
+  let mut decryptor = DecryptorBE32::from_aead(key.cipher(), &nonce.into());
+  let sealed_len = CHUNK_LEN + TAG_LEN;
+  let mut chunk = vec![0; sealed_len];
+  while remaining > sealed_len as u64 {
+    input.read_exact(&mut chunk).with_path(source)?;
+    let opened = decryptor
+      .decrypt_next(Payload {
+        msg: &chunk,
+        aad: &header,
+      })
+      .map_err(|_| Error::Decrypt(source.to_path_buf()))?;
+    output.write_all(&opened).with_path(target)?;
+    remaining -= sealed_len as u64;
+  }
+  let last = &mut chunk[..remaining as usize];
+  input.read_exact(last).with_path(source)?;
+  let opened = decryptor
+    .decrypt_last(Payload {
+      msg: last,
+      aad: &header,
+    })
+    .map_err(|_| Error::Decrypt(source.to_path_buf()))?;
+  output.write_all(&opened).with_path(target)?;
+  output.flush().with_path(target)
+}
+
+/// Size of the image sealed in `path` as CRIU wrote it, read from the
+/// header without the key.
+pub(crate) fn original_size(path: &Path) -> Result<u64> {
+  let mut input = File::open(path).with_path(path)?;
+  let header = read_header(&mut input, path)?;
+  let size = header[MAGIC.len()..MAGIC.len() + 8]
+    .try_into()
+    .map_err(|_| Error::Decrypt(path.to_path_buf()))?;
+  Ok(u64::from_le_bytes(size))
+}
+
+fn read_header(input: &mut impl Read, path: &Path) -> Result<[u8; HEADER_LEN]> {
+  let mut header = [0; HEADER_LEN];
+  input
+    .read_exact(&mut header)
+    .map_err(|_| Error::Decrypt(path.to_path_buf()))?;
+  if &header[..MAGIC.len()] != MAGIC {
+    return Err(Error::Decrypt(path.to_path_buf()));
+  }
+  Ok(header)
+}
@@ -0,0 +1,222 @@
+//! Content-addressed chunks of page images, shared between checkpoints.
+//!
+//! A deduplicated `pages-<n>.img` is stored as `pages-<n>.img.chunks`, the
+//! list of its chunks, and every chunk once in `.chunks/<ab>/<sha256>` in the
+//! store root, zstd compressed as `<sha256>.zst` when compression is on.
+//! Chunks end on page boundaries picked from the content of the pages, so a
+//! page mapped in the middle of the memory only changes the chunks around it.
+//! A chunk is removed once no list in the store refers to it any more.
+
+use crate::error::{Error, IoContext, Result};
+use crate::store::CheckpointStore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub(crate) const CHUNKS_DIR: &str = ".chunks";
+pub(crate) const EXTENSION: &str = "chunks";
+
+/// Chunks are cut between pages, CRIU writes whole pages.
+const PAGE_SIZE: usize = 4096;
+/// a chunk ends after a page whose fingerprint has these bits clear, 16
+/// pages on average
+const BOUNDARY_MASK: u64 = 0xf;
+const MIN_PAGES: usize = 4;
+const MAX_PAGES: usize = 64;
+
+/// What a `.chunks` file holds.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct ChunkList {
+  /// size of the image as CRIU wrote it
+  pub size: u64,
+  /// hex SHA-256 of every chunk, in order
+  pub chunks: Vec<String>,
+}
+
+impl ChunkList {
+  /// The list in `path`, every entry has to be a hash, they name files.
+  pub fn load(path: &Path) -> Result<Self> {
+    let corrupt = |message: String| Error::CorruptMeta {
+      path: path.to_path_buf(),
+      message,
+    };
+    let text = std::fs::read_to_string(path).with_path(path)?;
+    let list: ChunkList = toml::from_str(&text).map_err(|e| corrupt(e.to_string()))?;
+    let is_hash = |hash: &String| {
+      hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    if let Some(bad) = list.chunks.iter().find(|hash| !is_hash(hash)) {
+      return Err(corrupt(format!("{:?} is not a chunk hash", bad)));
+    }
+    Ok(list)
+  }
+
+  fn save(&self, path: &Path) -> Result<()> {
+    let text = toml::to_string(self).map_err(|e| Error::CorruptMeta {
+      path: path.to_path_buf(),
+      message: e.to_string(),
+    })?;
+    std::fs::write(path, text).with_path(path)
+  }
+}
+
+/// Split `source` into chunks under `chunks_dir`, writing their list to
+/// `target`. Chunks already there are not written again.
+pub(crate) fn split_file(
+  source: &Path,
+  target: &Path,
+  chunks_dir: &Path,
+  compress: Option<i32>,
+) -> Result<()> {
+  let mut input = BufReader::new(File::open(source).with_path(source)?);
+  let mut list = ChunkList::default();
+  let mut chunk = Vec::with_capacity(MAX_PAGES * PAGE_SIZE);
+  let mut page = vec![0; PAGE_SIZE];
+  loop {
+    let len = read_page(&mut input, &mut page).with_path(source)?;
+    if len == 0 {
+      break;
+    }
+    chunk.extend_from_slice(&page[..len]);
+    list.size += len as u64;
+    let pages = chunk.len().div_ceil(PAGE_SIZE);
+    let boundary = pages >= MIN_PAGES && fingerprint(&page[..len]) & BOUNDARY_MASK == 0;
+    if boundary || pages >= MAX_PAGES || len < PAGE_SIZE {
+      list.chunks.push(store_chunk(chunks_dir, &chunk, compress)?);
+      chunk.clear();
+    }
+  }
+  if !chunk.is_empty() {
+    list.chunks.push(store_chunk(chunks_dir, &chunk, compress)?);
+  }
+  list.save(target)
+}
+
+/// Rebuild the image listed in `source` into `target`, checking every chunk
+/// against its hash.
+pub(crate) fn join_file(source: &Path, target: &Path, chunks_dir: &Path) -> Result<()> {
+  let list = ChunkList::load(source)?;
+  let mut output = BufWriter::new(File::create(target).with_path(target)?);
+  for hash in &list.chunks {
+    let chunk = load_chunk(chunks_dir, hash)?;
+    if hex_digest(&chunk) != *hash {
+      return Err(Error::BadChunk(hash.clone()));
+    }
+    output.write_all(&chunk).with_path(target)?;
+  }
+  output.flush().with_path(target)
+}
+
+/// Remove the chunks no list in the store refers to any more, lists of
+/// quarantined checkpoints included. Returns how many were removed. The
+/// caller holds the store lock.
+pub(crate) fn collect_garbage(store: &CheckpointStore) -> Result<usize> {
+  let chunks_dir = store.chunks_dir();
+  if !chunks_dir.is_dir() {
+    return Ok(0);
+  }
+  let mut referenced = HashSet::new();
+  for list in find_lists(store.root())? {
+    referenced.extend(ChunkList::load(&list)?.chunks);
+  }
+
+  let mut removed = 0;
+  for prefix in std::fs::read_dir(&chunks_dir).with_path(&chunks_dir)? {
+    let prefix = prefix.with_path(&chunks_dir)?.path();
+    for entry in std::fs::read_dir(&prefix).with_path(&prefix)? {
+      let path = entry.with_path(&prefix)?.path();
+      let name = path.file_name().unwrap_or_default().to_string_lossy();
+      let hash = name.strip_suffix(".zst").unwrap_or(&name);
+      if !referenced.contains(hash) {
+        std::fs::remove_file(&path).with_path(&path)?;
+        removed += 1;
+      }
+    }
+  }
+  Ok(removed)
+}
+
+/// Every `.chunks` file under `dir`, the chunks themselves left out.
+fn find_lists(dir: &Path) -> Result<Vec<PathBuf>> {
+  let mut lists = Vec::new();
+  for entry in std::fs::read_dir(dir).with_path(dir)? {
+    let entry = entry.with_path(dir)?;
+    let path = entry.path();
+    let file_type = entry.file_type().with_path(&path)?;
+    if file_type.is_dir() && entry.file_name() != CHUNKS_DIR {
+      lists.extend(find_lists(&path)?);
+    } else if file_type.is_file() && path.extension().is_some_and(|ext| ext == EXTENSION) {
+      lists.push(path);
+    }
+  }
+  Ok(lists)
+}
+
+/// Fill `page`, short only at the end of the input. Returns the bytes read.
+fn read_page(input: &mut impl Read, page: &mut [u8]) -> std::io::Result<usize> {
+  let mut len = 0;
+  while len < page.len() {
+    match input.read(&mut page[len..])? {
+      0 => break,
+      n => len += n,
+    }
+  }
+  Ok(len)
+}
+
+/// Cheap hash of a page to pick chunk boundaries with, FNV-1a over words.
+fn fingerprint(page: &[u8]) -> u64 {
+  page.chunks(8).fold(0xcbf29ce484222325, |hash, word| {
+    let mut bytes = [0; 8];
+    bytes[..word.len()].copy_from_slice(word);
+    (hash ^ u64::from_le_bytes(bytes)).wrapping_mul(0x100000001b3)
+  })
+}
+
+fn store_chunk(chunks_dir: &Path, chunk: &[u8], compress: Option<i32>) -> Result<String> {
+  let hash = hex_digest(chunk);
+  let path = chunk_path(chunks_dir, &hash);
+  if path.exists() || with_zst(&path).exists() {
+    return Ok(hash);
+  }
+  let dir = path.parent().unwrap_or(chunks_dir);
+  std::fs::create_dir_all(dir).with_path(dir)?;
+  let (path, data) = match compress {
+    Some(level) => (
+      with_zst(&path),
+      zstd::bulk::compress(chunk, level).with_path(&path)?,
+    ),
+    None => (path, chunk.to_vec()),
+  };
+  // a chunk is complete once it has its name, others may share it already
+  let tmp = path.with_extension("tmp");
+  std::fs::write(&tmp, data).with_path(&tmp)?;
+  std::fs::rename(&tmp, &path).with_path(&path)?;
+  Ok(hash)
+}
+
+fn load_chunk(chunks_dir: &Path, hash: &str) -> Result<Vec<u8>> {
+  let path = chunk_path(chunks_dir, hash);
+  let compressed = with_zst(&path);
+  if compressed.exists() {
+    let data = std::fs::read(&compressed).with_path(&compressed)?;
+    zstd::decode_all(data.as_slice()).with_path(&compressed)
+  } else {
+    std::fs::read(&path).with_path(&path)
+  }
+}
+
+fn chunk_path(chunks_dir: &Path, hash: &str) -> PathBuf {
+  chunks_dir.join(&hash[..2]).join(hash)
+}
+
+fn with_zst(path: &Path) -> PathBuf {
+  path.with_extension("zst")
+}
+
+fn hex_digest(data: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(data))
+}
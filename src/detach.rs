@@ -0,0 +1,234 @@
+//! Restoring a process detached from the terminal that ran hcriu.
+//!
+//! CRIU hands a restored shell job the terminal CRIU itself runs on. A
+//! detached restore runs CRIU in a session of its own on a fresh
+//! pseudo-terminal, and once the process runs the master side goes to a
+//! forked hcriu process that keeps it open, since the process would get
+//! SIGHUP when it closes. That holder copies what the process writes to
+//! `output.log`, or drops it, and in `pty` mode types in what is written to
+//! the `input` FIFO. It exits with the process.
+//!
+//! Where to find the process again is recorded in `.detached/<pid>/` of the
+//! store, next to the log and the FIFO.
+
+use crate::error::{Error, IoContext, Result};
+use crate::options::load_toml;
+use crate::store::CheckpointStore;
+use crate::utils::CheckpointMeta;
+use chrono::Utc;
+use clap::ValueEnum;
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::pty::openpty;
+use nix::sys::stat::Mode;
+use nix::unistd::{ForkResult, fork, mkfifo, read, setsid, ttyname, write};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+/// Run dirs of detached processes, one per pid.
+pub const DETACHED_DIR: &str = ".detached";
+const RECORD_FILE: &str = "detached.toml";
+const OUTPUT_FILE: &str = "output.log";
+const INPUT_FIFO: &str = "input";
+
+/// Where the stdio of a detached restore goes.
+#[derive(Debug, ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Detach {
+  /// what the process writes goes to `output.log`
+  Log,
+  /// what the process writes is dropped
+  Null,
+  /// like `log`, and what is written to the `input` FIFO is typed in
+  Pty,
+}
+
+/// A process restored detached, saved as `detached.toml` in its run dir.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Detached {
+  pub pid: i32,
+  pub checkpoint_id: String,
+  pub mode: Detach,
+  /// pseudo-terminal the process runs on
+  pub tty: PathBuf,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub output: Option<PathBuf>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub input: Option<PathBuf>,
+  /// pid of the hcriu process keeping the terminal open
+  pub holder: i32,
+  pub restored_at: String,
+}
+
+impl Detached {
+  /// The record of `pid`, if it was restored detached.
+  pub fn load(store: &CheckpointStore, pid: i32) -> Result<Self> {
+    load_toml(&run_dir(store, pid).join(RECORD_FILE))
+  }
+
+  fn save(&self, path: &Path) -> Result<()> {
+    let toml = toml::to_string(self).map_err(|e| Error::CorruptMeta {
+      path: path.to_path_buf(),
+      message: e.to_string(),
+    })?;
+    std::fs::write(path, toml).with_path(path)
+  }
+}
+
+pub fn run_dir(store: &CheckpointStore, pid: i32) -> PathBuf {
+  store.root().join(DETACHED_DIR).join(pid.to_string())
+}
+
+/// A fresh pseudo-terminal for CRIU to restore `pid` on.
+pub(crate) struct Terminal {
+  master: OwnedFd,
+  slave: OwnedFd,
+  tty: PathBuf,
+  mode: Detach,
+  run_dir: PathBuf,
+  pid: i32,
+  checkpoint_id: String,
+}
+
+impl Terminal {
+  pub(crate) fn open(store: &CheckpointStore, meta: &CheckpointMeta, mode: Detach) -> Result<Self> {
+    let ptmx = Path::new("/dev/ptmx");
+    let pty = openpty(None, None)
+      .map_err(std::io::Error::from)
+      .with_path(ptmx)?;
+    let tty = ttyname(&pty.slave)
+      .map_err(std::io::Error::from)
+      .with_path(ptmx)?;
+    Ok(Terminal {
+      master: pty.master,
+      slave: pty.slave,
+      tty,
+      mode,
+      run_dir: run_dir(store, meta.pid),
+      pid: meta.pid,
+      checkpoint_id: meta.checkpoint_id.clone(),
+    })
+  }
+
+  /// The side CRIU runs on.
+  pub(crate) fn slave(&self) -> BorrowedFd<'_> {
+    self.slave.as_fd()
+  }
+
+  /// Hand the master side to a holder process once the process runs on the
+  /// terminal, and record where to find it.
+  pub(crate) fn keep(self) -> Result<Detached> {
+    let run_dir = &self.run_dir;
+    // a record left by an earlier process with the same pid is stale
+    let _ = std::fs::remove_dir_all(run_dir);
+    std::fs::create_dir_all(run_dir).with_path(run_dir)?;
+    let (output_path, output) = match self.mode {
+      Detach::Null => {
+        let null = Path::new("/dev/null");
+        let file = OpenOptions::new().write(true).open(null).with_path(null)?;
+        (None, file)
+      }
+      Detach::Log | Detach::Pty => {
+        let path = run_dir.join(OUTPUT_FILE);
+        let file = File::create(&path).with_path(&path)?;
+        (Some(path), file)
+      }
+    };
+    let (input_path, input) = match self.mode {
+      Detach::Pty => {
+        let path = run_dir.join(INPUT_FIFO);
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)
+          .map_err(std::io::Error::from)
+          .with_path(&path)?;
+        // opened for writing too, so writers closing it is no end of input
+        let file = OpenOptions::new()
+          .read(true)
+          .write(true)
+          .open(&path)
+          .with_path(&path)?;
+        (Some(path), Some(file))
+      }
+      Detach::Log | Detach::Null => (None, None),
+    };
+
+    // the terminal hangs up once only the process has it open
+    drop(self.slave);
+    // SAFETY: the child only makes system calls on fds opened above and
+    // exits without unwinding, so locks other threads held don't matter
+    let holder = match unsafe { fork() } {
+      Ok(ForkResult::Child) => {
+        // out of the session of hcriu, its terminal closing is no concern
+        let _ = setsid();
+        relay(&self.master, &output, input.as_ref());
+        // SAFETY: exits the child without running the parent's destructors
+        unsafe { nix::libc::_exit(0) }
+      }
+      Ok(ForkResult::Parent { child }) => child,
+      Err(e) => return Err(std::io::Error::from(e)).with_path(&self.tty),
+    };
+
+    let detached = Detached {
+      pid: self.pid,
+      checkpoint_id: self.checkpoint_id,
+      mode: self.mode,
+      tty: self.tty,
+      output: output_path,
+      input: input_path,
+      holder: holder.as_raw(),
+      restored_at: Utc::now().to_string(),
+    };
+    detached.save(&run_dir.join(RECORD_FILE))?;
+    Ok(detached)
+  }
+}
+
+/// Copy what the process writes to `output` and what arrives on `input` to
+/// the process, until the terminal hangs up. Allocates nothing, it runs in
+/// a forked child.
+fn relay(master: &OwnedFd, output: &File, input: Option<&File>) {
+  let mut buf = [0; 4096];
+  loop {
+    let mut fds = [
+      PollFd::new(master.as_fd(), PollFlags::POLLIN),
+      PollFd::new(
+        input.map_or(master.as_fd(), |i| i.as_fd()),
+        PollFlags::POLLIN,
+      ),
+    ];
+    let watched = if input.is_some() { 2 } else { 1 };
+    match poll(&mut fds[..watched], PollTimeout::NONE) {
+      Ok(_) | Err(Errno::EINTR) => {}
+      Err(_) => return,
+    }
+    let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+    let (from_process, from_input) = (ready(&fds[0]), watched == 2 && ready(&fds[1]));
+    if from_process && !copy(master.as_fd(), output.as_fd(), &mut buf) {
+      return;
+    }
+    if let Some(input) = input.filter(|_| from_input) {
+      copy(input.as_fd(), master.as_fd(), &mut buf);
+    }
+  }
+}
+
+/// Copy one read of `from` to `to`, false once `from` is done.
+fn copy(from: BorrowedFd, to: BorrowedFd, buf: &mut [u8]) -> bool {
+  let len = match read(from, buf) {
+    Ok(0) => return false,
+    Ok(len) => len,
+    Err(Errno::EINTR) => return true,
+    // EIO once the terminal hung up
+    Err(_) => return false,
+  };
+  let mut written = 0;
+  while written < len {
+    match write(to, &buf[written..len]) {
+      Ok(n) => written += n,
+      Err(Errno::EINTR) => {}
+      Err(_) => break,
+    }
+  }
+  true
+}
@@ -1,64 +1,396 @@
-use crate::utils;
+use crate::backend::{CriuBackend, DumpRequest};
+use crate::error::{Error, IoContext, Result};
+use crate::image::DumpStats;
+use crate::manifest::Manifest;
+use crate::options::DumpOptions;
+use crate::pack::{self, Packing};
+use crate::page_server::{self, Hello, Reply};
+use crate::store::CheckpointStore;
+use crate::utils::CheckpointStatus;
+use crate::{archive, merge, quota, sign, utils};
 use humantime::Duration;
-use rust_criu::Criu;
-use std::os::unix::io::AsRawFd;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
 use std::thread;
 
 pub fn handle_dump(
-  criu: &mut Criu,
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
   pid: i32,
   interval: Option<Duration>,
   tag: Option<String>,
-  leave_running: bool,
-) {
+  options: &DumpOptions,
+) -> Result<()> {
   if let Some(interval) = interval {
     let interval_ms = interval.as_millis() as u64;
+    let mut parent: Option<utils::CheckpointMeta> = None;
     loop {
-      dump_once(criu, pid, &tag, true);
+      parent = Some(dump_periodic(
+        store,
+        criu,
+        pid,
+        &tag,
+        options,
+        parent.as_ref(),
+      )?);
       thread::sleep(std::time::Duration::from_millis(interval_ms));
     }
   } else {
-    dump_once(criu, pid, &tag, leave_running);
+    dump_once(store, criu, pid, &tag, options, None)?;
+    Ok(())
   }
 }
 
-fn dump_once(criu: &mut Criu, pid: i32, tag: &Option<String>, leave_running: bool) {
-  let meta = utils::CheckpointMeta::new(pid, tag);
-  let checkpoint_dir = utils::get_hcriu_dir().join(meta.checkpoint_id.clone());
-  if !checkpoint_dir.exists() {
-    std::fs::create_dir_all(&checkpoint_dir).unwrap();
+/// One round of a periodic dump: a dump on top of `parent`, the previous
+/// round, then the retention configured for its tag. Returns the new
+/// checkpoint.
+pub fn dump_periodic(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  pid: i32,
+  tag: &Option<String>,
+  options: &DumpOptions,
+  parent: Option<&utils::CheckpointMeta>,
+) -> Result<utils::CheckpointMeta> {
+  // every periodic dump tracks memory changes, so the next one only needs
+  // to write the pages dirtied since its parent
+  let options = options.clone().leave_running(true).track_mem(true);
+  let meta = dump_once(store, criu, pid, tag, &options, parent)?;
+  // a failed prune must not stop the job, the next round tries again
+  if let Err(e) = apply_retention(store, &meta) {
+    eprintln!("Failed to apply retention: {}", e);
+  }
+  Ok(meta)
+}
+
+/// Dump `pid` once and write the checkpoint to `output` as an archive, see
+/// [`crate::archive`], instead of adding it to the store. The images are
+/// written plain, and only go through a scratch dir.
+pub fn handle_dump_stream(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  pid: i32,
+  tag: Option<String>,
+  options: &DumpOptions,
+  output: &mut dyn Write,
+) -> Result<()> {
+  let mut meta = utils::CheckpointMeta::new(pid, &tag, None)?;
+  meta.options = options.clone();
+  let signing_key = if options.sign {
+    Some(sign::signing_key(store)?)
   } else {
-    eprintln!("Checkpoint {} already exists", meta.checkpoint_id);
-    std::process::exit(1);
+    None
+  };
+  let estimate = quota::estimate_dump_size(pid, None);
+  let scratch = pack::scratch_dir("hcriu-stream-", estimate, false)?;
+  let checkpoint_dir = scratch.path().join(&meta.checkpoint_id);
+  std::fs::create_dir_all(&checkpoint_dir).with_path(&checkpoint_dir)?;
+  let meta_file = checkpoint_dir.join("meta.toml");
+  meta.save(&meta_file)?;
+
+  if let Err(e) = run_criu(criu, pid, &checkpoint_dir, &mut meta, options, None, None) {
+    let kept = scratch.keep().join(&meta.checkpoint_id);
+    eprintln!("Dump failed, logs are kept in {}", kept.display());
+    return Err(e);
+  }
+  Manifest::build(&checkpoint_dir)?.save(&checkpoint_dir)?;
+  if let Some(key) = &signing_key {
+    sign::sign_checkpoint(&checkpoint_dir, &meta, key)?;
+  }
+  meta.status = CheckpointStatus::Complete;
+  meta.save(&meta_file)?;
+
+  let checkpoints = vec![meta.checkpoint_id.clone()];
+  let stream = Path::new("-");
+  archive::write_checkpoints(scratch.path(), checkpoints, stream, &mut *output)?
+    .flush()
+    .with_path(stream)?;
+  eprintln!("Dumped checkpoint {} to the stream", meta.checkpoint_id);
+  Ok(())
+}
+
+/// Dump `pid` once into the store of the hcriu page server at `server`, see
+/// [`crate::page_server`]. CRIU sends the pages straight to it and the rest
+/// of the checkpoint follows, nothing is kept here. The server signs the
+/// checkpoint with its own key when asked to.
+pub fn handle_dump_remote(
+  criu: &mut dyn CriuBackend,
+  pid: i32,
+  tag: Option<String>,
+  options: &DumpOptions,
+  server: &str,
+) -> Result<()> {
+  let net_error = |e: std::io::Error| Error::PageServer {
+    address: server.to_string(),
+    message: e.to_string(),
+  };
+  // pre-dump passes would need an image dir of their own on the server
+  if options.pre_dump > 0 {
+    return Err(Error::PageServer {
+      address: server.to_string(),
+      message: "pre-dump passes can't be sent to a page server".to_string(),
+    });
   }
-  let checkpoint_fd = std::fs::File::open(&checkpoint_dir).unwrap();
-  criu.set_work_dir_fd(checkpoint_fd.as_raw_fd());
+  let mut meta = utils::CheckpointMeta::new(pid, &tag, None)?;
+  meta.options = options.clone();
+  let mut control = TcpStream::connect(server).map_err(net_error)?;
+  let peer = control.peer_addr().map_err(net_error)?;
+  let hello = Hello {
+    meta: meta.clone(),
+    estimate: quota::estimate_dump_size(pid, None),
+  };
+  page_server::send(&mut control, &hello, peer)?;
+  let ready: Reply = page_server::receive(&mut control, peer)?;
+  let Some(port) = ready.check(peer)?.port else {
+    return Err(Error::PageServer {
+      address: server.to_string(),
+      message: "no port to send pages to".to_string(),
+    });
+  };
 
+  // only the images besides the pages are written here
+  let scratch = pack::scratch_dir("hcriu-remote-", 0, false)?;
+  let checkpoint_dir = scratch.path().join(&meta.checkpoint_id);
+  std::fs::create_dir_all(&checkpoint_dir).with_path(&checkpoint_dir)?;
   let meta_file = checkpoint_dir.join("meta.toml");
-  meta.save(&meta_file).unwrap();
+  meta.save(&meta_file)?;
+  let address = SocketAddr::new(peer.ip(), port);
+  if let Err(e) = run_criu(
+    criu,
+    pid,
+    &checkpoint_dir,
+    &mut meta,
+    options,
+    None,
+    Some(address),
+  ) {
+    let kept = scratch.keep().join(&meta.checkpoint_id);
+    eprintln!("Dump failed, logs are kept in {}", kept.display());
+    return Err(e);
+  }
+  meta.status = CheckpointStatus::Complete;
+  meta.save(&meta_file)?;
+
+  let checkpoints = vec![meta.checkpoint_id.clone()];
+  archive::write_checkpoints(scratch.path(), checkpoints, Path::new(server), &mut control)?
+    .flush()
+    .map_err(net_error)?;
+  let done: Reply = page_server::receive(&mut control, peer)?;
+  done.check(peer)?;
+  println!("Dump success to {} at {}", meta.checkpoint_id, server);
+  Ok(())
+}
+
+fn dump_once(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  pid: i32,
+  tag: &Option<String>,
+  options: &DumpOptions,
+  parent: Option<&utils::CheckpointMeta>,
+) -> Result<utils::CheckpointMeta> {
+  let parent_id = parent.map(|p| p.checkpoint_id.clone());
+  let mut meta = utils::CheckpointMeta::new(pid, tag, parent_id)?;
+  meta.options = options.clone();
+  let _lock = store.lock()?;
+  // get the keys first, a wrong one should not cost a freeze, and under the
+  // lock, the first key fixes the salt of the store
+  let packing = Packing::for_dump(store, options)?;
+  let signing_key = if options.sign {
+    Some(sign::signing_key(store)?)
+  } else {
+    None
+  };
+  let estimate = quota::estimate_dump_size(pid, parent);
+  quota::make_room(store, &meta.tag, meta.parent_id.as_deref(), estimate)?;
+  let checkpoint_dir = store.checkpoint_dir(&meta.checkpoint_id);
+  let staging_dir = store.staging_dir(&meta.checkpoint_id);
+  if checkpoint_dir.exists() || staging_dir.exists() {
+    return Err(Error::CheckpointExists(meta.checkpoint_id));
+  }
+  std::fs::create_dir_all(&staging_dir).with_path(&staging_dir)?;
+  let meta_file = staging_dir.join("meta.toml");
+  meta.save(&meta_file)?;
+
+  // CRIU reads the plain images of the parent, so a packed chain is
+  // unpacked to a scratch dir for the dump and the `parent` links are
+  // pointed back at the store afterwards
+  let staged_parent = match &meta.parent_id {
+    Some(parent_id) => pack::stage_chain(store, &store.parent_chain(parent_id)?)?,
+    None => None,
+  };
+  let parent_img = meta
+    .parent_id
+    .as_ref()
+    .map(|parent_id| match &staged_parent {
+      Some(staged) => staged
+        .path()
+        .join(parent_id)
+        .join("image")
+        .to_string_lossy()
+        .into_owned(),
+      None => format!("../../{}/image", parent_id),
+    });
+
+  // nothing shows up in the store until criu succeeded, a failed dump only
+  // leaves its logs in quarantine
+  let result = run_criu(
+    criu,
+    pid,
+    &staging_dir,
+    &mut meta,
+    options,
+    parent_img,
+    None,
+  )
+  .and_then(|()| match (&meta.parent_id, &staged_parent) {
+    (Some(parent_id), Some(_)) => relink_parents(&staging_dir, parent_id),
+    _ => Ok(()),
+  });
+  if let Err(e) = result {
+    meta.status = CheckpointStatus::Failed;
+    meta.save(&meta_file)?;
+    let quarantine_dir = store.quarantine(&meta.checkpoint_id)?;
+    eprintln!("Dump failed, logs are kept in {}", quarantine_dir.display());
+    return Err(e);
+  }
+  pack::pack_checkpoint(&staging_dir, &packing)?;
+  Manifest::build(&staging_dir)?.save(&staging_dir)?;
+  if let Some(key) = &signing_key {
+    sign::sign_checkpoint(&staging_dir, &meta, key)?;
+  }
+  meta.status = CheckpointStatus::Complete;
+  meta.save(&meta_file)?;
+  store.commit(&meta.checkpoint_id)?;
+  println!("Dump success to {}", checkpoint_dir.display());
+
+  Ok(meta)
+}
+
+/// Run the pre-dump passes and the final dump in `checkpoint_dir`, recording
+/// their stats in `meta`. With `page_server` the final dump sends its pages
+/// there.
+fn run_criu(
+  criu: &mut dyn CriuBackend,
+  pid: i32,
+  checkpoint_dir: &Path,
+  meta: &mut utils::CheckpointMeta,
+  options: &DumpOptions,
+  mut parent_img: Option<String>,
+  page_server: Option<SocketAddr>,
+) -> Result<()> {
+  // pre-dump passes copy memory while the process keeps running, each one
+  // on top of the previous, so the final dump only writes what is left
+  let mut last_pages = None;
+  for pass in 1..=options.pre_dump {
+    let pass_dir = checkpoint_dir.join("pre-dump").join(pass.to_string());
+    std::fs::create_dir_all(&pass_dir).with_path(&pass_dir)?;
+    // pre-dump dirs sit one level deeper than image dir
+    let pass_parent = parent_img.as_ref().map(|p| {
+      if Path::new(p).is_absolute() {
+        p.clone()
+      } else {
+        format!("../{}", p)
+      }
+    });
+    criu.pre_dump(&DumpRequest {
+      pid,
+      work_dir: checkpoint_dir,
+      image_dir: &pass_dir,
+      parent_img: pass_parent.as_deref(),
+      log_file: &format!("pre-dump-{}.log", pass),
+      leave_running: false,
+      track_mem: true,
+      page_server: None,
+      options,
+    })?;
+
+    let pages = read_stats(&pass_dir).pages_written;
+    // progress goes to stderr, stdout may carry a stream
+    eprintln!("Pre-dump pass {} wrote {} pages", pass, pages);
+    meta.pages_written.push(pages);
+    parent_img = Some(format!("../pre-dump/{}", pass));
+
+    // stop once the dirty set no longer shrinks, more passes won't help
+    if last_pages.is_some_and(|last| pages >= last) {
+      break;
+    }
+    last_pages = Some(pages);
+  }
 
   let image_dir = checkpoint_dir.join("image");
-  std::fs::create_dir_all(&image_dir).unwrap();
-  let image_fd = std::fs::File::open(&image_dir).unwrap();
-  criu.set_images_dir_fd(image_fd.as_raw_fd());
-
-  criu.set_log_level(0);
-  criu.set_log_file("dump.log".to_string());
-
-  criu.set_pid(pid);
-  criu.set_leave_running(leave_running);
-  criu.set_shell_job(true);
-  criu.set_ext_unix_sk(true);
-
-  criu
-    .dump()
-    .map_err(|e| {
-      eprintln!("Failed to dump: {}", e);
-      std::process::exit(1);
-    })
-    .and_then(|_| {
-      println!("Dump success to {}", checkpoint_dir.display());
-      Ok(())
-    })
-    .unwrap();
+  std::fs::create_dir_all(&image_dir).with_path(&image_dir)?;
+
+  // only pages dirtied since the parent dump are written, the rest are
+  // read through the `parent` link, which criu resolves from image dir
+  criu.dump(&DumpRequest {
+    pid,
+    work_dir: checkpoint_dir,
+    image_dir: &image_dir,
+    parent_img: parent_img.as_deref(),
+    log_file: &options.log_file,
+    leave_running: options.leave_running,
+    track_mem: options.track_mem || options.pre_dump > 0,
+    page_server,
+    options,
+  })?;
+
+  let stats = read_stats(&image_dir);
+  meta.freeze_time_us = Some(stats.frozen_time);
+  meta.pages_written.push(stats.pages_written);
+  Ok(())
+}
+
+/// Point `parent` links made to a scratch copy of the parent chain back at
+/// the store. Pre-dump passes sit one level deeper than the image dir.
+fn relink_parents(checkpoint_dir: &Path, parent_id: &str) -> Result<()> {
+  let mut links = vec![(
+    checkpoint_dir.join("image").join("parent"),
+    format!("../../{}/image", parent_id),
+  )];
+  let pre_dump_dir = checkpoint_dir.join("pre-dump");
+  if pre_dump_dir.is_dir() {
+    for entry in std::fs::read_dir(&pre_dump_dir).with_path(&pre_dump_dir)? {
+      links.push((
+        entry.with_path(&pre_dump_dir)?.path().join("parent"),
+        format!("../../../{}/image", parent_id),
+      ));
+    }
+  }
+  for (link, target) in links {
+    if std::fs::read_link(&link).is_ok_and(|current| current.is_absolute()) {
+      std::fs::remove_file(&link).with_path(&link)?;
+      std::os::unix::fs::symlink(target, &link).with_path(&link)?;
+    }
+  }
+  Ok(())
+}
+
+/// Prune the dumped series with the policy configured for its tag, if any.
+fn apply_retention(store: &CheckpointStore, meta: &utils::CheckpointMeta) -> Result<()> {
+  // read every round, so editing the config takes effect in a running job
+  let config = store.config()?;
+  let Some(retention) = config.retention_for(&meta.tag) else {
+    return Ok(());
+  };
+  let _lock = store.lock()?;
+  let plan = merge::plan_merge(
+    &store.list()?,
+    &meta.tag,
+    Some(meta.pid),
+    &retention.policy,
+    &merge::MergeOptions::new().squash(retention.squash),
+  )?;
+  if !plan.merged().is_empty() {
+    merge::apply_merge(store, &plan)?;
+  }
+  Ok(())
+}
+
+fn read_stats(image_dir: &Path) -> DumpStats {
+  DumpStats::read(image_dir).unwrap_or_else(|e| {
+    eprintln!("Failed to read dump stats: {}", e);
+    DumpStats::default()
+  })
 }
@@ -0,0 +1,248 @@
+use crate::utils::CheckpointMeta;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong in hcriu, the binaries decide how to report it.
+#[derive(Debug)]
+pub enum Error {
+  /// CRIU itself failed, `action` is dump, pre-dump or restore
+  Criu {
+    action: &'static str,
+    message: String,
+  },
+  /// no process with this pid, or its cmdline can't be read
+  ProcessNotFound(i32),
+  /// reading or writing the checkpoint store failed
+  Io {
+    path: PathBuf,
+    source: io::Error,
+  },
+  /// the checkpoints directory does not exist
+  StoreNotFound(PathBuf),
+  /// home directory can't be determined to expand `~`
+  HomeNotFound,
+  CheckpointExists(String),
+  CheckpointNotFound(String),
+  /// the dump of this checkpoint did not finish, its images can't be used
+  Incomplete(String),
+  /// the checkpoint is pinned and the action was not forced
+  Pinned(String),
+  PrefixTooShort(String),
+  /// a revision names more than one checkpoint
+  AmbiguousRevision {
+    revision: String,
+    candidates: Vec<CheckpointMeta>,
+  },
+  /// a revision does not follow the syntax of [`crate::revision`]
+  InvalidRevision {
+    revision: String,
+    message: String,
+  },
+  /// `meta.toml` is missing fields or is not valid TOML
+  CorruptMeta {
+    path: PathBuf,
+    message: String,
+  },
+  /// `dump_time` in `meta.toml` can't be parsed
+  InvalidTimestamp {
+    checkpoint_id: String,
+    value: String,
+  },
+  /// an options or config file is not valid TOML for what it describes
+  InvalidConfig {
+    path: PathBuf,
+    message: String,
+  },
+  /// the merge filter matched no checkpoint
+  NothingToMerge,
+  /// a dump would break a store quota that can't or may not evict
+  QuotaExceeded(String),
+  /// deleting needs `--yes` when stdin is not a terminal
+  ConfirmationRequired(usize),
+  /// `hcriu fsck` found problems and was not asked to repair them
+  StoreDamaged(usize),
+  /// images of a checkpoint to restore differ from its manifest
+  Tampered {
+    checkpoint_id: String,
+    mismatches: Vec<String>,
+  },
+  /// `hcriu verify` found checkpoints that differ from their manifest
+  VerifyFailed(usize),
+  /// the store is encrypted and no key was given, nor can one be asked for
+  KeyRequired,
+  /// the key does not match the one the store was encrypted with
+  WrongKey,
+  /// an encrypted image does not authenticate, it was damaged or swapped
+  Decrypt(PathBuf),
+  /// no private tmpfs has room for the decrypted images, in bytes
+  NoScratchSpace(u64),
+  /// a shared chunk no longer matches its hash
+  BadChunk(String),
+  /// the signature of a checkpoint does not match its manifest or metadata
+  BadSignature(String),
+  /// no trusted key signed a checkpoint and the store denies those
+  Untrusted {
+    checkpoint_id: String,
+    reason: String,
+  },
+  /// an archive to import is not one `hcriu export` wrote, or is damaged
+  InvalidArchive {
+    path: PathBuf,
+    message: String,
+  },
+  /// talking to a page server, or serving one, failed
+  PageServer {
+    address: String,
+    message: String,
+  },
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Criu { action, message } => write!(f, "Failed to {}: {}", action, message),
+      Error::ProcessNotFound(pid) => write!(f, "Process {} not found", pid),
+      Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+      Error::StoreNotFound(path) => {
+        write!(f, "Checkpoints directory {} not found", path.display())
+      }
+      Error::HomeNotFound => write!(f, "Home directory not found"),
+      Error::CheckpointExists(id) => write!(f, "Checkpoint {} already exists", id),
+      Error::CheckpointNotFound(id) => write!(f, "Checkpoint {} not found", id),
+      Error::Incomplete(id) => write!(f, "Checkpoint {} is incomplete", id),
+      Error::Pinned(id) => write!(f, "Checkpoint {} is pinned, unpin it first", id),
+      Error::PrefixTooShort(prefix) => {
+        write!(f, "Prefix '{}' must be at least 4 characters long", prefix)
+      }
+      Error::AmbiguousRevision {
+        revision,
+        candidates,
+      } => {
+        write!(
+          f,
+          "Ambiguous revision: {} checkpoints match '{}':",
+          candidates.len(),
+          revision
+        )?;
+        for candidate in candidates {
+          write!(
+            f,
+            "\n  {}  {}  {}",
+            candidate.checkpoint_id, candidate.dump_time, candidate.tag
+          )?;
+        }
+        Ok(())
+      }
+      Error::InvalidRevision { revision, message } => {
+        write!(f, "Invalid revision '{}': {}", revision, message)
+      }
+      Error::CorruptMeta { path, message } => {
+        write!(f, "Corrupt metadata {}: {}", path.display(), message)
+      }
+      Error::InvalidTimestamp {
+        checkpoint_id,
+        value,
+      } => write!(
+        f,
+        "Invalid dump time '{}' of checkpoint {}",
+        value, checkpoint_id
+      ),
+      Error::InvalidConfig { path, message } => {
+        write!(f, "Invalid config {}: {}", path.display(), message)
+      }
+      Error::NothingToMerge => write!(f, "No checkpoints to merge"),
+      Error::QuotaExceeded(message) => write!(f, "Quota exceeded: {}", message),
+      Error::ConfirmationRequired(count) => write!(
+        f,
+        "Refusing to delete {} checkpoints without confirmation, pass --yes",
+        count
+      ),
+      Error::StoreDamaged(count) => write!(
+        f,
+        "Found {} problems in the store, run hcriu fsck --repair",
+        count
+      ),
+      Error::Tampered {
+        checkpoint_id,
+        mismatches,
+      } => {
+        write!(
+          f,
+          "Images of checkpoint {} do not match its manifest, pass --force to restore anyway:",
+          checkpoint_id
+        )?;
+        for mismatch in mismatches {
+          write!(f, "\n  {}", mismatch)?;
+        }
+        Ok(())
+      }
+      Error::VerifyFailed(count) => {
+        write!(f, "{} checkpoints do not match their manifest", count)
+      }
+      Error::KeyRequired => write!(
+        f,
+        "The store is encrypted, pass --key-file or set HCRIU_PASSPHRASE"
+      ),
+      Error::WrongKey => write!(f, "Wrong key for this store"),
+      Error::Decrypt(path) => write!(f, "Failed to decrypt {}", path.display()),
+      Error::NoScratchSpace(bytes) => write!(
+        f,
+        "No private tmpfs has {} free for the decrypted images",
+        bytesize::ByteSize(*bytes)
+      ),
+      Error::BadChunk(hash) => write!(f, "Chunk {} is damaged", hash),
+      Error::BadSignature(id) => write!(
+        f,
+        "Signature of checkpoint {} does not match, pass --force to restore anyway",
+        id
+      ),
+      Error::Untrusted {
+        checkpoint_id,
+        reason,
+      } => write!(
+        f,
+        "Checkpoint {} {}, and the store denies unsigned checkpoints",
+        checkpoint_id, reason
+      ),
+      Error::InvalidArchive { path, message } => {
+        write!(f, "Invalid archive {}: {}", path.display(), message)
+      }
+      Error::PageServer { address, message } => write!(f, "Page server {}: {}", address, message),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+impl Error {
+  pub(crate) fn criu(action: &'static str, e: Box<dyn std::error::Error>) -> Self {
+    Error::Criu {
+      action,
+      message: e.to_string(),
+    }
+  }
+}
+
+/// Attach the path being worked on to an `io::Error`.
+pub(crate) trait IoContext<T> {
+  fn with_path(self, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+  fn with_path(self, path: &Path) -> Result<T> {
+    self.map_err(|source| Error::Io {
+      path: path.to_path_buf(),
+      source,
+    })
+  }
+}
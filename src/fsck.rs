@@ -0,0 +1,334 @@
+//! Consistency check of a checkpoint store, and repair of what it finds.
+//!
+//! The check runs under the store lock, so a staging dir seen here belongs to
+//! a dump that died rather than to one still running, unless the dump keeps
+//! it locked while it waits on the network. Repair never deletes:
+//! broken entries are moved to `.quarantine`, and metadata that is missing or
+//! unreadable is rebuilt from the images when they are there.
+
+use crate::crypt::ENCRYPTION_FILE;
+use crate::error::{Error, IoContext, Result};
+use crate::image::DumpStats;
+use crate::pack;
+use crate::sign::TRUSTED_KEYS_FILE;
+use crate::store::{CONFIG_FILE, CheckpointStore, STAGING_PREFIX};
+use crate::utils::{CheckpointMeta, CheckpointStatus};
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Tag given to checkpoints whose metadata had to be rebuilt.
+pub const RECOVERED_TAG: &str = "recovered";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+  /// a file or directory in the store that is not a checkpoint
+  Orphan(PathBuf),
+  /// a checkpoint directory with images but no `meta.toml`
+  MissingMeta(PathBuf),
+  /// `meta.toml` can't be read
+  CorruptMeta { path: PathBuf, message: String },
+  /// the image dir is missing, has no memory images or a dangling `parent`
+  MissingImages {
+    checkpoint_id: String,
+    path: PathBuf,
+  },
+  /// the parent is gone or broken itself, so the pages it held are lost
+  BrokenChain {
+    checkpoint_id: String,
+    parent_id: String,
+    path: PathBuf,
+  },
+  /// a dump that never finished, left in staging or not marked complete
+  HalfWritten(PathBuf),
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Problem::Orphan(path) => write!(f, "Orphan entry {}", path.display()),
+      Problem::MissingMeta(path) => write!(f, "Missing metadata in {}", path.display()),
+      Problem::CorruptMeta { path, message } => {
+        write!(f, "Corrupt metadata {}: {}", path.display(), message)
+      }
+      Problem::MissingImages { checkpoint_id, .. } => {
+        write!(f, "Missing images of checkpoint {}", checkpoint_id)
+      }
+      Problem::BrokenChain {
+        checkpoint_id,
+        parent_id,
+        ..
+      } => write!(
+        f,
+        "Broken parent chain of checkpoint {}: parent {} is missing or broken",
+        checkpoint_id, parent_id
+      ),
+      Problem::HalfWritten(path) => write!(f, "Half-written dump {}", path.display()),
+    }
+  }
+}
+
+/// Check the store, repairing what is found with `repair`. Without it, any
+/// problem is an error so scripts notice.
+pub fn handle_fsck(store: &CheckpointStore, repair: bool) -> Result<()> {
+  let _lock = store.lock()?;
+  let mut problems = check_store(store)?;
+  if problems.is_empty() {
+    println!("No problems found in {}", store.root().display());
+    return Ok(());
+  }
+  for problem in &problems {
+    println!("{}", problem);
+  }
+  if !repair {
+    return Err(Error::StoreDamaged(problems.len()));
+  }
+
+  // rebuilt metadata can point to a parent that is gone, so check again
+  // until nothing is left, every round removes or fixes entries
+  while !problems.is_empty() {
+    repair_store(store, &problems)?;
+    problems = check_store(store)?;
+    for problem in &problems {
+      println!("{}", problem);
+    }
+  }
+  println!("Store repaired");
+  Ok(())
+}
+
+/// Every problem in the store, the caller holds the store lock.
+pub fn check_store(store: &CheckpointStore) -> Result<Vec<Problem>> {
+  let root = store.root();
+  let mut entries = std::fs::read_dir(root)
+    .with_path(root)?
+    .map(|entry| entry.map(|e| e.path()))
+    .collect::<std::io::Result<Vec<_>>>()
+    .with_path(root)?;
+  entries.sort();
+
+  let mut problems = Vec::new();
+  let mut checkpoints = Vec::new();
+  for path in entries {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name.starts_with(STAGING_PREFIX) {
+      // a dump received by a page server waits without the store lock
+      if !CheckpointStore::staging_in_use(&path)? {
+        problems.push(Problem::HalfWritten(path));
+      }
+      continue;
+    }
+    // the lock file and quarantine
+    let store_file = [CONFIG_FILE, ENCRYPTION_FILE, TRUSTED_KEYS_FILE].contains(&name.as_ref());
+    if name.starts_with('.') || store_file {
+      continue;
+    }
+    if !path.is_dir() || !is_checkpoint_id(&name) {
+      problems.push(Problem::Orphan(path));
+      continue;
+    }
+
+    let meta_path = path.join("meta.toml");
+    if !meta_path.exists() {
+      if path.join("image").is_dir() {
+        problems.push(Problem::MissingMeta(path));
+      } else {
+        problems.push(Problem::Orphan(path));
+      }
+      continue;
+    }
+    let meta = match CheckpointMeta::load(&meta_path) {
+      Ok(meta) => meta,
+      Err(e) => {
+        problems.push(Problem::CorruptMeta {
+          path: meta_path,
+          message: e.to_string(),
+        });
+        continue;
+      }
+    };
+    if meta.status != CheckpointStatus::Complete {
+      problems.push(Problem::HalfWritten(path));
+    } else if !has_images(&path.join("image")) {
+      problems.push(Problem::MissingImages {
+        checkpoint_id: meta.checkpoint_id,
+        path,
+      });
+    } else {
+      checkpoints.push((meta, path));
+    }
+  }
+
+  // a checkpoint is broken once any ancestor is, so drop broken ones until
+  // every remaining parent is healthy
+  let mut healthy = checkpoints
+    .iter()
+    .map(|(c, _)| c.checkpoint_id.clone())
+    .collect::<HashSet<_>>();
+  loop {
+    let broken = checkpoints
+      .iter()
+      .filter(|(c, _)| healthy.contains(&c.checkpoint_id))
+      .filter_map(|(c, path)| {
+        let parent_id = c.parent_id.as_ref()?;
+        (!healthy.contains(parent_id)).then(|| Problem::BrokenChain {
+          checkpoint_id: c.checkpoint_id.clone(),
+          parent_id: parent_id.clone(),
+          path: path.clone(),
+        })
+      })
+      .collect::<Vec<_>>();
+    if broken.is_empty() {
+      break;
+    }
+    for problem in broken {
+      if let Problem::BrokenChain { checkpoint_id, .. } = &problem {
+        healthy.remove(checkpoint_id);
+      }
+      problems.push(problem);
+    }
+  }
+  Ok(problems)
+}
+
+/// Quarantine or rebuild what `check_store` found, the caller holds the store
+/// lock.
+pub fn repair_store(store: &CheckpointStore, problems: &[Problem]) -> Result<()> {
+  for problem in problems {
+    match problem {
+      Problem::HalfWritten(path) => {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // staged dumps lose their images like a failed dump does
+        let target = match name.strip_prefix(STAGING_PREFIX) {
+          Some(checkpoint_id) => store.quarantine(checkpoint_id)?,
+          None => store.quarantine_entry(path)?,
+        };
+        println!("Quarantined {} to {}", path.display(), target.display());
+      }
+      Problem::MissingMeta(path) => rebuild_or_quarantine(store, path)?,
+      Problem::CorruptMeta { path, .. } => {
+        let checkpoint_dir = path.parent().unwrap_or(path);
+        rebuild_or_quarantine(store, checkpoint_dir)?;
+      }
+      Problem::Orphan(path)
+      | Problem::MissingImages { path, .. }
+      | Problem::BrokenChain { path, .. } => {
+        let target = store.quarantine_entry(path)?;
+        println!("Quarantined {} to {}", path.display(), target.display());
+      }
+    }
+  }
+  Ok(())
+}
+
+fn rebuild_or_quarantine(store: &CheckpointStore, checkpoint_dir: &Path) -> Result<()> {
+  let Some(meta) = rebuild_meta(checkpoint_dir)? else {
+    let target = store.quarantine_entry(checkpoint_dir)?;
+    println!(
+      "Quarantined {} to {}",
+      checkpoint_dir.display(),
+      target.display()
+    );
+    return Ok(());
+  };
+
+  // keep the unreadable file around, someone may want to fix it by hand
+  let meta_path = checkpoint_dir.join("meta.toml");
+  if meta_path.exists() {
+    let quarantine_dir = store.quarantine_dir(&meta.checkpoint_id);
+    std::fs::create_dir_all(&quarantine_dir).with_path(&quarantine_dir)?;
+    let target = quarantine_dir.join("meta.toml");
+    std::fs::rename(&meta_path, &target).with_path(&meta_path)?;
+  }
+  meta.save(&meta_path)?;
+  println!(
+    "Rebuilt metadata of checkpoint {} with tag {}",
+    meta.checkpoint_id, RECOVERED_TAG
+  );
+  Ok(())
+}
+
+/// Metadata recovered from the images of a checkpoint, `None` when there are
+/// not enough of them to restore anything.
+fn rebuild_meta(checkpoint_dir: &Path) -> Result<Option<CheckpointMeta>> {
+  let image_dir = checkpoint_dir.join("image");
+  if !has_images(&image_dir) {
+    return Ok(None);
+  }
+  let checkpoint_id = checkpoint_dir
+    .file_name()
+    .unwrap_or_default()
+    .to_string_lossy()
+    .into_owned();
+
+  // CRIU writes one pagemap per process, the root of the tree has the
+  // lowest pid in all but the most unusual trees
+  let pid = std::fs::read_dir(&image_dir)
+    .with_path(&image_dir)?
+    .flatten()
+    .filter_map(|entry| {
+      let name = entry.file_name().to_string_lossy().into_owned();
+      pack::unpacked_name(&name)
+        .strip_prefix("pagemap-")?
+        .strip_suffix(".img")?
+        .parse::<i32>()
+        .ok()
+    })
+    .min();
+  let Some(pid) = pid else {
+    return Ok(None);
+  };
+
+  let modified = std::fs::metadata(&image_dir)
+    .and_then(|m| m.modified())
+    .with_path(&image_dir)?;
+  let stats = DumpStats::read(&image_dir).ok();
+  Ok(Some(CheckpointMeta {
+    checkpoint_id,
+    pid,
+    cmd: "unknown".to_string(),
+    tag: RECOVERED_TAG.to_string(),
+    dump_time: DateTime::<Utc>::from(modified).to_string(),
+    parent_id: parent_from_link(checkpoint_dir),
+    freeze_time_us: stats.as_ref().map(|s| s.frozen_time),
+    pages_written: stats.iter().map(|s| s.pages_written).collect(),
+    ..Default::default()
+  }))
+}
+
+/// The parent checkpoint the `parent` link of the images points to, through
+/// the pre-dump passes if there are any.
+fn parent_from_link(checkpoint_dir: &Path) -> Option<String> {
+  let pre_dump_dir = checkpoint_dir.join("pre-dump");
+  let passes = std::fs::read_dir(&pre_dump_dir).map_or(0, |dir| dir.count());
+  let mut link = checkpoint_dir.join("image").join("parent");
+  for _ in 0..=passes {
+    let target = std::fs::read_link(&link).ok()?;
+    let mut components = target.iter().rev();
+    let last = components.next()?;
+    if last == "image" {
+      return Some(components.next()?.to_string_lossy().into_owned());
+    }
+    // `../pre-dump/N` or `../N`, whose own link leads further back
+    link = pre_dump_dir.join(last).join("parent");
+  }
+  None
+}
+
+/// The image dir holds memory images, and its `parent` link resolves.
+fn has_images(image_dir: &Path) -> bool {
+  let Ok(entries) = std::fs::read_dir(image_dir) else {
+    return false;
+  };
+  let has_pagemap = entries
+    .flatten()
+    .any(|entry| entry.file_name().to_string_lossy().starts_with("pagemap-"));
+  let parent = image_dir.join("parent");
+  has_pagemap && (parent.symlink_metadata().is_err() || parent.exists())
+}
+
+/// Checkpoint ids are hex SHA-256 digests.
+pub(crate) fn is_checkpoint_id(name: &str) -> bool {
+  name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
@@ -0,0 +1,315 @@
+//! Minimal readers and writers for CRIU image files.
+//!
+//! A CRIU image starts with one or two magic numbers followed by protobuf
+//! messages, each prefixed with its length as a little endian `u32`. Only the
+//! handful of messages hcriu looks at are decoded here.
+
+use std::io::{self, Read};
+use std::path::Path;
+
+pub(crate) const IMG_COMMON_MAGIC: u32 = 0x54564319;
+const IMG_SERVICE_MAGIC: u32 = 0x55105940;
+const STATS_MAGIC: u32 = 0x57093306;
+pub(crate) const PAGEMAP_MAGIC: u32 = 0x56084025;
+
+pub(crate) const PE_PARENT: u32 = 1 << 0;
+pub(crate) const PE_PRESENT: u32 = 1 << 2;
+
+/// Figures CRIU records in `stats-dump` after a dump or pre-dump.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DumpStats {
+  /// time spent freezing the process tree, in microseconds
+  pub freezing_time: u64,
+  /// time the process tree stayed frozen, in microseconds
+  pub frozen_time: u64,
+  pub pages_scanned: u64,
+  pub pages_skipped_parent: u64,
+  pub pages_written: u64,
+}
+
+impl DumpStats {
+  /// Read the `stats-dump` file CRIU leaves in `image_dir`.
+  pub fn read(image_dir: &Path) -> io::Result<Self> {
+    let data = std::fs::read(image_dir.join("stats-dump"))?;
+    let mut reader = data.as_slice();
+    let (_, magic) = read_magic(&mut reader)?;
+    if magic != STATS_MAGIC {
+      return Err(invalid_data("not a stats image"));
+    }
+
+    let mut stats = DumpStats::default();
+    for (field, value) in decode_message(read_message(&mut reader)?)? {
+      // StatsEntry.dump is a nested DumpStatsEntry
+      let (1, Field::Bytes(dump)) = (field, value) else {
+        continue;
+      };
+      for (field, value) in decode_message(dump)? {
+        let Field::Varint(value) = value else {
+          continue;
+        };
+        match field {
+          1 => stats.freezing_time = value,
+          2 => stats.frozen_time = value,
+          5 => stats.pages_scanned = value,
+          6 => stats.pages_skipped_parent = value,
+          7 => stats.pages_written = value,
+          _ => {}
+        }
+      }
+    }
+    Ok(stats)
+  }
+
+  /// Write a `stats-dump` file the way CRIU does, used by the fake backend.
+  pub(crate) fn write(&self, image_dir: &Path) -> io::Result<()> {
+    let mut dump = Vec::new();
+    encode_field(&mut dump, 1, self.freezing_time);
+    encode_field(&mut dump, 2, self.frozen_time);
+    encode_field(&mut dump, 3, 0);
+    encode_field(&mut dump, 4, 0);
+    encode_field(&mut dump, 5, self.pages_scanned);
+    encode_field(&mut dump, 6, self.pages_skipped_parent);
+    encode_field(&mut dump, 7, self.pages_written);
+    let mut entry = Vec::new();
+    encode_bytes(&mut entry, 1, &dump);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&IMG_SERVICE_MAGIC.to_le_bytes());
+    out.extend_from_slice(&STATS_MAGIC.to_le_bytes());
+    write_message(&mut out, &entry);
+    std::fs::write(image_dir.join("stats-dump"), out)
+  }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PagemapEntry {
+  pub(crate) vaddr: u64,
+  pub(crate) nr_pages: u32,
+  /// set by CRIU versions that predate `flags`
+  pub(crate) in_parent: Option<bool>,
+  pub(crate) flags: Option<u32>,
+}
+
+impl PagemapEntry {
+  pub(crate) fn is_parent(&self) -> bool {
+    match self.flags {
+      Some(flags) => flags & PE_PARENT != 0,
+      None => self.in_parent.unwrap_or(false),
+    }
+  }
+
+  pub(crate) fn is_present(&self) -> bool {
+    match self.flags {
+      Some(flags) => flags & PE_PRESENT != 0,
+      None => !self.in_parent.unwrap_or(false),
+    }
+  }
+
+  pub(crate) fn end(&self, page_size: u64) -> u64 {
+    self.vaddr + self.nr_pages as u64 * page_size
+  }
+
+  /// Same kind of entry as `self`, moved to a new range and location.
+  pub(crate) fn with_location(&self, vaddr: u64, nr_pages: u32, in_parent: bool) -> Self {
+    let (flags, old_in_parent) = match self.flags {
+      Some(flags) => {
+        let flags = flags & !(PE_PARENT | PE_PRESENT);
+        let location = if in_parent { PE_PARENT } else { PE_PRESENT };
+        (Some(flags | location), None)
+      }
+      None => (None, Some(in_parent)),
+    };
+    PagemapEntry {
+      vaddr,
+      nr_pages,
+      in_parent: old_in_parent,
+      flags,
+    }
+  }
+}
+
+/// A `pagemap-*.img` file: a magic, a `pagemap_head` and `pagemap_entry`s.
+pub(crate) struct Pagemap {
+  /// `IMG_COMMON_MAGIC` or `IMG_SERVICE_MAGIC`, absent in old images
+  pub(crate) image_magic: Option<u32>,
+  pub(crate) magic: u32,
+  pub(crate) pages_id: u32,
+  pub(crate) entries: Vec<PagemapEntry>,
+}
+
+impl Pagemap {
+  pub(crate) fn parse(data: &[u8]) -> io::Result<Self> {
+    let mut reader = data;
+    let (image_magic, magic) = read_magic(&mut reader)?;
+    if magic != PAGEMAP_MAGIC {
+      return Err(invalid_data("not a pagemap image"));
+    }
+
+    let mut pages_id = 0;
+    for (field, value) in decode_message(read_message(&mut reader)?)? {
+      if let (1, Field::Varint(value)) = (field, value) {
+        pages_id = value as u32;
+      }
+    }
+
+    let mut entries = Vec::new();
+    while !reader.is_empty() {
+      let mut entry = PagemapEntry {
+        vaddr: 0,
+        nr_pages: 0,
+        in_parent: None,
+        flags: None,
+      };
+      for (field, value) in decode_message(read_message(&mut reader)?)? {
+        let Field::Varint(value) = value else {
+          continue;
+        };
+        match field {
+          1 => entry.vaddr = value,
+          2 => entry.nr_pages = value as u32,
+          3 => entry.in_parent = Some(value != 0),
+          4 => entry.flags = Some(value as u32),
+          _ => {}
+        }
+      }
+      entries.push(entry);
+    }
+
+    Ok(Pagemap {
+      image_magic,
+      magic,
+      pages_id,
+      entries,
+    })
+  }
+
+  pub(crate) fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Some(image_magic) = self.image_magic {
+      out.extend_from_slice(&image_magic.to_le_bytes());
+    }
+    out.extend_from_slice(&self.magic.to_le_bytes());
+
+    let mut head = Vec::new();
+    encode_field(&mut head, 1, self.pages_id as u64);
+    write_message(&mut out, &head);
+
+    for entry in &self.entries {
+      let mut msg = Vec::new();
+      encode_field(&mut msg, 1, entry.vaddr);
+      encode_field(&mut msg, 2, entry.nr_pages as u64);
+      if let Some(in_parent) = entry.in_parent {
+        encode_field(&mut msg, 3, in_parent as u64);
+      }
+      if let Some(flags) = entry.flags {
+        encode_field(&mut msg, 4, flags as u64);
+      }
+      write_message(&mut out, &msg);
+    }
+    out
+  }
+}
+
+pub(crate) enum Field<'a> {
+  Varint(u64),
+  Bytes(&'a [u8]),
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u32(reader: &mut &[u8]) -> io::Result<u32> {
+  let mut buf = [0u8; 4];
+  reader.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+/// Read the magic of an image, returns the common or service magic if the
+/// image has one, and the magic of the image type.
+pub(crate) fn read_magic(reader: &mut &[u8]) -> io::Result<(Option<u32>, u32)> {
+  let magic = read_u32(reader)?;
+  if magic == IMG_COMMON_MAGIC || magic == IMG_SERVICE_MAGIC {
+    Ok((Some(magic), read_u32(reader)?))
+  } else {
+    Ok((None, magic))
+  }
+}
+
+/// Read one size-prefixed protobuf message.
+pub(crate) fn read_message<'a>(reader: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+  let len = read_u32(reader)? as usize;
+  if reader.len() < len {
+    return Err(invalid_data("truncated image entry"));
+  }
+  let (msg, rest) = reader.split_at(len);
+  *reader = rest;
+  Ok(msg)
+}
+
+pub(crate) fn write_message(out: &mut Vec<u8>, msg: &[u8]) {
+  out.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+  out.extend_from_slice(msg);
+}
+
+fn read_varint(reader: &mut &[u8]) -> io::Result<u64> {
+  let mut value = 0u64;
+  for shift in (0..64).step_by(7) {
+    let (&byte, rest) = reader
+      .split_first()
+      .ok_or_else(|| invalid_data("truncated varint"))?;
+    *reader = rest;
+    value |= ((byte & 0x7f) as u64) << shift;
+    if byte & 0x80 == 0 {
+      return Ok(value);
+    }
+  }
+  Err(invalid_data("varint too long"))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    out.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+/// Decode the varint and length-delimited fields of a message, fixed size
+/// fields are skipped.
+pub(crate) fn decode_message(mut msg: &[u8]) -> io::Result<Vec<(u64, Field<'_>)>> {
+  let mut fields = Vec::new();
+  while !msg.is_empty() {
+    let key = read_varint(&mut msg)?;
+    let len = match key & 0x7 {
+      0 => {
+        fields.push((key >> 3, Field::Varint(read_varint(&mut msg)?)));
+        continue;
+      }
+      1 => 8,
+      2 => read_varint(&mut msg)? as usize,
+      5 => 4,
+      _ => return Err(invalid_data("unknown wire type")),
+    };
+    if msg.len() < len {
+      return Err(invalid_data("truncated field"));
+    }
+    let (value, rest) = msg.split_at(len);
+    if key & 0x7 == 2 {
+      fields.push((key >> 3, Field::Bytes(value)));
+    }
+    msg = rest;
+  }
+  Ok(fields)
+}
+
+pub(crate) fn encode_field(out: &mut Vec<u8>, field: u64, value: u64) {
+  write_varint(out, field << 3);
+  write_varint(out, value);
+}
+
+pub(crate) fn encode_bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+  write_varint(out, (field << 3) | 2);
+  write_varint(out, value.len() as u64);
+  out.extend_from_slice(value);
+}
@@ -1,13 +1,43 @@
+pub mod archive;
+pub mod backend;
+pub mod compress;
+pub mod config;
+pub mod crypt;
+pub mod dedup;
+pub mod detach;
 pub mod dump;
+mod error;
+pub mod fsck;
+pub mod image;
 pub mod list;
+pub mod manifest;
 pub mod merge;
+pub mod options;
+pub mod pack;
+pub mod page_server;
+pub mod pin;
+pub mod quota;
 pub mod restore;
+pub mod retention;
+pub mod revision;
+pub mod sign;
+pub mod squash;
+pub mod store;
 pub mod utils;
+pub mod verify;
 
 use clap::ValueEnum;
 
+pub use backend::{Backend, CliBackend, CriuBackend, FakeBackend, RpcBackend};
+pub use config::StoreConfig;
+pub use crypt::KeySource;
+pub use error::{Error, Result};
+pub use options::{DumpOptions, RestoreOptions};
+pub use retention::{RetentionPolicy, Timezone};
+pub use store::CheckpointStore;
+
 #[derive(Debug, ValueEnum, Clone)]
 pub enum Sort {
   Time,
   Pid,
-}
\ No newline at end of file
+}
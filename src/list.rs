@@ -1,10 +1,59 @@
-use crate::{Sort, utils};
+use crate::error::Result;
+use crate::store::CheckpointStore;
+use crate::{Sort, pack, revision, utils};
+use bytesize::ByteSize;
+use comfy_table::Table;
 
-pub fn handle_list(sort: Sort) {
-  let mut checkpoints = utils::get_all_checkpoints();
+/// Print the checkpoints with their size on disk, and the size their images
+/// take once decompressed.
+pub fn handle_list(store: &CheckpointStore, sort: Sort) -> Result<()> {
+  let mut checkpoints = store.list()?;
   match sort {
     Sort::Time => checkpoints.sort_by(|a, b| a.dump_time.cmp(&b.dump_time)),
     Sort::Pid => checkpoints.sort_by(|a, b| a.pid.cmp(&b.pid)),
   }
-  utils::print_checkpoints_table(checkpoints.iter().collect());
+
+  let mut table = Table::new();
+  let mut header = utils::CHECKPOINT_HEADER.to_vec();
+  header.extend(["Size", "Logical Size"]);
+  table.set_header(header);
+  for checkpoint in &checkpoints {
+    let checkpoint_dir = store.checkpoint_dir(&checkpoint.checkpoint_id);
+    let mut row = utils::checkpoint_row(checkpoint);
+    let id = &checkpoint.checkpoint_id;
+    row.push(size_cell(id, store.checkpoint_size(id)));
+    row.push(size_cell(id, pack::logical_size(&checkpoint_dir)));
+    table.add_row(row);
+  }
+  println!("{}", table);
+  Ok(())
+}
+
+/// `size` formatted for display, `?` with a warning when a damaged checkpoint
+/// could not be measured.
+fn size_cell(checkpoint_id: &str, size: Result<u64>) -> String {
+  match size {
+    Ok(size) => ByteSize(size).to_string(),
+    Err(e) => {
+      eprintln!("Failed to size {}, run hcriu fsck: {}", checkpoint_id, e);
+      "?".to_string()
+    }
+  }
+}
+
+/// Print the metadata of the checkpoint `revision` names, see
+/// [`crate::revision`], and the chain it restores from.
+pub fn handle_inspect(store: &CheckpointStore, revision: &str) -> Result<()> {
+  let meta = revision::resolve(store, revision)?;
+  let checkpoint_dir = store.checkpoint_dir(&meta.checkpoint_id);
+  println!("Checkpoint {}", meta.checkpoint_id);
+  println!("Directory: {}", checkpoint_dir.display());
+  let id = &meta.checkpoint_id;
+  println!("Size: {}", size_cell(id, store.checkpoint_size(id)));
+  println!(
+    "Logical size: {}",
+    size_cell(id, pack::logical_size(&checkpoint_dir))
+  );
+  utils::print_checkpoints_table(store.parent_chain(&meta.checkpoint_id)?.iter().collect());
+  Ok(())
 }
@@ -0,0 +1,129 @@
+//! SHA-256 of every image file of a checkpoint.
+//!
+//! The manifest is written once a dump is complete, and again whenever hcriu
+//! rewrites the images itself, as squashing does. Paths are relative to the
+//! checkpoint dir. `parent` links are recorded with their target, a link
+//! pointed elsewhere would restore other pages.
+
+use crate::error::{Error, IoContext, Result};
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Manifest {
+  /// hex SHA-256 by path relative to the checkpoint dir
+  pub files: BTreeMap<String, String>,
+  /// target of every `parent` link by path relative to the checkpoint dir
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub links: BTreeMap<String, String>,
+}
+
+/// A file that no longer matches the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+  Missing(String),
+  Changed(String),
+  /// present in the images but not in the manifest
+  Unexpected(String),
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Mismatch::Missing(path) => write!(f, "missing {}", path),
+      Mismatch::Changed(path) => write!(f, "changed {}", path),
+      Mismatch::Unexpected(path) => write!(f, "unexpected {}", path),
+    }
+  }
+}
+
+impl Manifest {
+  /// Hash the images currently in `checkpoint_dir`.
+  pub fn build(checkpoint_dir: &Path) -> Result<Self> {
+    let name = |path: &Path| {
+      path
+        .strip_prefix(checkpoint_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+    };
+    let mut files = BTreeMap::new();
+    for path in utils::image_files(checkpoint_dir)? {
+      files.insert(name(&path), hash_file(&path)?);
+    }
+    let mut links = BTreeMap::new();
+    for path in utils::image_links(checkpoint_dir)? {
+      let target = std::fs::read_link(&path).with_path(&path)?;
+      links.insert(name(&path), target.to_string_lossy().into_owned());
+    }
+    Ok(Manifest { files, links })
+  }
+
+  /// The manifest of `checkpoint_dir`, `None` for checkpoints dumped before
+  /// manifests existed.
+  pub fn load(checkpoint_dir: &Path) -> Result<Option<Self>> {
+    let path = checkpoint_dir.join(MANIFEST_FILE);
+    if !path.exists() {
+      return Ok(None);
+    }
+    let text = std::fs::read_to_string(&path).with_path(&path)?;
+    toml::from_str(&text)
+      .map(Some)
+      .map_err(|e| Error::CorruptMeta {
+        path,
+        message: e.to_string(),
+      })
+  }
+
+  pub fn save(&self, checkpoint_dir: &Path) -> Result<()> {
+    let path = checkpoint_dir.join(MANIFEST_FILE);
+    let text = toml::to_string(self).map_err(|e| Error::CorruptMeta {
+      path: path.clone(),
+      message: e.to_string(),
+    })?;
+    std::fs::write(&path, text).with_path(&path)
+  }
+
+  /// Rehash the images of `checkpoint_dir` and list what differs.
+  pub fn verify(&self, checkpoint_dir: &Path) -> Result<Vec<Mismatch>> {
+    let current = Manifest::build(checkpoint_dir)?;
+    let mut mismatches = compare(&self.files, &current.files);
+    mismatches.extend(compare(&self.links, &current.links));
+    Ok(mismatches)
+  }
+}
+
+/// What differs between `recorded` and `current` hashes or link targets.
+fn compare(
+  recorded: &BTreeMap<String, String>,
+  current: &BTreeMap<String, String>,
+) -> Vec<Mismatch> {
+  let mut mismatches = Vec::new();
+  for (path, value) in recorded {
+    match current.get(path) {
+      None => mismatches.push(Mismatch::Missing(path.clone())),
+      Some(current) if current != value => mismatches.push(Mismatch::Changed(path.clone())),
+      Some(_) => {}
+    }
+  }
+  for path in current.keys() {
+    if !recorded.contains_key(path) {
+      mismatches.push(Mismatch::Unexpected(path.clone()));
+    }
+  }
+  mismatches
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+  let mut file = File::open(path).with_path(path)?;
+  let mut hasher = Sha256::new();
+  std::io::copy(&mut file, &mut hasher).with_path(path)?;
+  Ok(format!("{:x}", hasher.finalize()))
+}
@@ -1,95 +1,414 @@
-use crate::utils;
+use crate::error::{Error, IoContext, Result};
+use crate::manifest::Manifest;
+use crate::pack::{self, Packing};
+use crate::retention::{KeepReason, RetentionPolicy};
+use crate::store::CheckpointStore;
+use crate::{revision, sign, squash, utils, verify};
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeOptions {
+  /// only print what would be merged
+  pub dry_run: bool,
+  /// fold merged parents into their incremental children instead of keeping them
+  pub squash: bool,
+  /// delete without asking on the terminal
+  pub yes: bool,
+  /// merge pinned checkpoints like any other
+  pub force: bool,
+}
+
+impl MergeOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn dry_run(mut self, dry_run: bool) -> Self {
+    self.dry_run = dry_run;
+    self
+  }
+
+  pub fn squash(mut self, squash: bool) -> Self {
+    self.squash = squash;
+    self
+  }
+
+  pub fn yes(mut self, yes: bool) -> Self {
+    self.yes = yes;
+    self
+  }
+
+  pub fn force(mut self, force: bool) -> Self {
+    self.force = force;
+    self
+  }
+}
+
+/// Delete the checkpoints of `tag` that `policy` does not keep, an empty
+/// policy keeps only the latest one. Checkpoints of other tags or pids are
+/// never touched, pinned ones only with `force`. Unless `yes` is set, the
+/// deletion is confirmed on the terminal after the preview.
 pub fn handle_merge(
-  tag: String,
-  dry_run: bool,
+  store: &CheckpointStore,
+  tag: &str,
+  pid: Option<i32>,
+  policy: &RetentionPolicy,
+  options: &MergeOptions,
+) -> Result<()> {
+  let _lock = store.lock()?;
+  let plan = plan_merge(&store.list()?, tag, pid, policy, options)?;
+  plan.print();
+
+  let merged_count = plan.merged().len();
+  if options.dry_run || merged_count == 0 {
+    return Ok(());
+  }
+  if !options.yes && !confirm(merged_count)? {
+    println!("Merge aborted");
+    return Ok(());
+  }
+  apply_merge(store, &plan)
+}
+
+/// What a merge will do, worked out without touching the store.
+pub struct MergePlan {
+  /// checkpoints matching the filter, oldest first
+  pub filtered: Vec<utils::CheckpointMeta>,
+  /// why the policy keeps each kept checkpoint, by id
+  pub reasons: HashMap<String, Vec<KeepReason>>,
+  /// checkpoints the policy drops but a survivor still reads pages from
+  pub required: Vec<RequiredParent>,
+  merged_ids: HashSet<String>,
+  squashes: Vec<SquashPlan>,
+}
+
+pub struct RequiredParent {
+  pub parent_id: String,
+  pub child_id: String,
+  /// the child does not match the filter, so it can't be squashed
+  pub outside_filter: bool,
+}
+
+/// Work out which checkpoints of `tag` (and `pid`) a merge deletes.
+pub fn plan_merge(
+  all_checkpoints: &[utils::CheckpointMeta],
+  tag: &str,
   pid: Option<i32>,
-  keep_daily: bool,
-  keep_hourly: bool,
-) {
-  let all_checkpoints = utils::get_all_checkpoints();
-  let filtered_checkpoints = all_checkpoints
+  policy: &RetentionPolicy,
+  options: &MergeOptions,
+) -> Result<MergePlan> {
+  let mut filtered = all_checkpoints
     .iter()
-    .filter(|c| c.tag == tag)
+    .filter(|c| c.tag == tag && pid.is_none_or(|pid| c.pid == pid))
+    .cloned()
     .collect::<Vec<_>>();
+  filtered.sort_by_key(|c| c.dump_time.clone());
 
-  let mut filtered_checkpoints = if let Some(pid) = pid {
-    filtered_checkpoints
-      .iter()
-      .filter(|c| c.pid == pid)
-      .map(|c| *c)
-      .collect::<Vec<_>>()
+  let policy = if policy.is_empty() {
+    policy.clone().keep_last(1)
   } else {
-    filtered_checkpoints
+    policy.clone()
   };
+  let mut reasons = policy.apply(&filtered.iter().collect::<Vec<_>>())?;
+  if !options.force {
+    for checkpoint in filtered.iter().filter(|c| c.pinned) {
+      reasons
+        .entry(checkpoint.checkpoint_id.clone())
+        .or_default()
+        .push(KeepReason::Pinned);
+    }
+  }
+  if reasons.is_empty() {
+    return Err(Error::NothingToMerge);
+  }
+
+  // only checkpoints matching the filter are ever deleted or rewritten
+  let mut merged_ids = filtered
+    .iter()
+    .filter(|c| !reasons.contains_key(&c.checkpoint_id))
+    .map(|c| c.checkpoint_id.clone())
+    .collect::<HashSet<_>>();
+  let in_scope = filtered
+    .iter()
+    .map(|c| c.checkpoint_id.as_str())
+    .collect::<HashSet<_>>();
 
-  filtered_checkpoints.sort_by(|a, b| a.dump_time.cmp(&b.dump_time));
-
-  // filter checkpoints by time
-  let keep_checkpoints = if keep_daily {
-    // keep the latest checkpoint of each day
-    let mut daily_checkpoints = Vec::new();
-    let mut current_day = String::new();
-    for checkpoint in filtered_checkpoints.iter().rev() {
-      let day = checkpoint.dump_time.split(' ').next().unwrap();
-      if day != current_day {
-        daily_checkpoints.push(*checkpoint);
-        current_day = day.to_string();
+  // incremental children read pages from their parent, so a merged parent
+  // either gets squashed into its surviving children or has to stay, and
+  // children outside the filter always keep their parent as it is
+  let mut required = Vec::new();
+  let outside_scope = all_checkpoints
+    .iter()
+    .filter(|c| !in_scope.contains(c.checkpoint_id.as_str()))
+    .collect::<Vec<_>>();
+  for (parent_id, child_id) in required_parents(all_checkpoints, &merged_ids, &outside_scope) {
+    merged_ids.remove(&parent_id);
+    required.push(RequiredParent {
+      parent_id,
+      child_id,
+      outside_filter: true,
+    });
+  }
+  let squashes = if options.squash {
+    plan_squashes(all_checkpoints, &merged_ids)
+  } else {
+    let survivors = all_checkpoints
+      .iter()
+      .filter(|c| !merged_ids.contains(&c.checkpoint_id))
+      .collect::<Vec<_>>();
+    for (parent_id, child_id) in required_parents(all_checkpoints, &merged_ids, &survivors) {
+      merged_ids.remove(&parent_id);
+      required.push(RequiredParent {
+        parent_id,
+        child_id,
+        outside_filter: false,
+      });
+    }
+    Vec::new()
+  };
+
+  Ok(MergePlan {
+    filtered,
+    reasons,
+    required,
+    merged_ids,
+    squashes,
+  })
+}
+
+impl MergePlan {
+  pub fn merged(&self) -> Vec<&utils::CheckpointMeta> {
+    self
+      .filtered
+      .iter()
+      .filter(|c| self.merged_ids.contains(&c.checkpoint_id))
+      .collect()
+  }
+
+  pub fn kept(&self) -> Vec<&utils::CheckpointMeta> {
+    self
+      .filtered
+      .iter()
+      .filter(|c| !self.merged_ids.contains(&c.checkpoint_id))
+      .collect()
+  }
+
+  /// Print why checkpoints are kept and what will be merged.
+  pub fn print(&self) {
+    for checkpoint in self.filtered.iter().rev() {
+      if let Some(reasons) = self.reasons.get(&checkpoint.checkpoint_id) {
+        println!(
+          "Keeping checkpoint {} ({}): {}",
+          &checkpoint.checkpoint_id[..7],
+          checkpoint.dump_time,
+          reasons
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+        );
       }
     }
-    daily_checkpoints
-  } else if keep_hourly {
-    // keep the latest checkpoint of each hour
-    let mut hourly_checkpoints = Vec::new();
-    let mut current_hour = String::new();
-    for checkpoint in filtered_checkpoints.iter().rev() {
-      let hour = checkpoint
-        .dump_time
-        .split(' ')
-        .nth(1)
-        .unwrap()
-        .split(':')
-        .next()
-        .unwrap();
-      if hour != current_hour {
-        hourly_checkpoints.push(*checkpoint);
-        current_hour = hour.to_string();
+    for required in &self.required {
+      if required.outside_filter {
+        println!(
+          "Keeping checkpoint {} as parent of {}, which is outside the filter",
+          &required.parent_id[..7],
+          &required.child_id[..7]
+        );
+      } else {
+        println!(
+          "Keeping checkpoint {} as parent of {}, use --squash to fold it into its children",
+          &required.parent_id[..7],
+          &required.child_id[..7]
+        );
       }
     }
-    hourly_checkpoints
-  } else {
-    // keep only the latest checkpoint
-    vec![
-      *filtered_checkpoints
-        .iter()
-        .max_by_key(|c| &c.dump_time)
-        .unwrap(),
-    ]
-  };
+    for plan in &self.squashes {
+      println!(
+        "Checkpoint {} will absorb the pages of {}",
+        &plan.checkpoint_id[..7],
+        plan
+          .folded
+          .iter()
+          .map(|id| id[..7].to_string())
+          .collect::<Vec<_>>()
+          .join(", ")
+      );
+    }
+    println!("The following checkpoints will be merged:");
+    utils::print_checkpoints_table(self.merged());
+    println!("The following checkpoints will be kept:");
+    utils::print_checkpoints_table(self.kept());
+  }
+}
 
-  if keep_checkpoints.is_empty() {
-    eprintln!("No checkpoints to merge");
-    std::process::exit(1);
+/// Squash and delete as planned, the caller holds the store lock.
+pub fn apply_merge(store: &CheckpointStore, plan: &MergePlan) -> Result<()> {
+  // squash before deleting anything, so a failed squash loses no pages
+  for squash in &plan.squashes {
+    apply_squash(store, squash)?;
+    println!("Squashed checkpoint {}", squash.checkpoint_id);
+  }
+  let merged = plan.merged();
+  for c in &merged {
+    store.delete(&c.checkpoint_id)?;
+    println!("Deleted checkpoint {}", c.checkpoint_id);
   }
+  println!("Merged {:?} checkpoints", merged.len());
+  Ok(())
+}
 
-  // Fix: Use Vec<&CheckpointMeta> since .iter() returns references
-  let merged_checkpoints = all_checkpoints
+/// Delete the checkpoint `revision` names, see [`crate::revision`]. Its
+/// pages are folded into the checkpoints that use it as their parent, and a
+/// pinned one is only deleted with `force`.
+pub fn handle_delete(store: &CheckpointStore, revision: &str, force: bool) -> Result<()> {
+  let _lock = store.lock()?;
+  let meta = revision::resolve(store, revision)?;
+  if meta.pinned && !force {
+    return Err(Error::Pinned(meta.checkpoint_id));
+  }
+  delete_folding(store, &store.list()?, &meta.checkpoint_id)?;
+  println!("Deleted checkpoint {}", meta.checkpoint_id);
+  Ok(())
+}
+
+/// Delete one checkpoint, folding its pages into the checkpoints that use it
+/// as their parent. The caller holds the store lock.
+pub(crate) fn delete_folding(
+  store: &CheckpointStore,
+  all_checkpoints: &[utils::CheckpointMeta],
+  checkpoint_id: &str,
+) -> Result<()> {
+  let deleted = HashSet::from([checkpoint_id.to_string()]);
+  for squash in plan_squashes(all_checkpoints, &deleted) {
+    apply_squash(store, &squash)?;
+  }
+  store.delete(checkpoint_id)
+}
+
+/// Ask on the terminal before deleting, refuse when there is no one to ask.
+fn confirm(count: usize) -> Result<bool> {
+  let stdin = std::io::stdin();
+  if !stdin.is_terminal() {
+    return Err(Error::ConfirmationRequired(count));
+  }
+  print!("Delete {} checkpoints? [y/N] ", count);
+  let _ = std::io::stdout().flush();
+  // a failed read counts as no
+  let mut answer = String::new();
+  Ok(stdin.read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// A surviving checkpoint whose merged ancestors are folded into it.
+struct SquashPlan {
+  checkpoint_id: String,
+  /// merged ancestors, nearest parent first
+  folded: Vec<String>,
+  /// first ancestor that survives the merge, if any
+  new_parent: Option<String>,
+}
+
+/// Merged ancestors of `survivors`, as `(parent, child)` pairs.
+fn required_parents(
+  all_checkpoints: &[utils::CheckpointMeta],
+  merged_ids: &HashSet<String>,
+  survivors: &[&utils::CheckpointMeta],
+) -> Vec<(String, String)> {
+  let parents = all_checkpoints
     .iter()
-    .filter(|c| !keep_checkpoints.contains(c))
-    .collect::<Vec<_>>();
+    .filter_map(|c| Some((c.checkpoint_id.clone(), c.parent_id.clone()?)))
+    .collect::<HashMap<_, _>>();
 
-  if dry_run {
-    println!("The following checkpoints will be merged:");
-    utils::print_checkpoints_table(merged_checkpoints);
-    println!("The following checkpoints will be kept:");
-    utils::print_checkpoints_table(keep_checkpoints);
+  let mut merged_ids = merged_ids.clone();
+  let mut required = Vec::new();
+  // walk up from every survivor, each merged ancestor on the way is needed
+  for checkpoint in survivors {
+    let mut child_id = &checkpoint.checkpoint_id;
+    while let Some(parent_id) = parents.get(child_id) {
+      if merged_ids.remove(parent_id) {
+        required.push((parent_id.clone(), child_id.clone()));
+      }
+      child_id = parent_id;
+    }
+  }
+  required
+}
+
+fn plan_squashes(
+  all_checkpoints: &[utils::CheckpointMeta],
+  merged_ids: &HashSet<String>,
+) -> Vec<SquashPlan> {
+  let existing = all_checkpoints
+    .iter()
+    .map(|c| (c.checkpoint_id.clone(), c))
+    .collect::<HashMap<_, _>>();
+
+  let mut plans = Vec::new();
+  for checkpoint in all_checkpoints {
+    if merged_ids.contains(&checkpoint.checkpoint_id) {
+      continue;
+    }
+    let mut folded = Vec::new();
+    let mut next = checkpoint.parent_id.clone();
+    while let Some(parent_id) = next.clone() {
+      if !merged_ids.contains(&parent_id) {
+        break;
+      }
+      next = existing[&parent_id].parent_id.clone();
+      folded.push(parent_id);
+    }
+    if !folded.is_empty() {
+      plans.push(SquashPlan {
+        checkpoint_id: checkpoint.checkpoint_id.clone(),
+        folded,
+        new_parent: next,
+      });
+    }
+  }
+  plans
+}
+
+fn apply_squash(store: &CheckpointStore, plan: &SquashPlan) -> Result<()> {
+  let checkpoint_dir = store.checkpoint_dir(&plan.checkpoint_id);
+  let new_parent = plan
+    .new_parent
+    .as_ref()
+    .map(|id| format!("../../{}/image", id));
+
+  let meta_file = store.meta_path(&plan.checkpoint_id);
+  let mut meta = utils::CheckpointMeta::load(&meta_file)?;
+  // the manifest is rebuilt and the signature renewed below, so whatever is
+  // folded in has to check out first or tampering would be signed off on
+  verify::verify_chain(store, &meta)?;
+  sign::check_chain(store, &meta, store.config()?.signing.unsigned)?;
+
+  // squashing reads and writes plain images, so a packed chain is squashed
+  // in a scratch copy and the result packed back into the store
+  let packing = Packing::of_checkpoint(store, &meta)?;
+  let staged = pack::stage_chain(store, &store.parent_chain(&plan.checkpoint_id)?)?;
+  let work_dir = match &staged {
+    Some(staged) => staged.path().join(&plan.checkpoint_id),
+    None => checkpoint_dir.clone(),
+  };
+
+  // pre-dump passes of the checkpoint itself are part of its chain and get
+  // folded in as well
+  let image_dir = work_dir.join("image");
+  squash::squash_images(&image_dir, new_parent.as_deref()).with_path(&image_dir)?;
+  if staged.is_some() {
+    pack::replace_images(&work_dir, &checkpoint_dir, &packing)?;
   } else {
-    merged_checkpoints.iter().for_each(|c| {
-      // delete the checkpoint
-      let checkpoint_dir = utils::get_hcriu_dir().join(c.checkpoint_id.clone());
-      std::fs::remove_dir_all(&checkpoint_dir).unwrap();
-      println!("Deleted checkpoint {}", c.checkpoint_id);
-    });
-    println!("Merged {:?} checkpoints", merged_checkpoints.len());
+    let pre_dump_dir = checkpoint_dir.join("pre-dump");
+    if pre_dump_dir.exists() {
+      std::fs::remove_dir_all(&pre_dump_dir).with_path(&pre_dump_dir)?;
+    }
   }
+  // the images were rewritten on purpose, hash them again
+  Manifest::build(&checkpoint_dir)?.save(&checkpoint_dir)?;
+
+  meta.parent_id = plan.new_parent.clone();
+  meta.save(&meta_file)?;
+  sign::resign(store, &checkpoint_dir, &meta)
 }
@@ -0,0 +1,246 @@
+//! CRIU options for dump and restore.
+//!
+//! Both structs serialise to TOML, so they can be kept in a config file and
+//! are recorded in `meta.toml`, letting restore replay what the dump used.
+
+use crate::error::{Error, IoContext, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct DumpOptions {
+  /// leave the process running after the dump
+  pub leave_running: bool,
+  /// number of pre-dump passes made while the process keeps running
+  pub pre_dump: u32,
+  /// track memory changes so the next dump can be incremental
+  pub track_mem: bool,
+  /// the process is attached to a terminal session
+  pub shell_job: bool,
+  /// allow unix sockets connected to peers outside the process tree
+  pub ext_unix_sk: bool,
+  /// dump established TCP connections
+  pub tcp_established: bool,
+  /// dump file locks
+  pub file_locks: bool,
+  /// dump and restore the cgroups of the process
+  pub manage_cgroups: bool,
+  /// CRIU log verbosity, from 0 (errors only) to 4
+  pub log_level: u32,
+  /// log file name, relative to the checkpoint dir
+  pub log_file: String,
+  /// seconds CRIU may spend collecting the process tree
+  pub timeout: Option<u32>,
+  /// largest deleted file, in bytes, CRIU will copy into the images
+  pub ghost_limit: Option<u32>,
+  /// zstd level to compress the page images with once the dump is complete
+  pub compress: Option<i32>,
+  /// sign the manifest and metadata with the local signing key
+  pub sign: bool,
+}
+
+impl Default for DumpOptions {
+  fn default() -> Self {
+    DumpOptions {
+      leave_running: false,
+      pre_dump: 0,
+      track_mem: false,
+      shell_job: true,
+      ext_unix_sk: true,
+      tcp_established: false,
+      file_locks: false,
+      manage_cgroups: false,
+      log_level: 0,
+      log_file: "dump.log".to_string(),
+      timeout: None,
+      ghost_limit: None,
+      compress: None,
+      sign: false,
+    }
+  }
+}
+
+impl DumpOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Read options from a TOML file, missing keys keep their default.
+  pub fn load(path: &Path) -> Result<Self> {
+    load_toml(path)
+  }
+
+  pub fn leave_running(mut self, leave_running: bool) -> Self {
+    self.leave_running = leave_running;
+    self
+  }
+
+  pub fn pre_dump(mut self, passes: u32) -> Self {
+    self.pre_dump = passes;
+    self
+  }
+
+  pub fn track_mem(mut self, track_mem: bool) -> Self {
+    self.track_mem = track_mem;
+    self
+  }
+
+  pub fn shell_job(mut self, shell_job: bool) -> Self {
+    self.shell_job = shell_job;
+    self
+  }
+
+  pub fn ext_unix_sk(mut self, ext_unix_sk: bool) -> Self {
+    self.ext_unix_sk = ext_unix_sk;
+    self
+  }
+
+  pub fn tcp_established(mut self, tcp_established: bool) -> Self {
+    self.tcp_established = tcp_established;
+    self
+  }
+
+  pub fn file_locks(mut self, file_locks: bool) -> Self {
+    self.file_locks = file_locks;
+    self
+  }
+
+  pub fn manage_cgroups(mut self, manage_cgroups: bool) -> Self {
+    self.manage_cgroups = manage_cgroups;
+    self
+  }
+
+  pub fn log_level(mut self, log_level: u32) -> Self {
+    self.log_level = log_level;
+    self
+  }
+
+  pub fn log_file(mut self, log_file: impl Into<String>) -> Self {
+    self.log_file = log_file.into();
+    self
+  }
+
+  pub fn timeout(mut self, seconds: u32) -> Self {
+    self.timeout = Some(seconds);
+    self
+  }
+
+  pub fn ghost_limit(mut self, bytes: u32) -> Self {
+    self.ghost_limit = Some(bytes);
+    self
+  }
+
+  pub fn compress(mut self, level: i32) -> Self {
+    self.compress = Some(level);
+    self
+  }
+
+  pub fn sign(mut self, sign: bool) -> Self {
+    self.sign = sign;
+    self
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct RestoreOptions {
+  /// the process is attached to a terminal session
+  pub shell_job: bool,
+  /// allow unix sockets connected to peers outside the process tree
+  pub ext_unix_sk: bool,
+  /// restore established TCP connections
+  pub tcp_established: bool,
+  /// restore file locks
+  pub file_locks: bool,
+  /// restore the cgroups of the process
+  pub manage_cgroups: bool,
+  /// CRIU log verbosity, from 0 (errors only) to 4
+  pub log_level: u32,
+  /// log file name, relative to the checkpoint dir
+  pub log_file: String,
+}
+
+impl Default for RestoreOptions {
+  fn default() -> Self {
+    RestoreOptions {
+      shell_job: true,
+      ext_unix_sk: true,
+      tcp_established: false,
+      file_locks: false,
+      manage_cgroups: false,
+      log_level: 0,
+      log_file: "restore.log".to_string(),
+    }
+  }
+}
+
+/// Restore with the options a checkpoint was dumped with.
+impl From<&DumpOptions> for RestoreOptions {
+  fn from(dump: &DumpOptions) -> Self {
+    RestoreOptions {
+      shell_job: dump.shell_job,
+      ext_unix_sk: dump.ext_unix_sk,
+      tcp_established: dump.tcp_established,
+      file_locks: dump.file_locks,
+      manage_cgroups: dump.manage_cgroups,
+      log_level: dump.log_level,
+      ..Default::default()
+    }
+  }
+}
+
+impl RestoreOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Read options from a TOML file, missing keys keep their default.
+  pub fn load(path: &Path) -> Result<Self> {
+    load_toml(path)
+  }
+
+  pub fn shell_job(mut self, shell_job: bool) -> Self {
+    self.shell_job = shell_job;
+    self
+  }
+
+  pub fn ext_unix_sk(mut self, ext_unix_sk: bool) -> Self {
+    self.ext_unix_sk = ext_unix_sk;
+    self
+  }
+
+  pub fn tcp_established(mut self, tcp_established: bool) -> Self {
+    self.tcp_established = tcp_established;
+    self
+  }
+
+  pub fn file_locks(mut self, file_locks: bool) -> Self {
+    self.file_locks = file_locks;
+    self
+  }
+
+  pub fn manage_cgroups(mut self, manage_cgroups: bool) -> Self {
+    self.manage_cgroups = manage_cgroups;
+    self
+  }
+
+  pub fn log_level(mut self, log_level: u32) -> Self {
+    self.log_level = log_level;
+    self
+  }
+
+  pub fn log_file(mut self, log_file: impl Into<String>) -> Self {
+    self.log_file = log_file.into();
+    self
+  }
+}
+
+pub(crate) fn load_toml<T: DeserializeOwned>(path: &Path) -> Result<T> {
+  let content = std::fs::read_to_string(path).with_path(path)?;
+  toml::from_str(&content).map_err(|e| Error::InvalidConfig {
+    path: path.to_path_buf(),
+    message: e.to_string(),
+  })
+}
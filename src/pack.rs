@@ -0,0 +1,416 @@
+//! How image files are kept at rest, and how CRIU gets to read them.
+//!
+//! An image written by CRIU is stored as `<name>.img`, `<name>.img.zst` when
+//! its pages are compressed, `<name>.img.enc` when the store is encrypted, or
+//! `<name>.img.zst.enc` with both. In a deduplicated store the page images
+//! are stored as `<name>.img.chunks` instead, see [`crate::dedup`]. CRIU
+//! only reads plain images, so restore and incremental dumps stage the whole
+//! parent chain in a scratch dir with packed images unpacked and everything
+//! else linked. Decrypted images only ever go to a private tmpfs.
+
+use crate::crypt::{self, Key};
+use crate::error::{Error, IoContext, Result};
+use crate::options::DumpOptions;
+use crate::store::CheckpointStore;
+use crate::utils::{self, CheckpointMeta};
+use crate::{compress, dedup};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Where images are staged when they fit.
+const TMPFS: &str = "/dev/shm";
+
+/// What is done to the images of a checkpoint once CRIU wrote them.
+#[derive(Debug, Clone, Default)]
+pub struct Packing {
+  /// zstd level for the page images
+  pub compress: Option<i32>,
+  /// encrypt every image with this key
+  pub key: Option<Key>,
+  /// split the page images into chunks shared through this dir
+  pub dedup: Option<PathBuf>,
+}
+
+impl Packing {
+  /// Packing of a new dump, from its options and the store config. The key
+  /// is unlocked here, so a wrong one fails before CRIU runs.
+  pub fn for_dump(store: &CheckpointStore, options: &DumpOptions) -> Result<Self> {
+    let config = store.config()?;
+    // chunks are named after their content, which would tell anyone with
+    // the store which pages are equal, and what they hold for guessable ones
+    if config.encryption.enabled && config.dedup.enabled {
+      return Err(Error::InvalidConfig {
+        path: store.config_path(),
+        message: "dedup can't be combined with encryption".to_string(),
+      });
+    }
+    let key = if config.encryption.enabled {
+      Some(store.key()?)
+    } else {
+      None
+    };
+    Ok(Packing {
+      compress: config.compression_level(options),
+      key,
+      dedup: config.dedup.enabled.then(|| store.chunks_dir()),
+    })
+  }
+
+  /// Packing an existing checkpoint is stored with, to pack it again the same
+  /// way once its images were rewritten.
+  pub fn of_checkpoint(store: &CheckpointStore, meta: &CheckpointMeta) -> Result<Self> {
+    let mut packing = Packing::default();
+    let checkpoint_dir = store.checkpoint_dir(&meta.checkpoint_id);
+    for path in utils::image_files(&checkpoint_dir)? {
+      if is_encrypted(&path) && packing.key.is_none() {
+        packing.key = Some(store.key()?);
+      }
+      if is_compressed(&path) && packing.compress.is_none() {
+        let level = store.config()?.compression.level;
+        packing.compress = Some(meta.options.compress.unwrap_or(level));
+      }
+      if is_chunked(&path) && packing.dedup.is_none() {
+        packing.dedup = Some(store.chunks_dir());
+        packing.compress = store.config()?.compression_level(&meta.options);
+      }
+    }
+    Ok(packing)
+  }
+
+  pub fn is_plain(&self) -> bool {
+    self.compress.is_none() && self.key.is_none() && self.dedup.is_none()
+  }
+}
+
+/// Pack the images of a checkpoint in place.
+pub fn pack_checkpoint(checkpoint_dir: &Path, packing: &Packing) -> Result<()> {
+  if packing.is_plain() {
+    return Ok(());
+  }
+  for path in utils::image_files(checkpoint_dir)? {
+    let dir = path.parent().unwrap_or(checkpoint_dir);
+    let packed = pack_file(&path, dir, packing)?;
+    if packed != path {
+      std::fs::remove_file(&path).with_path(&path)?;
+    }
+  }
+  Ok(())
+}
+
+/// Size of a checkpoint with its images as CRIU wrote them. Only headers are
+/// read, so this works without the key.
+pub fn logical_size(checkpoint_dir: &Path) -> Result<u64> {
+  let mut size = utils::dir_size(checkpoint_dir)?;
+  for path in utils::image_files(checkpoint_dir)? {
+    if is_packed(&path) {
+      let stored = std::fs::metadata(&path).with_path(&path)?.len();
+      size = size - stored + unpacked_size(&path)?;
+    }
+  }
+  Ok(size)
+}
+
+/// A copy of `chain` that CRIU can read, `None` when no checkpoint in it is
+/// packed and the store can be used as it is. The images of the first
+/// checkpoint are in `<dir>/<id>/image`, the `parent` links resolve as they
+/// do in the store. Removed on drop.
+pub(crate) fn stage_chain(
+  store: &CheckpointStore,
+  chain: &[CheckpointMeta],
+) -> Result<Option<TempDir>> {
+  let mut needed = 0;
+  let mut packed = false;
+  let mut key = None;
+  for checkpoint in chain {
+    for path in utils::image_files(&store.checkpoint_dir(&checkpoint.checkpoint_id))? {
+      if is_encrypted(&path) && key.is_none() {
+        key = Some(store.key()?);
+      }
+      if is_packed(&path) {
+        packed = true;
+        needed += unpacked_size(&path)?;
+      }
+    }
+  }
+  if !packed {
+    return Ok(None);
+  }
+
+  let chunks_dir = store.chunks_dir();
+  let staged = scratch_dir("hcriu-restore-", needed, key.is_some())?;
+  for checkpoint in chain {
+    let source = store.checkpoint_dir(&checkpoint.checkpoint_id);
+    let target = staged.path().join(&checkpoint.checkpoint_id);
+    for dir in utils::IMAGE_DIRS {
+      if source.join(dir).is_dir() {
+        mirror(
+          &source.join(dir),
+          &target.join(dir),
+          key.as_ref(),
+          &chunks_dir,
+        )?;
+      }
+    }
+  }
+  Ok(Some(staged))
+}
+
+/// Copy the images of `source` to `target` in a form that needs neither the
+/// key nor the chunks of the store: encrypted images are decrypted and
+/// chunked ones rebuilt, compressed ones stay as they are. Returns whether
+/// any image changed form.
+pub(crate) fn copy_portable(store: &CheckpointStore, source: &Path, target: &Path) -> Result<bool> {
+  fn walk(store: &CheckpointStore, source: &Path, target: &Path) -> Result<bool> {
+    std::fs::create_dir_all(target).with_path(target)?;
+    let mut changed = false;
+    for entry in std::fs::read_dir(source).with_path(source)? {
+      let entry = entry.with_path(source)?;
+      let path = entry.path();
+      let dest = target.join(entry.file_name());
+      let file_type = entry.file_type().with_path(&path)?;
+      if file_type.is_dir() {
+        changed |= walk(store, &path, &dest)?;
+      } else if file_type.is_symlink() {
+        let link = std::fs::read_link(&path).with_path(&path)?;
+        std::os::unix::fs::symlink(link, &dest).with_path(&dest)?;
+      } else if is_encrypted(&path) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let plain = target.join(name.trim_end_matches(".enc"));
+        crypt::decrypt_file(&store.key()?, &path, &plain)?;
+        changed = true;
+      } else if is_chunked(&path) {
+        unpack_file(&path, target, None, &store.chunks_dir())?;
+        changed = true;
+      } else {
+        std::fs::copy(&path, &dest).with_path(&dest)?;
+      }
+    }
+    Ok(changed)
+  }
+
+  let mut changed = false;
+  for dir in utils::IMAGE_DIRS {
+    if source.join(dir).is_dir() {
+      changed |= walk(store, &source.join(dir), &target.join(dir))?;
+    }
+  }
+  Ok(changed)
+}
+
+/// Unpack the images of a checkpoint outside the store in place, as copied
+/// by [`copy_portable`].
+pub(crate) fn unpack_in_place(checkpoint_dir: &Path) -> Result<()> {
+  for path in utils::image_files(checkpoint_dir)? {
+    if is_packed(&path) {
+      // portable copies hold neither encrypted nor chunked images
+      let dir = path.parent().unwrap_or(checkpoint_dir);
+      unpack_file(&path, dir, None, checkpoint_dir)?;
+      std::fs::remove_file(&path).with_path(&path)?;
+    }
+  }
+  Ok(())
+}
+
+/// A scratch dir for `needed` bytes of images, on a private tmpfs when they
+/// are decrypted. Removed on drop.
+pub(crate) fn scratch_dir(prefix: &str, needed: u64, private: bool) -> Result<TempDir> {
+  let base = scratch_base(needed, private)?;
+  tempfile::Builder::new()
+    .prefix(prefix)
+    .tempdir_in(&base)
+    .with_path(&base)
+}
+
+/// Swap the images of `checkpoint_dir` for the ones rewritten in a staged
+/// copy of it, packed with `packing`. Pre-dump passes are dropped, the
+/// rewritten images no longer read from them.
+pub(crate) fn replace_images(
+  staged_dir: &Path,
+  checkpoint_dir: &Path,
+  packing: &Packing,
+) -> Result<()> {
+  let source = staged_dir.join("image");
+  let image_dir = checkpoint_dir.join("image");
+  let new_dir = checkpoint_dir.join("image.new");
+  let old_dir = checkpoint_dir.join("image.old");
+  if new_dir.exists() {
+    std::fs::remove_dir_all(&new_dir).with_path(&new_dir)?;
+  }
+  std::fs::create_dir(&new_dir).with_path(&new_dir)?;
+  for entry in std::fs::read_dir(&source).with_path(&source)? {
+    let path = entry.with_path(&source)?.path();
+    if path.file_name().is_some_and(|name| name == "parent") {
+      let link = std::fs::read_link(&path).with_path(&path)?;
+      let dest = new_dir.join("parent");
+      std::os::unix::fs::symlink(link, &dest).with_path(&dest)?;
+    } else {
+      // files the rewrite left alone are still links into the store
+      pack_file(&path, &new_dir, packing)?;
+    }
+  }
+
+  // swap only once everything is written, so the old images stay complete
+  // until the new ones are
+  std::fs::rename(&image_dir, &old_dir).with_path(&image_dir)?;
+  std::fs::rename(&new_dir, &image_dir).with_path(&new_dir)?;
+  std::fs::remove_dir_all(&old_dir).with_path(&old_dir)?;
+  let pre_dump_dir = checkpoint_dir.join("pre-dump");
+  if pre_dump_dir.exists() {
+    std::fs::remove_dir_all(&pre_dump_dir).with_path(&pre_dump_dir)?;
+  }
+  Ok(())
+}
+
+/// Name of a file at rest with its packing extensions taken off.
+pub(crate) fn unpacked_name(name: &str) -> &str {
+  if let Some(name) = name.strip_suffix(".chunks") {
+    return name;
+  }
+  let name = name.strip_suffix(".enc").unwrap_or(name);
+  name.strip_suffix(".zst").unwrap_or(name)
+}
+
+/// A private tmpfs when the images are decrypted, refusing to write them to
+/// disk, tmpfs or the system temp dir otherwise.
+fn scratch_base(needed: u64, private: bool) -> Result<PathBuf> {
+  let fits = |dir: &Path| utils::free_space(dir).is_ok_and(|free| free > needed);
+  // the runtime dir is a tmpfs only its user can read
+  let candidates = [
+    Some(PathBuf::from(TMPFS)),
+    std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+  ];
+  for dir in candidates.into_iter().flatten() {
+    if fits(&dir) {
+      return Ok(dir);
+    }
+  }
+  if private {
+    return Err(Error::NoScratchSpace(needed));
+  }
+  Ok(std::env::temp_dir())
+}
+
+/// Recreate `source` at `target`: packed images are unpacked, other files
+/// are linked and `parent` links copied as they are.
+fn mirror(source: &Path, target: &Path, key: Option<&Key>, chunks_dir: &Path) -> Result<()> {
+  std::fs::create_dir_all(target).with_path(target)?;
+  for entry in std::fs::read_dir(source).with_path(source)? {
+    let entry = entry.with_path(source)?;
+    let path = entry.path();
+    let dest = target.join(entry.file_name());
+    let file_type = entry.file_type().with_path(&path)?;
+    if file_type.is_dir() {
+      mirror(&path, &dest, key, chunks_dir)?;
+    } else if file_type.is_symlink() {
+      let link = std::fs::read_link(&path).with_path(&path)?;
+      std::os::unix::fs::symlink(link, &dest).with_path(&dest)?;
+    } else if is_packed(&path) {
+      unpack_file(&path, target, key, chunks_dir)?;
+    } else {
+      std::os::unix::fs::symlink(&path, &dest).with_path(&dest)?;
+    }
+  }
+  Ok(())
+}
+
+/// Write `source` packed into `dir`, returns the path it is stored at. A file
+/// nothing applies to is copied, unless it already is in `dir`.
+fn pack_file(source: &Path, dir: &Path, packing: &Packing) -> Result<PathBuf> {
+  let plain = dir.join(source.file_name().unwrap_or_default());
+  let original_size = std::fs::metadata(source).with_path(source)?.len();
+  let mut target = plain.clone();
+  let mut current = source.to_path_buf();
+  if let Some(chunks_dir) = packing
+    .dedup
+    .as_ref()
+    .filter(|_| compress::is_pages(source))
+  {
+    // chunks are compressed one by one, a compressed list would not share
+    target = with_extension(&target, dedup::EXTENSION);
+    dedup::split_file(&current, &target, chunks_dir, packing.compress)?;
+    current = target.clone();
+  } else if let Some(level) = packing.compress.filter(|_| compress::is_pages(source)) {
+    target = with_extension(&target, compress::EXTENSION);
+    compress::compress_file(&current, &target, level).inspect_err(|_| {
+      let _ = std::fs::remove_file(&target);
+    })?;
+    current = target.clone();
+  }
+  if let Some(key) = &packing.key {
+    target = with_extension(&target, crypt::EXTENSION);
+    crypt::encrypt_file(key, &current, &target, original_size).inspect_err(|_| {
+      let _ = std::fs::remove_file(&target);
+    })?;
+    if current != source {
+      std::fs::remove_file(&current).with_path(&current)?;
+    }
+    current = target;
+  }
+  if current == source && plain != source {
+    std::fs::copy(source, &plain).with_path(&plain)?;
+    current = plain;
+  }
+  Ok(current)
+}
+
+/// Unpack `source` into `dir` under its plain name.
+fn unpack_file(source: &Path, dir: &Path, key: Option<&Key>, chunks_dir: &Path) -> Result<()> {
+  let name = source.file_name().unwrap_or_default().to_string_lossy();
+  if is_chunked(source) {
+    return dedup::join_file(source, &dir.join(unpacked_name(&name)), chunks_dir);
+  }
+  let mut current = source.to_path_buf();
+  if let Some(sealed) = name.strip_suffix(".enc") {
+    let key = key.ok_or(Error::KeyRequired)?;
+    let target = dir.join(sealed);
+    crypt::decrypt_file(key, source, &target)?;
+    current = target;
+  }
+  if has_extension(&current, compress::EXTENSION) {
+    let target = dir.join(unpacked_name(&name));
+    compress::decompress_file(&current, &target)?;
+    if current != source {
+      std::fs::remove_file(&current).with_path(&current)?;
+    }
+  }
+  Ok(())
+}
+
+/// Size of a packed image once unpacked, from its header.
+fn unpacked_size(path: &Path) -> Result<u64> {
+  if is_encrypted(path) {
+    crypt::original_size(path)
+  } else if is_chunked(path) {
+    Ok(dedup::ChunkList::load(path)?.size)
+  } else {
+    compress::content_size(path)
+  }
+}
+
+fn is_packed(path: &Path) -> bool {
+  is_encrypted(path) || is_chunked(path) || has_extension(path, compress::EXTENSION)
+}
+
+fn is_chunked(path: &Path) -> bool {
+  has_extension(path, dedup::EXTENSION)
+}
+
+fn is_compressed(path: &Path) -> bool {
+  let name = path.file_name().unwrap_or_default().to_string_lossy();
+  name.strip_suffix(".enc").unwrap_or(&name).ends_with(".zst")
+}
+
+fn is_encrypted(path: &Path) -> bool {
+  has_extension(path, crypt::EXTENSION)
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+  path.extension().is_some_and(|ext| ext == extension)
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(".");
+  name.push(extension);
+  PathBuf::from(name)
+}
@@ -0,0 +1,333 @@
+//! Receiving dumps from another hcriu process through CRIU's page server.
+//!
+//! `hcriu page-server --listen <addr>` takes dumps sent by
+//! `hcriu dump --page-server <addr>`, one at a time, into its own store. The
+//! dumping side opens a control connection and sends the metadata of the
+//! dump. The server answers with a port, and the connection CRIU makes to it
+//! is handed to a CRIU page server writing the pages into a staging dir. Once
+//! CRIU is done the dumping side sends the rest of the checkpoint over the
+//! control connection as an archive, see [`crate::archive`], and the server
+//! completes it like a local dump and answers whether that worked.
+//!
+//! Messages on the control connection are TOML, each preceded by its length
+//! as a big-endian u32.
+
+use crate::backend::{CriuBackend, PageServerRequest};
+use crate::error::{Error, IoContext, Result};
+use crate::manifest::Manifest;
+use crate::pack::{self, Packing};
+use crate::store::{CheckpointStore, STAGING_PREFIX};
+use crate::utils::{CheckpointMeta, CheckpointStatus};
+use crate::{archive, fsck, quota, sign};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Log of the CRIU page server, next to the dump log.
+const PAGE_SERVER_LOG: &str = "page-server.log";
+
+/// How long the dumping side may leave a connection idle, or take to connect
+/// for the pages, before its dump is given up.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest control message, metadata is a few hundred bytes.
+const MAX_MESSAGE: u32 = 1 << 20;
+
+/// First message of the dumping side.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Hello {
+  pub meta: CheckpointMeta,
+  /// bytes the dump is expected to take, to make room for
+  pub estimate: u64,
+}
+
+/// Answer of the server, first with the port to send pages to, then once the
+/// checkpoint is complete.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub(crate) struct Reply {
+  pub port: Option<u16>,
+  pub error: Option<String>,
+}
+
+impl Reply {
+  /// Fail with the error the server reported, if any.
+  pub(crate) fn check(self, address: impl Display) -> Result<Self> {
+    match self.error {
+      Some(message) => Err(Error::PageServer {
+        address: address.to_string(),
+        message,
+      }),
+      None => Ok(self),
+    }
+  }
+}
+
+/// Take dumps sent to `listen` into the store until killed. A failed dump is
+/// reported and does not stop the server.
+pub fn handle_page_server(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  listen: &str,
+) -> Result<()> {
+  let listener = TcpListener::bind(listen).map_err(|e| net_error(listen, e))?;
+  let address = listener.local_addr().map_err(|e| net_error(listen, e))?;
+  println!("Page server listening on {}", address);
+  loop {
+    match serve_dump(store, criu, &listener) {
+      Ok(meta) => println!(
+        "Received checkpoint {} of pid {}",
+        meta.checkpoint_id, meta.pid
+      ),
+      Err(e) => eprintln!("{}", e),
+    }
+  }
+}
+
+/// Take the next dump sent to `listener` into the store.
+pub fn serve_dump(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  listener: &TcpListener,
+) -> Result<CheckpointMeta> {
+  let (mut control, peer) = listener.accept().map_err(|e| net_error("-", e))?;
+  set_timeouts(&control, peer)?;
+  let hello: Hello = receive(&mut control, peer)?;
+  let result = receive_dump(store, criu, listener, &mut control, peer, hello);
+  let reply = Reply {
+    port: None,
+    error: result.as_ref().err().map(|e| e.to_string()),
+  };
+  // the dumping side may be gone already, the error is reported here anyway
+  let _ = send(&mut control, &reply, peer);
+  result
+}
+
+pub(crate) fn send<T: Serialize>(
+  socket: &mut TcpStream,
+  message: &T,
+  address: impl Display,
+) -> Result<()> {
+  let text = toml::to_string(message).map_err(|e| Error::PageServer {
+    address: address.to_string(),
+    message: e.to_string(),
+  })?;
+  let mut frame = (text.len() as u32).to_be_bytes().to_vec();
+  frame.extend(text.as_bytes());
+  socket.write_all(&frame).map_err(|e| net_error(&address, e))
+}
+
+pub(crate) fn receive<T: DeserializeOwned>(
+  socket: &mut TcpStream,
+  address: impl Display,
+) -> Result<T> {
+  let mut len = [0; 4];
+  socket
+    .read_exact(&mut len)
+    .map_err(|e| net_error(&address, e))?;
+  let len = u32::from_be_bytes(len);
+  if len > MAX_MESSAGE {
+    return Err(Error::PageServer {
+      address: address.to_string(),
+      message: format!("message of {} bytes is too large", len),
+    });
+  }
+  let mut text = vec![0; len as usize];
+  socket
+    .read_exact(&mut text)
+    .map_err(|e| net_error(&address, e))?;
+  let text = String::from_utf8_lossy(&text);
+  toml::from_str(&text).map_err(|e| Error::PageServer {
+    address: address.to_string(),
+    message: e.to_string(),
+  })
+}
+
+fn net_error(address: impl Display, e: std::io::Error) -> Error {
+  Error::PageServer {
+    address: address.to_string(),
+    message: e.to_string(),
+  }
+}
+
+/// Complete the dump announced by `hello` in the store, like a local dump
+/// does: a failed one only leaves its logs in quarantine. The store lock is
+/// only taken to make room and to commit, the staging dir is reserved while
+/// the images come in.
+fn receive_dump(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  listener: &TcpListener,
+  control: &mut TcpStream,
+  peer: SocketAddr,
+  hello: Hello,
+) -> Result<CheckpointMeta> {
+  let mut meta = hello.meta;
+  if !fsck::is_checkpoint_id(&meta.checkpoint_id) || meta.parent_id.is_some() {
+    return Err(Error::PageServer {
+      address: peer.to_string(),
+      message: "only full dumps can be sent".to_string(),
+    });
+  }
+  meta.status = CheckpointStatus::InProgress;
+  let checkpoint_dir = store.checkpoint_dir(&meta.checkpoint_id);
+  let staging_dir = store.staging_dir(&meta.checkpoint_id);
+  let meta_file = staging_dir.join("meta.toml");
+  let (packing, signing_key, _reserved) = {
+    let _lock = store.lock()?;
+    let packing = Packing::for_dump(store, &meta.options)?;
+    let signing_key = if meta.options.sign {
+      Some(sign::signing_key(store)?)
+    } else {
+      None
+    };
+    quota::make_room(store, &meta.tag, None, hello.estimate)?;
+    if checkpoint_dir.exists() || staging_dir.exists() {
+      return Err(Error::CheckpointExists(meta.checkpoint_id));
+    }
+    let image_dir = staging_dir.join("image");
+    std::fs::create_dir_all(&image_dir).with_path(&image_dir)?;
+    let reserved = store.reserve_staging(&meta.checkpoint_id)?;
+    meta.save(&meta_file)?;
+    (packing, signing_key, reserved)
+  };
+
+  let checkpoint_id = meta.checkpoint_id.clone();
+  let received = receive_images(store, criu, listener, control, peer, &checkpoint_id);
+  let _lock = store.lock()?;
+  match received {
+    Ok(received) => meta = received,
+    Err(e) => {
+      meta.status = CheckpointStatus::Failed;
+      meta.save(&meta_file)?;
+      let quarantine_dir = store.quarantine(&meta.checkpoint_id)?;
+      eprintln!("Dump failed, logs are kept in {}", quarantine_dir.display());
+      return Err(e);
+    }
+  }
+  pack::pack_checkpoint(&staging_dir, &packing)?;
+  Manifest::build(&staging_dir)?.save(&staging_dir)?;
+  if let Some(key) = &signing_key {
+    sign::sign_checkpoint(&staging_dir, &meta, key)?;
+  }
+  meta.status = CheckpointStatus::Complete;
+  meta.save(&meta_file)?;
+  store.commit(&meta.checkpoint_id)?;
+  Ok(meta)
+}
+
+/// Serve the pages of the dump, then add the rest of the checkpoint sent on
+/// `control` to its staging dir. Returns the metadata the dumping side
+/// completed.
+fn receive_images(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  listener: &TcpListener,
+  control: &mut TcpStream,
+  peer: SocketAddr,
+  checkpoint_id: &str,
+) -> Result<CheckpointMeta> {
+  let staging_dir = store.staging_dir(checkpoint_id);
+  let local = listener.local_addr().map_err(|e| net_error(peer, e))?;
+  let pages = TcpListener::bind((local.ip(), 0)).map_err(|e| net_error(local, e))?;
+  let port = pages.local_addr().map_err(|e| net_error(local, e))?.port();
+  let ready = Reply {
+    port: Some(port),
+    error: None,
+  };
+  send(control, &ready, peer)?;
+  let socket = accept_pages(&pages, peer)?;
+  criu.page_server(&PageServerRequest {
+    work_dir: &staging_dir,
+    image_dir: &staging_dir.join("image"),
+    log_file: PAGE_SERVER_LOG,
+    socket: &socket,
+  })?;
+  drop(socket);
+
+  let received = tempfile::Builder::new()
+    .prefix(&format!("{}receive-", STAGING_PREFIX))
+    .tempdir_in(store.root())
+    .with_path(store.root())?;
+  let source = Path::new(checkpoint_id);
+  let chain = archive::read_checkpoints(&mut *control, received.path(), source, false)?;
+  let invalid = |message: &str| Error::InvalidArchive {
+    path: source.to_path_buf(),
+    message: message.to_string(),
+  };
+  let [meta] = chain.as_slice() else {
+    return Err(invalid("a dump sent to a page server has no parents"));
+  };
+  if meta.checkpoint_id != checkpoint_id {
+    return Err(invalid("the checkpoint is not the one announced"));
+  }
+  merge_into(&received.path().join(checkpoint_id), &staging_dir)?;
+  Ok(meta.clone())
+}
+
+/// The connection CRIU of the dumping side makes to `pages`. Only the host
+/// that announced the dump may send its pages, and it has to connect within
+/// [`NETWORK_TIMEOUT`].
+fn accept_pages(pages: &TcpListener, peer: SocketAddr) -> Result<TcpStream> {
+  let local = pages.local_addr().map_err(|e| net_error(peer, e))?;
+  pages
+    .set_nonblocking(true)
+    .map_err(|e| net_error(local, e))?;
+  let started = Instant::now();
+  loop {
+    match pages.accept() {
+      Ok((socket, from)) if from.ip() == peer.ip() => {
+        socket
+          .set_nonblocking(false)
+          .map_err(|e| net_error(from, e))?;
+        set_timeouts(&socket, from)?;
+        return Ok(socket);
+      }
+      Ok(_) => {}
+      Err(e) if e.kind() == ErrorKind::WouldBlock => {
+        if started.elapsed() > NETWORK_TIMEOUT {
+          return Err(Error::PageServer {
+            address: local.to_string(),
+            message: format!("no connection for the pages after {:?}", NETWORK_TIMEOUT),
+          });
+        }
+        std::thread::sleep(Duration::from_millis(10));
+      }
+      Err(e) => return Err(net_error(local, e)),
+    }
+  }
+}
+
+/// Give up on a peer that leaves `socket` idle for [`NETWORK_TIMEOUT`].
+fn set_timeouts(socket: &TcpStream, address: impl Display) -> Result<()> {
+  socket
+    .set_read_timeout(Some(NETWORK_TIMEOUT))
+    .and_then(|()| socket.set_write_timeout(Some(NETWORK_TIMEOUT)))
+    .map_err(|e| net_error(address, e))
+}
+
+/// Move what the dumping side wrote into `staging_dir`, next to the pages
+/// the page server wrote there. Nothing it wrote is replaced.
+fn merge_into(source: &Path, staging_dir: &Path) -> Result<()> {
+  for entry in std::fs::read_dir(source).with_path(source)? {
+    let path = entry.with_path(source)?.path();
+    let name = path.file_name().unwrap_or_default();
+    if path.is_dir() && name == "image" {
+      merge_into(&path, &staging_dir.join("image"))?;
+      continue;
+    }
+    let target = staging_dir.join(name);
+    // the metadata is the one file both sides write
+    if path.is_dir() || (target.exists() && name != "meta.toml") {
+      return Err(Error::InvalidArchive {
+        path: path.clone(),
+        message: "unexpected in a dump sent to a page server".to_string(),
+      });
+    }
+    std::fs::rename(&path, &target).with_path(&path)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,18 @@
+use crate::error::Result;
+use crate::revision;
+use crate::store::CheckpointStore;
+
+/// Pin or unpin a checkpoint. Merge, retention and quota eviction leave
+/// pinned checkpoints alone.
+pub fn handle_pin(store: &CheckpointStore, checkpoint_id: &str, pinned: bool) -> Result<()> {
+  let _lock = store.lock()?;
+  let mut meta = revision::resolve(store, checkpoint_id)?;
+  meta.pinned = pinned;
+  meta.save(&store.meta_path(&meta.checkpoint_id))?;
+  if pinned {
+    println!("Pinned checkpoint {}", meta.checkpoint_id);
+  } else {
+    println!("Unpinned checkpoint {}", meta.checkpoint_id);
+  }
+  Ok(())
+}
@@ -0,0 +1,199 @@
+//! Store quotas, checked before each dump.
+//!
+//! A dump is expected to write as many pages as the last dump of its parent,
+//! or the resident memory of the process when it has no parent. When that
+//! would break a limit, the oldest checkpoints within the limit's scope are
+//! evicted, the ones the retention policy of their tag drops go first, or the
+//! dump is refused if the limit says so. Pinned checkpoints are never evicted.
+
+use crate::config::{Quota, QuotaAction, StoreConfig, tag_matches};
+use crate::error::{Error, Result};
+use crate::merge;
+use crate::store::CheckpointStore;
+use crate::utils::CheckpointMeta;
+use bytesize::ByteSize;
+use procfs::process::Process;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy)]
+enum Scope<'a> {
+  Store,
+  Tag(&'a str),
+}
+
+impl Scope<'_> {
+  fn contains(&self, checkpoint: &CheckpointMeta) -> bool {
+    match self {
+      Scope::Store => true,
+      Scope::Tag(pattern) => tag_matches(pattern, &checkpoint.tag),
+    }
+  }
+}
+
+impl fmt::Display for Scope<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Scope::Store => write!(f, "the store"),
+      Scope::Tag(pattern) => write!(f, "tag '{}'", pattern),
+    }
+  }
+}
+
+/// Bytes the next dump of `pid` is expected to write.
+pub fn estimate_dump_size(pid: i32, parent: Option<&CheckpointMeta>) -> u64 {
+  let pages = match parent {
+    Some(parent) => parent.pages_written.last().copied().unwrap_or(0),
+    None => Process::new(pid)
+      .and_then(|p| p.stat())
+      .map(|stat| stat.rss)
+      .unwrap_or(0),
+  };
+  pages * procfs::page_size()
+}
+
+/// Evict checkpoints until a dump of `tag` needing `estimate` bytes fits
+/// every quota, or fail with the quota it would break. `parent_id`, the
+/// checkpoint the dump builds on, is never evicted.
+pub fn make_room(
+  store: &CheckpointStore,
+  tag: &str,
+  parent_id: Option<&str>,
+  estimate: u64,
+) -> Result<()> {
+  let config = store.config()?;
+  let mut limits = vec![(Scope::Store, &config.quota)];
+  for tag_quota in config.tag_quotas_for(tag) {
+    limits.push((Scope::Tag(&tag_quota.tag), &tag_quota.quota));
+  }
+  limits.retain(|(_, quota)| !quota.is_empty());
+
+  while !limits.is_empty() {
+    let checkpoints = store.list()?;
+    let Some((scope, quota, broken)) = first_broken(store, &checkpoints, &limits, estimate)? else {
+      return Ok(());
+    };
+    if quota.on_exceed == QuotaAction::Refuse {
+      return Err(Error::QuotaExceeded(format!(
+        "{}, refusing to dump as on_exceed is refuse",
+        broken
+      )));
+    }
+    let Some(victim) = pick_victim(&config, &checkpoints, scope, parent_id)? else {
+      return Err(Error::QuotaExceeded(format!(
+        "{}, and there is nothing left to evict",
+        broken
+      )));
+    };
+
+    let size = store.checkpoint_size(&victim.checkpoint_id)?;
+    merge::delete_folding(store, &checkpoints, &victim.checkpoint_id)?;
+    println!(
+      "Evicted checkpoint {} (tag {}, dumped {}, {}): {}",
+      &victim.checkpoint_id[..7],
+      victim.tag,
+      victim.dump_time,
+      ByteSize(size),
+      broken
+    );
+  }
+  Ok(())
+}
+
+/// The first limit the dump would break, with an explanation.
+fn first_broken<'a>(
+  store: &CheckpointStore,
+  checkpoints: &[CheckpointMeta],
+  limits: &[(Scope<'a>, &'a Quota)],
+  estimate: u64,
+) -> Result<Option<(Scope<'a>, &'a Quota, String)>> {
+  for (scope, quota) in limits {
+    let scoped = checkpoints
+      .iter()
+      .filter(|c| scope.contains(c))
+      .collect::<Vec<_>>();
+
+    if let Some(max_count) = quota.max_count
+      && scoped.len() + 1 > max_count
+    {
+      let broken = format!(
+        "{} would hold {} checkpoints, max_count is {}",
+        scope,
+        scoped.len() + 1,
+        max_count
+      );
+      return Ok(Some((*scope, *quota, broken)));
+    }
+
+    if let Some(max_bytes) = quota.max_bytes {
+      let mut used = estimate;
+      for checkpoint in &scoped {
+        used += store.checkpoint_size(&checkpoint.checkpoint_id)?;
+      }
+      // shared chunks belong to no checkpoint in particular
+      if let Scope::Store = scope {
+        used += store.chunks_size()?;
+      }
+      if used > max_bytes.as_u64() {
+        let broken = format!(
+          "{} would use {} with the new dump, max_bytes is {}",
+          scope,
+          ByteSize(used),
+          max_bytes
+        );
+        return Ok(Some((*scope, *quota, broken)));
+      }
+    }
+
+    if let Some(min_free) = quota.min_free {
+      let free = store.free_space()?;
+      if free < estimate + min_free.as_u64() {
+        let broken = format!(
+          "{} free, the dump needs about {} and min_free is {}",
+          ByteSize(free),
+          ByteSize(estimate),
+          min_free
+        );
+        return Ok(Some((*scope, *quota, broken)));
+      }
+    }
+  }
+  Ok(None)
+}
+
+/// Oldest unpinned checkpoint in `scope`, preferring those retention would
+/// drop.
+fn pick_victim<'c>(
+  config: &StoreConfig,
+  checkpoints: &'c [CheckpointMeta],
+  scope: Scope,
+  parent_id: Option<&str>,
+) -> Result<Option<&'c CheckpointMeta>> {
+  let candidates = checkpoints
+    .iter()
+    .filter(|c| scope.contains(c) && !c.pinned && Some(c.checkpoint_id.as_str()) != parent_id)
+    .collect::<Vec<_>>();
+
+  let mut retained = HashSet::new();
+  for tag in candidates.iter().map(|c| &c.tag).collect::<HashSet<_>>() {
+    if let Some(retention) = config.retention_for(tag) {
+      let series = checkpoints
+        .iter()
+        .filter(|c| &c.tag == tag)
+        .collect::<Vec<_>>();
+      retained.extend(retention.policy.apply(&series)?.into_keys());
+    }
+  }
+
+  let mut ordered = Vec::new();
+  for checkpoint in candidates {
+    let kept = retained.contains(&checkpoint.checkpoint_id);
+    ordered.push((kept, checkpoint.timestamp()?, checkpoint));
+  }
+  Ok(
+    ordered
+      .into_iter()
+      .min_by_key(|(kept, time, _)| (*kept, *time))
+      .map(|(_, _, checkpoint)| checkpoint),
+  )
+}
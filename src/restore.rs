@@ -1,65 +1,199 @@
-use crate::utils;
-use rust_criu::Criu;
-use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use crate::backend::{CriuBackend, LazyPagesRequest, RestoreRequest};
+use crate::detach::{Detach, Terminal};
+use crate::error::{Error, IoContext, Result};
+use crate::options::RestoreOptions;
+use crate::store::CheckpointStore;
+use crate::utils::CheckpointStatus;
+use crate::{archive, pack, revision, sign, verify};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-pub fn handle_restore(criu: &mut Criu, checkpoint_id: String) {
-  let checkpoint_dir = find_by_prefix(&checkpoint_id);
-  let checkpoint_fd = std::fs::File::open(&checkpoint_dir).unwrap();
-  criu.set_work_dir_fd(checkpoint_fd.as_raw_fd());
-  let image_dir = checkpoint_dir.join("image");
-  let image_fd = std::fs::File::open(&image_dir).unwrap();
-  criu.set_images_dir_fd(image_fd.as_raw_fd());
-  criu.set_log_level(0);
-  criu.set_log_file("restore.log".to_string());
-  criu.set_shell_job(true);
+/// Log of the lazy-pages daemon, next to the restore log.
+const LAZY_PAGES_LOG: &str = "lazy-pages.log";
+/// Run dirs of lazy restores in the store root.
+const LAZY_RUN_PREFIX: &str = ".lazy-restore-";
+
+/// Restore the checkpoint `checkpoint_id` names, see [`crate::revision`].
+/// Without `options` the ones it was dumped with are used.
+/// Images that differ from their manifest, and checkpoints the signing policy
+/// of the store refuses, are only restored with `force`. A `lazy` restore
+/// resumes the process at once and serves its memory on demand, returning
+/// once every page was faulted in. With `detach` the process gets a terminal
+/// of its own instead of the one hcriu runs on, see [`crate::detach`].
+pub fn handle_restore(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  checkpoint_id: String,
+  options: Option<&RestoreOptions>,
+  force: bool,
+  lazy: bool,
+  detach: Option<Detach>,
+) -> Result<()> {
+  let meta = revision::resolve(store, &checkpoint_id)?;
+  if meta.status != CheckpointStatus::Complete {
+    return Err(Error::Incomplete(meta.checkpoint_id));
+  }
+  if !force {
+    verify::verify_chain(store, &meta)?;
+    sign::check_chain(store, &meta, store.config()?.signing.unsigned)?;
+  }
+  let checkpoint_dir = store.checkpoint_dir(&meta.checkpoint_id);
+  let options = match options {
+    Some(options) => options.clone(),
+    None => RestoreOptions::from(&meta.options),
+  };
 
-  criu
-    .restore()
-    .map_err(|e| {
-      eprintln!("Failed to restore: {}", e);
-      std::process::exit(1);
-    })
-    .and_then(|_| {
-      println!("Restore Success");
-      Ok(())
-    })
-    .unwrap();
+  // packed images are unpacked into a scratch copy of the chain, which is
+  // removed once CRIU is done with it
+  let chain = store.parent_chain(&meta.checkpoint_id)?;
+  let staged = pack::stage_chain(store, &chain)?;
+  let image_dir = match &staged {
+    Some(staged) => staged.path().join(&meta.checkpoint_id).join("image"),
+    None => checkpoint_dir.join("image"),
+  };
+  let terminal = detach
+    .map(|mode| Terminal::open(store, &meta, mode))
+    .transpose()?;
+  run_restore(
+    store,
+    criu,
+    &checkpoint_dir,
+    &image_dir,
+    &options,
+    lazy,
+    terminal,
+  )
 }
 
-fn find_by_prefix(prefix: &str) -> PathBuf {
-  if prefix.len() < 4 {
-    eprintln!("Prefix must be at least 4 characters long");
-    std::process::exit(1);
+/// Restore the checkpoint of an archive read from `input`, as written by
+/// `hcriu dump --stream`. The archive is unpacked into a scratch dir that is
+/// removed once CRIU is done, nothing is added to the store. Its images and
+/// signatures are checked like those of the store, unless `force`.
+pub fn handle_restore_stream(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  input: &mut dyn Read,
+  options: Option<&RestoreOptions>,
+  force: bool,
+  lazy: bool,
+  detach: Option<Detach>,
+) -> Result<()> {
+  let staged = tempfile::Builder::new()
+    .prefix("hcriu-stream-")
+    .tempdir()
+    .with_path(&std::env::temp_dir())?;
+  let chain = archive::read_checkpoints(input, staged.path(), Path::new("-"), !force)?;
+  let policy = store.config()?.signing.unsigned;
+  for checkpoint in &chain {
+    let checkpoint_dir = staged.path().join(&checkpoint.checkpoint_id);
+    if !force {
+      sign::check_policy(store, &checkpoint_dir, checkpoint, policy)?;
+    }
+    pack::unpack_in_place(&checkpoint_dir)?;
   }
 
-  let hcriu_dir = utils::get_hcriu_dir();
-  let mut checkpoints = Vec::new();
-  for entry in std::fs::read_dir(hcriu_dir).unwrap() {
-    let entry = entry.unwrap();
-    let path = entry.path();
-    if path.is_dir()
-      && path
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .starts_with(prefix)
-    {
-      checkpoints.push(path);
-    }
+  let meta = &chain[0];
+  let checkpoint_dir = staged.path().join(&meta.checkpoint_id);
+  let options = match options {
+    Some(options) => options.clone(),
+    None => RestoreOptions::from(&meta.options),
+  };
+  let image_dir = checkpoint_dir.join("image");
+  let terminal = detach
+    .map(|mode| Terminal::open(store, meta, mode))
+    .transpose()?;
+  run_restore(
+    store,
+    criu,
+    &checkpoint_dir,
+    &image_dir,
+    &options,
+    lazy,
+    terminal,
+  )
+}
+
+/// Have CRIU restore from images it can read. A lazy restore runs in a run
+/// dir of its own, where CRIU finds the socket of the lazy-pages daemon, so
+/// nothing of it is left in the checkpoint. The run dir is removed once the
+/// restore worked, and kept for its logs otherwise.
+fn run_restore(
+  store: &CheckpointStore,
+  criu: &mut dyn CriuBackend,
+  work_dir: &Path,
+  image_dir: &Path,
+  options: &RestoreOptions,
+  lazy: bool,
+  terminal: Option<Terminal>,
+) -> Result<()> {
+  if !lazy {
+    return restore_in(criu, work_dir, image_dir, options, false, terminal);
   }
+  let run_dir = tempfile::Builder::new()
+    .prefix(LAZY_RUN_PREFIX)
+    .tempdir_in(store.root())
+    .with_path(store.root())?;
+  restore_in(criu, run_dir.path(), image_dir, options, true, terminal).inspect_err(|_| {
+    let kept = run_dir.keep();
+    eprintln!("Lazy restore failed, logs are kept in {}", kept.display());
+  })
+}
 
-  if checkpoints.len() == 1 {
-    checkpoints[0].clone()
+/// Restore with `work_dir` as the work dir of CRIU. A lazy restore starts
+/// the lazy-pages daemon first, and waits for it to serve every page once
+/// the process runs, so the images stay around until then. A process
+/// restored on `terminal` is left to a holder before that.
+fn restore_in(
+  criu: &mut dyn CriuBackend,
+  work_dir: &Path,
+  image_dir: &Path,
+  options: &RestoreOptions,
+  lazy: bool,
+  terminal: Option<Terminal>,
+) -> Result<()> {
+  let daemon = if lazy {
+    Some(criu.lazy_pages(&LazyPagesRequest {
+      work_dir,
+      image_dir,
+      log_file: LAZY_PAGES_LOG,
+      log_level: options.log_level,
+    })?)
   } else {
-    eprintln!(
-      "Ambiguous prefix: {} checkpoints match '{}':",
-      checkpoints.len(),
-      prefix
+    None
+  };
+  // a failed restore drops the daemon, which stops it
+  criu.restore(&RestoreRequest {
+    work_dir,
+    image_dir,
+    options,
+    lazy_pages: lazy,
+    terminal: terminal.as_ref().map(Terminal::slave),
+  })?;
+  println!("Restore Success");
+  if let Some(terminal) = terminal {
+    let detached = terminal.keep()?;
+    println!(
+      "Process {} runs detached on {}",
+      detached.pid,
+      detached.tty.display()
     );
-    for checkpoint in checkpoints {
-      println!("  {}", checkpoint.display());
+    if let Some(output) = &detached.output {
+      println!("Its output goes to {}", output.display());
     }
-    std::process::exit(1);
+    if let Some(input) = &detached.input {
+      println!("Write to {} to type into its terminal", input.display());
+    }
+  }
+  if let Some(daemon) = daemon {
+    println!("Process resumed, its memory is served lazily");
+    let started = Instant::now();
+    daemon.wait()?;
+    let elapsed = Duration::from_millis(started.elapsed().as_millis() as u64);
+    println!(
+      "All pages faulted in after {}",
+      humantime::format_duration(elapsed)
+    );
   }
+  Ok(())
 }
@@ -0,0 +1,217 @@
+//! Restic-style retention: which checkpoints of a series to keep, and why.
+//!
+//! Every rule walks the checkpoints from newest to oldest. `keep_last` keeps
+//! the first N, the calendar rules keep the newest checkpoint of each of the
+//! last N hours, days, ISO weeks or months that have one, and `keep_within`
+//! keeps everything dumped within that duration of the newest checkpoint.
+//! A checkpoint is kept if any rule keeps it.
+
+use crate::error::Result;
+use crate::utils::CheckpointMeta;
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Timezone calendar buckets are computed in.
+#[derive(Debug, ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Timezone {
+  #[default]
+  Local,
+  Utc,
+}
+
+impl Timezone {
+  fn naive(self, time: DateTime<Utc>) -> NaiveDateTime {
+    match self {
+      Timezone::Local => time.with_timezone(&Local).naive_local(),
+      Timezone::Utc => time.naive_utc(),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct RetentionPolicy {
+  pub keep_last: Option<usize>,
+  pub keep_hourly: Option<usize>,
+  pub keep_daily: Option<usize>,
+  pub keep_weekly: Option<usize>,
+  pub keep_monthly: Option<usize>,
+  /// written like `2d` or `12h 30m` in config files
+  #[serde(with = "humantime_option", skip_serializing_if = "Option::is_none")]
+  pub keep_within: Option<Duration>,
+  pub timezone: Timezone,
+}
+
+mod humantime_option {
+  use serde::{Deserialize, Deserializer, Serializer, de};
+  use std::time::Duration;
+
+  pub fn serialize<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match duration {
+      Some(duration) => s.serialize_str(&humantime::format_duration(*duration).to_string()),
+      None => s.serialize_none(),
+    }
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(d)?;
+    humantime::parse_duration(&text)
+      .map(Some)
+      .map_err(de::Error::custom)
+  }
+}
+
+/// Calendar bucket a timestamp falls in, for one of the calendar rules.
+type Bucket = fn(NaiveDateTime) -> (i32, u32, u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepReason {
+  Last,
+  Hourly,
+  Daily,
+  Weekly,
+  Monthly,
+  Within(Duration),
+  /// not a rule, pinned checkpoints are kept whatever the policy says
+  Pinned,
+}
+
+impl fmt::Display for KeepReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      KeepReason::Last => write!(f, "last"),
+      KeepReason::Hourly => write!(f, "hourly"),
+      KeepReason::Daily => write!(f, "daily"),
+      KeepReason::Weekly => write!(f, "weekly"),
+      KeepReason::Monthly => write!(f, "monthly"),
+      KeepReason::Within(duration) => {
+        write!(f, "within {}", humantime::format_duration(*duration))
+      }
+      KeepReason::Pinned => write!(f, "pinned"),
+    }
+  }
+}
+
+impl RetentionPolicy {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn keep_last(mut self, n: usize) -> Self {
+    self.keep_last = Some(n);
+    self
+  }
+
+  pub fn keep_hourly(mut self, n: usize) -> Self {
+    self.keep_hourly = Some(n);
+    self
+  }
+
+  pub fn keep_daily(mut self, n: usize) -> Self {
+    self.keep_daily = Some(n);
+    self
+  }
+
+  pub fn keep_weekly(mut self, n: usize) -> Self {
+    self.keep_weekly = Some(n);
+    self
+  }
+
+  pub fn keep_monthly(mut self, n: usize) -> Self {
+    self.keep_monthly = Some(n);
+    self
+  }
+
+  pub fn keep_within(mut self, duration: Duration) -> Self {
+    self.keep_within = Some(duration);
+    self
+  }
+
+  pub fn timezone(mut self, timezone: Timezone) -> Self {
+    self.timezone = timezone;
+    self
+  }
+
+  /// No rule is set, applying the policy would keep nothing.
+  pub fn is_empty(&self) -> bool {
+    self.keep_last.is_none()
+      && self.keep_hourly.is_none()
+      && self.keep_daily.is_none()
+      && self.keep_weekly.is_none()
+      && self.keep_monthly.is_none()
+      && self.keep_within.is_none()
+  }
+
+  /// The reasons to keep each checkpoint, by checkpoint id. Checkpoints
+  /// missing from the result are not kept by any rule.
+  pub fn apply(&self, checkpoints: &[&CheckpointMeta]) -> Result<HashMap<String, Vec<KeepReason>>> {
+    let mut sorted = checkpoints
+      .iter()
+      .map(|c| Ok((*c, c.timestamp()?)))
+      .collect::<Result<Vec<_>>>()?;
+    sorted.sort_by_key(|(_, time)| Reverse(*time));
+
+    let mut kept: HashMap<String, Vec<KeepReason>> = HashMap::new();
+    let mut keep = |checkpoint: &CheckpointMeta, reason| {
+      kept
+        .entry(checkpoint.checkpoint_id.clone())
+        .or_default()
+        .push(reason);
+    };
+
+    if let Some(n) = self.keep_last {
+      for (checkpoint, _) in sorted.iter().take(n) {
+        keep(checkpoint, KeepReason::Last);
+      }
+    }
+
+    let calendar_rules: [(Option<usize>, KeepReason, Bucket); 4] = [
+      (self.keep_hourly, KeepReason::Hourly, |t| {
+        (t.year(), t.ordinal(), t.hour())
+      }),
+      (self.keep_daily, KeepReason::Daily, |t| {
+        (t.year(), t.ordinal(), 0)
+      }),
+      (self.keep_weekly, KeepReason::Weekly, |t| {
+        (t.iso_week().year(), t.iso_week().week(), 0)
+      }),
+      (self.keep_monthly, KeepReason::Monthly, |t| {
+        (t.year(), t.month(), 0)
+      }),
+    ];
+    for (count, reason, bucket) in calendar_rules {
+      let Some(mut remaining) = count else {
+        continue;
+      };
+      let mut last_bucket = None;
+      for (checkpoint, time) in &sorted {
+        if remaining == 0 {
+          break;
+        }
+        // newest first, so the first checkpoint seen in a bucket is its latest
+        let current = bucket(self.timezone.naive(*time));
+        if last_bucket != Some(current) {
+          keep(checkpoint, reason);
+          remaining -= 1;
+          last_bucket = Some(current);
+        }
+      }
+    }
+
+    if let (Some(within), Some((_, newest))) = (self.keep_within, sorted.first()) {
+      for (checkpoint, time) in &sorted {
+        if (*newest - *time).to_std().unwrap_or_default() <= within {
+          keep(checkpoint, KeepReason::Within(within));
+        }
+      }
+    }
+
+    Ok(kept)
+  }
+}
@@ -0,0 +1,174 @@
+//! Naming checkpoints the way git names commits.
+//!
+//! A revision is a checkpoint id or a unique prefix of one, or a series of
+//! checkpoints with an optional selector:
+//!
+//! - `web` or `web@latest`: the newest checkpoint tagged `web`
+//! - `pid:1234@latest`: the newest checkpoint of pid 1234
+//! - `web@{1 hour ago}`: the newest checkpoint of `web` dumped at least an
+//!   hour ago, `@{2025-05-07 12:00:00}` takes a UTC time instead
+//! - `@latest` and `@{...}` without a series pick among all checkpoints
+//!
+//! `~N` goes N checkpoints further back, `web~2` is two before the newest of
+//! `web`. After an id it follows `parent_id` links instead. A name that is
+//! both a tag and an id prefix is ambiguous.
+
+use crate::error::{Error, Result};
+use crate::store::{CheckpointStore, MIN_PREFIX_LEN};
+use crate::utils::CheckpointMeta;
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+
+enum Series<'a> {
+  All,
+  Pid(i32),
+  /// a tag, or an id prefix without a selector
+  Name(&'a str),
+}
+
+enum Selector {
+  Latest,
+  /// newest dumped at or before this time
+  At(DateTime<Utc>),
+}
+
+struct Revision<'a> {
+  series: Series<'a>,
+  selector: Option<Selector>,
+  back: usize,
+}
+
+/// The checkpoint `revision` names.
+pub fn resolve(store: &CheckpointStore, revision: &str) -> Result<CheckpointMeta> {
+  let parsed = parse(revision, Utc::now())?;
+  let checkpoints = store.list()?;
+  let series = match (&parsed.series, &parsed.selector) {
+    (Series::All, _) => checkpoints,
+    (Series::Pid(pid), _) => checkpoints.into_iter().filter(|c| c.pid == *pid).collect(),
+    (Series::Name(tag), Some(_)) => checkpoints.into_iter().filter(|c| c.tag == *tag).collect(),
+    (Series::Name(name), None) => {
+      let ids = id_matches(&checkpoints, name);
+      let tagged = newest_first(checkpoints.iter().filter(|c| c.tag == *name).cloned())?;
+      match (ids.len(), tagged.first()) {
+        (0, None) if is_short_id(name) => return Err(Error::PrefixTooShort(name.to_string())),
+        (0, None) => return Err(Error::CheckpointNotFound(revision.to_string())),
+        (0, Some(_)) => tagged,
+        (1, None) => return parents(store, ids[0].clone(), parsed.back, revision),
+        (_, newest) => {
+          return Err(Error::AmbiguousRevision {
+            revision: revision.to_string(),
+            candidates: ids.into_iter().cloned().chain(newest.cloned()).collect(),
+          });
+        }
+      }
+    }
+  };
+
+  let mut series = newest_first(series.into_iter())?;
+  if let Some(Selector::At(time)) = parsed.selector {
+    // dump times are stored in UTC, so they compare as timestamps
+    series.retain(|c| c.timestamp().is_ok_and(|t| t <= time));
+  }
+  series
+    .into_iter()
+    .nth(parsed.back)
+    .ok_or_else(|| Error::CheckpointNotFound(revision.to_string()))
+}
+
+fn parse(revision: &str, now: DateTime<Utc>) -> Result<Revision<'_>> {
+  let invalid = |message: &str| Error::InvalidRevision {
+    revision: revision.to_string(),
+    message: message.to_string(),
+  };
+
+  let (rest, back) = match revision.rsplit_once('~') {
+    Some((rest, "")) => (rest, 1),
+    Some((rest, n)) => (rest, n.parse().map_err(|_| invalid("`~` takes a count"))?),
+    None => (revision, 0),
+  };
+  let (series, selector) = if let Some(series) = rest.strip_suffix("@latest") {
+    (series, Some(Selector::Latest))
+  } else if let Some((series, time)) = rest.rsplit_once("@{") {
+    let time = time
+      .strip_suffix('}')
+      .ok_or_else(|| invalid("`@{` is not closed"))?;
+    let time = parse_time(time, now).ok_or_else(|| {
+      invalid("expected a duration like `1 hour ago` or a time like `2025-05-07 12:00:00`")
+    })?;
+    (series, Some(Selector::At(time)))
+  } else {
+    (rest, None)
+  };
+
+  let series = if series.is_empty() {
+    if selector.is_none() {
+      return Err(invalid(
+        "expected a checkpoint id, a tag, `pid:<pid>` or `@`",
+      ));
+    }
+    Series::All
+  } else if let Some(pid) = series.strip_prefix("pid:") {
+    Series::Pid(pid.parse().map_err(|_| invalid("`pid:` takes a pid"))?)
+  } else {
+    Series::Name(series)
+  };
+  Ok(Revision {
+    series,
+    selector,
+    back,
+  })
+}
+
+/// `1 hour ago`, or a time in UTC.
+fn parse_time(text: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+  let text = text.trim();
+  if let Some(ago) = text.strip_suffix("ago") {
+    let duration = humantime::parse_duration(ago.trim()).ok()?;
+    return now.checked_sub_signed(chrono::Duration::from_std(duration).ok()?);
+  }
+  humantime::parse_rfc3339_weak(text).ok().map(DateTime::from)
+}
+
+fn is_id(name: &str) -> bool {
+  name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_short_id(name: &str) -> bool {
+  is_id(name) && name.len() < MIN_PREFIX_LEN
+}
+
+fn id_matches<'a>(checkpoints: &'a [CheckpointMeta], name: &str) -> Vec<&'a CheckpointMeta> {
+  if !is_id(name) || name.len() < MIN_PREFIX_LEN {
+    return Vec::new();
+  }
+  let prefix = name.to_ascii_lowercase();
+  checkpoints
+    .iter()
+    .filter(|c| c.checkpoint_id.starts_with(&prefix))
+    .collect()
+}
+
+fn newest_first(checkpoints: impl Iterator<Item = CheckpointMeta>) -> Result<Vec<CheckpointMeta>> {
+  let mut dated = Vec::new();
+  for checkpoint in checkpoints {
+    dated.push((checkpoint.timestamp()?, checkpoint));
+  }
+  dated.sort_by_key(|(time, _)| Reverse(*time));
+  Ok(dated.into_iter().map(|(_, c)| c).collect())
+}
+
+/// Follow `back` parent links from `checkpoint`.
+fn parents(
+  store: &CheckpointStore,
+  mut checkpoint: CheckpointMeta,
+  back: usize,
+  revision: &str,
+) -> Result<CheckpointMeta> {
+  for _ in 0..back {
+    let Some(parent_id) = checkpoint.parent_id else {
+      return Err(Error::CheckpointNotFound(revision.to_string()));
+    };
+    checkpoint = store.get(&parent_id)?;
+  }
+  Ok(checkpoint)
+}
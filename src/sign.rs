@@ -0,0 +1,336 @@
+//! Ed25519 signatures over the manifest and metadata of a checkpoint.
+//!
+//! A signature covers `manifest.toml`, and with it the images and the
+//! targets of the `parent` links, and the fields of `meta.toml` that
+//! describe the dump, pinning and the dump status are left out as they
+//! change during the life of a checkpoint. It is kept in `signature.toml`
+//! next to them with the public key that made it and the signed fields, so
+//! fields added to the metadata later don't break old signatures. Restore
+//! checks every checkpoint of the chain against the keys listed in
+//! `trusted_keys` in the store root, one hex public key per line.
+
+use crate::config::UnsignedPolicy;
+use crate::error::{Error, IoContext, Result};
+use crate::manifest::MANIFEST_FILE;
+use crate::options::DumpOptions;
+use crate::store::CheckpointStore;
+use crate::utils::{self, CheckpointMeta};
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE_FILE: &str = "signature.toml";
+pub const TRUSTED_KEYS_FILE: &str = "trusted_keys";
+
+/// Where the signing key is kept unless the config names another file.
+const KEY_FILE: &str = "hcriu/signing.key";
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+struct SignatureFile {
+  /// hex public key of the signer
+  key: String,
+  /// hex Ed25519 signature
+  signature: String,
+  /// the signed fields of the metadata, as TOML
+  meta: String,
+}
+
+/// The fields of `meta.toml` a signature covers.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct SignedMeta {
+  checkpoint_id: String,
+  pid: i32,
+  cmd: String,
+  tag: String,
+  dump_time: String,
+  parent_id: Option<String>,
+  options: DumpOptions,
+}
+
+impl From<&CheckpointMeta> for SignedMeta {
+  fn from(meta: &CheckpointMeta) -> Self {
+    SignedMeta {
+      checkpoint_id: meta.checkpoint_id.clone(),
+      pid: meta.pid,
+      cmd: meta.cmd.clone(),
+      tag: meta.tag.clone(),
+      dump_time: meta.dump_time.clone(),
+      parent_id: meta.parent_id.clone(),
+      options: meta.options.clone(),
+    }
+  }
+}
+
+/// Who signed a checkpoint, as far as the store is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignedBy {
+  Trusted(String),
+  /// a valid signature by a key missing from `trusted_keys`
+  Untrusted(String),
+  Unsigned,
+}
+
+/// The signing key of the invoking user, created on first use.
+pub fn signing_key(store: &CheckpointStore) -> Result<SigningKey> {
+  let path = key_path(store)?;
+  if path.exists() {
+    return read_key(&path);
+  }
+  let mut secret = [0; 32];
+  OsRng.fill_bytes(&mut secret);
+  let key = SigningKey::from_bytes(&secret);
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir).with_path(dir)?;
+  }
+  std::fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(0o600)
+    .open(&path)
+    .and_then(|mut file| writeln!(file, "{}", hex::encode(secret)))
+    .with_path(&path)?;
+  // stdout may carry a stream
+  eprintln!(
+    "Created signing key {}, public key {}",
+    path.display(),
+    hex::encode(key.verifying_key().as_bytes())
+  );
+  Ok(key)
+}
+
+/// Sign the manifest and metadata of the checkpoint in `checkpoint_dir`.
+pub fn sign_checkpoint(
+  checkpoint_dir: &Path,
+  meta: &CheckpointMeta,
+  key: &SigningKey,
+) -> Result<()> {
+  let path = checkpoint_dir.join(SIGNATURE_FILE);
+  let corrupt = |e: toml::ser::Error| Error::CorruptMeta {
+    path: path.clone(),
+    message: e.to_string(),
+  };
+  let signed = toml::to_string(&SignedMeta::from(meta)).map_err(corrupt)?;
+  let signature = key.sign(&signed_bytes(checkpoint_dir, &signed)?);
+  let file = SignatureFile {
+    key: hex::encode(key.verifying_key().as_bytes()),
+    signature: hex::encode(signature.to_bytes()),
+    meta: signed,
+  };
+  let text = toml::to_string(&file).map_err(corrupt)?;
+  std::fs::write(&path, text).with_path(&path)
+}
+
+/// Sign the checkpoint in `checkpoint_dir` again after hcriu rewrote it,
+/// when it was signed with the local key. A signature by anyone else can't
+/// be kept and is dropped. The old signature is not checked, callers check
+/// it before rewriting anything they did not write themselves.
+pub(crate) fn resign(
+  store: &CheckpointStore,
+  checkpoint_dir: &Path,
+  meta: &CheckpointMeta,
+) -> Result<()> {
+  let Some(signature) = load_signature(checkpoint_dir)? else {
+    return Ok(());
+  };
+  let path = key_path(store)?;
+  if path.exists() {
+    let key = read_key(&path)?;
+    if hex::encode(key.verifying_key().as_bytes()) == signature.key {
+      return sign_checkpoint(checkpoint_dir, meta, &key);
+    }
+  }
+  let path = checkpoint_dir.join(SIGNATURE_FILE);
+  std::fs::remove_file(&path).with_path(&path)?;
+  eprintln!(
+    "Dropped the signature of checkpoint {}, it was made with another key",
+    meta.checkpoint_id
+  );
+  Ok(())
+}
+
+/// Check the signature of a checkpoint, failing when there is one that does
+/// not match.
+pub fn check_signature(store: &CheckpointStore, meta: &CheckpointMeta) -> Result<SignedBy> {
+  signed_by(store, &store.checkpoint_dir(&meta.checkpoint_id), meta)
+}
+
+fn signed_by(
+  store: &CheckpointStore,
+  checkpoint_dir: &Path,
+  meta: &CheckpointMeta,
+) -> Result<SignedBy> {
+  let Some(key) = verify_dir(checkpoint_dir, meta)? else {
+    return Ok(SignedBy::Unsigned);
+  };
+  if trusted_keys(store)?.contains(&key) {
+    Ok(SignedBy::Trusted(key))
+  } else {
+    Ok(SignedBy::Untrusted(key))
+  }
+}
+
+/// The hex public key that signed the checkpoint in `checkpoint_dir`, `None`
+/// when it is not signed. A signature that does not match is an error.
+pub(crate) fn verify_dir(checkpoint_dir: &Path, meta: &CheckpointMeta) -> Result<Option<String>> {
+  let Some(file) = load_signature(checkpoint_dir)? else {
+    return Ok(None);
+  };
+  let bad = || Error::BadSignature(meta.checkpoint_id.clone());
+  let key = hex::decode(&file.key)
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+    .ok_or_else(bad)?;
+  let signature = hex::decode(&file.signature)
+    .ok()
+    .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    .ok_or_else(bad)?;
+  key
+    .verify(&signed_bytes(checkpoint_dir, &file.meta)?, &signature)
+    .map_err(|_| bad())?;
+  // the signed fields have to be the ones restore is about to use
+  let signed: SignedMeta = toml::from_str(&file.meta).map_err(|_| bad())?;
+  if signed != SignedMeta::from(meta) {
+    return Err(bad());
+  }
+  Ok(Some(file.key))
+}
+
+/// Fail unless every checkpoint restoring `checkpoint` reads from is signed
+/// by a trusted key, or `policy` lets it through.
+pub(crate) fn check_chain(
+  store: &CheckpointStore,
+  checkpoint: &CheckpointMeta,
+  policy: UnsignedPolicy,
+) -> Result<()> {
+  for checkpoint in store.parent_chain(&checkpoint.checkpoint_id)? {
+    let checkpoint_dir = store.checkpoint_dir(&checkpoint.checkpoint_id);
+    check_policy(store, &checkpoint_dir, &checkpoint, policy)?;
+  }
+  Ok(())
+}
+
+/// Fail unless the checkpoint in `checkpoint_dir`, which may be outside the
+/// store, is signed by a key the store trusts, or `policy` lets it through.
+pub(crate) fn check_policy(
+  store: &CheckpointStore,
+  checkpoint_dir: &Path,
+  checkpoint: &CheckpointMeta,
+  policy: UnsignedPolicy,
+) -> Result<()> {
+  let reason = match signed_by(store, checkpoint_dir, checkpoint)? {
+    SignedBy::Trusted(_) => return Ok(()),
+    SignedBy::Untrusted(key) => format!("is signed by untrusted key {}", key),
+    SignedBy::Unsigned => "is not signed".to_string(),
+  };
+  match policy {
+    UnsignedPolicy::Allow => {}
+    UnsignedPolicy::Warn => eprintln!("Checkpoint {} {}", checkpoint.checkpoint_id, reason),
+    UnsignedPolicy::Deny => {
+      return Err(Error::Untrusted {
+        checkpoint_id: checkpoint.checkpoint_id.clone(),
+        reason,
+      });
+    }
+  }
+  Ok(())
+}
+
+/// Add `key` to the trusted keys of the store, the local public key without
+/// one.
+pub fn handle_trust(store: &CheckpointStore, key: Option<&str>) -> Result<()> {
+  let key = match key {
+    Some(key) => key.trim().to_lowercase(),
+    None => hex::encode(signing_key(store)?.verifying_key().as_bytes()),
+  };
+  let path = store.root().join(TRUSTED_KEYS_FILE);
+  let valid = hex::decode(&key)
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .is_some_and(|bytes| VerifyingKey::from_bytes(&bytes).is_ok());
+  if !valid {
+    return Err(Error::InvalidConfig {
+      path,
+      message: format!("{} is not a hex Ed25519 public key", key),
+    });
+  }
+  if trusted_keys(store)?.contains(&key) {
+    println!("Key {} is already trusted", key);
+    return Ok(());
+  }
+  std::fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(&path)
+    .and_then(|mut file| writeln!(file, "{}", key))
+    .with_path(&path)?;
+  println!("Trusted key {}", key);
+  Ok(())
+}
+
+/// Keys listed in `trusted_keys`, `#` starts a comment.
+fn trusted_keys(store: &CheckpointStore) -> Result<HashSet<String>> {
+  let path = store.root().join(TRUSTED_KEYS_FILE);
+  if !path.exists() {
+    return Ok(HashSet::new());
+  }
+  let text = std::fs::read_to_string(&path).with_path(&path)?;
+  Ok(
+    text
+      .lines()
+      .filter_map(|line| line.split('#').next()?.split_whitespace().next())
+      .map(str::to_lowercase)
+      .collect(),
+  )
+}
+
+/// The manifest of the checkpoint followed by the signed metadata.
+fn signed_bytes(checkpoint_dir: &Path, signed_meta: &str) -> Result<Vec<u8>> {
+  let manifest_path = checkpoint_dir.join(MANIFEST_FILE);
+  let mut bytes = b"hcriu checkpoint signature\n".to_vec();
+  bytes.extend(std::fs::read(&manifest_path).with_path(&manifest_path)?);
+  bytes.extend(signed_meta.as_bytes());
+  Ok(bytes)
+}
+
+fn load_signature(checkpoint_dir: &Path) -> Result<Option<SignatureFile>> {
+  let path = checkpoint_dir.join(SIGNATURE_FILE);
+  if !path.exists() {
+    return Ok(None);
+  }
+  let text = std::fs::read_to_string(&path).with_path(&path)?;
+  toml::from_str(&text)
+    .map(Some)
+    .map_err(|e| Error::CorruptMeta {
+      path,
+      message: e.to_string(),
+    })
+}
+
+fn key_path(store: &CheckpointStore) -> Result<PathBuf> {
+  match store.config()?.signing.key_file {
+    Some(path) => utils::expand_home(&path),
+    None => Ok(
+      dirs::config_dir()
+        .ok_or(Error::HomeNotFound)?
+        .join(KEY_FILE),
+    ),
+  }
+}
+
+fn read_key(path: &Path) -> Result<SigningKey> {
+  let text = std::fs::read_to_string(path).with_path(path)?;
+  hex::decode(text.trim())
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .map(|secret: [u8; 32]| SigningKey::from_bytes(&secret))
+    .ok_or_else(|| Error::InvalidConfig {
+      path: path.to_path_buf(),
+      message: "not a hex Ed25519 secret key".to_string(),
+    })
+}
@@ -0,0 +1,301 @@
+//! Fold the pages of incremental parents into a child checkpoint.
+//!
+//! CRIU describes the memory of every process in a `pagemap-*.img` file: a
+//! list of page ranges, each either stored in the raw `pages-<id>.img` file of
+//! the same image dir or marked as living in the `parent` image dir. Squashing
+//! rewrites those two files so the child carries the parent's pages itself.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::image::{Pagemap, PagemapEntry};
+
+/// Rewrite the images in `image_dir` so they stop reading from their parents.
+///
+/// The `parent` links are followed and every image dir on the way is folded in
+/// until `new_parent` (relative to `image_dir`) is reached. Pages still living
+/// there stay marked as in parent and `new_parent` becomes the target of the
+/// `parent` link. Without `new_parent` the whole chain is folded.
+pub fn squash_images(image_dir: &Path, new_parent: Option<&str>) -> io::Result<()> {
+  let ancestors = fold_chain(image_dir, new_parent)?;
+
+  let mut staged = Vec::new();
+  for entry in fs::read_dir(image_dir)? {
+    let name = entry?.file_name().to_string_lossy().into_owned();
+    if name.starts_with("pagemap") && name.ends_with(".img") {
+      staged.extend(squash_pagemap(image_dir, &name, &ancestors, new_parent)?);
+    }
+  }
+
+  // only swap files in once every pagemap has been rewritten
+  for (tmp, path) in staged {
+    fs::rename(tmp, path)?;
+  }
+
+  let link = image_dir.join("parent");
+  if link.symlink_metadata().is_ok() {
+    fs::remove_file(&link)?;
+  }
+  if let Some(new_parent) = new_parent {
+    std::os::unix::fs::symlink(new_parent, &link)?;
+  }
+  Ok(())
+}
+
+/// Image dirs between `image_dir` and `new_parent`, nearest parent first.
+fn fold_chain(image_dir: &Path, new_parent: Option<&str>) -> io::Result<Vec<PathBuf>> {
+  let stop = match new_parent {
+    Some(new_parent) => Some(image_dir.join(new_parent).canonicalize()?),
+    None => None,
+  };
+
+  let mut chain = Vec::new();
+  let mut dir = image_dir.to_path_buf();
+  while let Ok(link) = fs::read_link(dir.join("parent")) {
+    let parent = dir.join(link).canonicalize()?;
+    if stop.as_ref() == Some(&parent) {
+      break;
+    }
+    chain.push(parent.clone());
+    dir = parent;
+  }
+  Ok(chain)
+}
+
+/// Squash one pagemap, returns the `(staged, final)` paths of the new files.
+fn squash_pagemap(
+  image_dir: &Path,
+  name: &str,
+  ancestors: &[PathBuf],
+  new_parent: Option<&str>,
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+  let page_size = procfs::page_size();
+  let mut own = Layer::open(image_dir, name)?;
+  let mut layers = ancestors
+    .iter()
+    .map(|dir| Layer::open(dir, name))
+    .collect::<io::Result<Vec<_>>>()?;
+
+  let pagemap_path = image_dir.join(name);
+  let pages_path = image_dir.join(format!("pages-{}.img", own.pagemap.pages_id));
+  let pagemap_tmp = pagemap_path.with_extension("img.squash");
+  let pages_tmp = pages_path.with_extension("img.squash");
+  let mut pages_out = BufWriter::new(File::create(&pages_tmp)?);
+
+  let mut entries: Vec<PagemapEntry> = Vec::new();
+  let own_entries = own.pagemap.entries.clone();
+  for (idx, entry) in own_entries.iter().enumerate() {
+    let segments = if entry.is_parent() {
+      let mut segments = Vec::new();
+      resolve(
+        &layers,
+        0,
+        entry.vaddr,
+        entry.nr_pages as u64,
+        &mut segments,
+      )?;
+      segments
+    } else if entry.is_present() {
+      vec![Segment::Data {
+        layer: None,
+        offset: own.offsets[idx].unwrap(),
+        vaddr: entry.vaddr,
+        nr_pages: entry.nr_pages as u64,
+      }]
+    } else {
+      // lazy pages are not stored in any image, keep the entry untouched
+      entries.push(entry.clone());
+      continue;
+    };
+
+    for segment in segments {
+      match segment {
+        Segment::Data {
+          layer,
+          offset,
+          vaddr,
+          nr_pages,
+        } => {
+          let source = match layer {
+            Some(layer) => &mut layers[layer],
+            None => &mut own,
+          };
+          source.copy_pages(offset, nr_pages * page_size, &mut pages_out)?;
+          push_range(&mut entries, entry, vaddr, nr_pages, false);
+        }
+        Segment::Parent { vaddr, nr_pages } => {
+          if new_parent.is_none() {
+            return Err(io::Error::new(
+              io::ErrorKind::NotFound,
+              format!("{}: page {:#x} is not in the parent chain", name, vaddr),
+            ));
+          }
+          push_range(&mut entries, entry, vaddr, nr_pages, true);
+        }
+      }
+    }
+  }
+  pages_out.flush()?;
+
+  let pagemap = Pagemap {
+    image_magic: own.pagemap.image_magic,
+    magic: own.pagemap.magic,
+    pages_id: own.pagemap.pages_id,
+    entries,
+  };
+  fs::write(&pagemap_tmp, pagemap.encode())?;
+
+  Ok(vec![(pagemap_tmp, pagemap_path), (pages_tmp, pages_path)])
+}
+
+/// Find where the pages `[vaddr, vaddr + nr_pages)` live, starting at `depth`.
+fn resolve(
+  layers: &[Layer],
+  depth: usize,
+  vaddr: u64,
+  nr_pages: u64,
+  segments: &mut Vec<Segment>,
+) -> io::Result<()> {
+  if depth == layers.len() {
+    segments.push(Segment::Parent { vaddr, nr_pages });
+    return Ok(());
+  }
+
+  let page_size = procfs::page_size();
+  let layer = &layers[depth];
+  let end = vaddr + nr_pages * page_size;
+  let mut cur = vaddr;
+  while cur < end {
+    let idx = layer.find(cur).ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("page {:#x} missing in {}", cur, layer.dir.display()),
+      )
+    })?;
+    let entry = &layer.pagemap.entries[idx];
+    let n = (end.min(entry.end(page_size)) - cur) / page_size;
+    if entry.is_present() {
+      segments.push(Segment::Data {
+        layer: Some(depth),
+        offset: layer.offsets[idx].unwrap() + (cur - entry.vaddr),
+        vaddr: cur,
+        nr_pages: n,
+      });
+    } else if entry.is_parent() {
+      resolve(layers, depth + 1, cur, n, segments)?;
+    } else {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("page {:#x} in {} was not dumped", cur, layer.dir.display()),
+      ));
+    }
+    cur += n * page_size;
+  }
+  Ok(())
+}
+
+/// Append a range to `entries`, merging it with the previous one if possible.
+fn push_range(
+  entries: &mut Vec<PagemapEntry>,
+  template: &PagemapEntry,
+  vaddr: u64,
+  nr_pages: u64,
+  in_parent: bool,
+) {
+  let page_size = procfs::page_size();
+  let range = template.with_location(vaddr, nr_pages as u32, in_parent);
+  if let Some(last) = entries.last_mut()
+    && last.flags == range.flags
+    && last.in_parent == range.in_parent
+    && last.end(page_size) == vaddr
+  {
+    last.nr_pages += range.nr_pages;
+    return;
+  }
+  entries.push(range);
+}
+
+enum Segment {
+  /// pages stored in the pages file of a layer, `None` being the child itself
+  Data {
+    layer: Option<usize>,
+    offset: u64,
+    vaddr: u64,
+    nr_pages: u64,
+  },
+  /// pages that stay in the parent kept after the squash
+  Parent { vaddr: u64, nr_pages: u64 },
+}
+
+/// One image dir of the chain, with its pagemap and open pages file.
+struct Layer {
+  dir: PathBuf,
+  pagemap: Pagemap,
+  /// offset in the pages file of every present entry
+  offsets: Vec<Option<u64>>,
+  pages: Option<File>,
+}
+
+impl Layer {
+  fn open(dir: &Path, name: &str) -> io::Result<Self> {
+    let pagemap = Pagemap::parse(&fs::read(dir.join(name))?)?;
+    let page_size = procfs::page_size();
+    let mut offset = 0;
+    let offsets = pagemap
+      .entries
+      .iter()
+      .map(|e| {
+        if e.is_present() {
+          let o = offset;
+          offset += e.nr_pages as u64 * page_size;
+          Some(o)
+        } else {
+          None
+        }
+      })
+      .collect();
+    let pages_path = dir.join(format!("pages-{}.img", pagemap.pages_id));
+    let pages = if pages_path.exists() {
+      Some(File::open(pages_path)?)
+    } else {
+      None
+    };
+    Ok(Layer {
+      dir: dir.to_path_buf(),
+      pagemap,
+      offsets,
+      pages,
+    })
+  }
+
+  /// Index of the entry covering `vaddr`, entries are sorted by address.
+  fn find(&self, vaddr: u64) -> Option<usize> {
+    let page_size = procfs::page_size();
+    let entries = &self.pagemap.entries;
+    let idx = entries.partition_point(|e| e.vaddr <= vaddr);
+    if idx == 0 || entries[idx - 1].end(page_size) <= vaddr {
+      None
+    } else {
+      Some(idx - 1)
+    }
+  }
+
+  fn copy_pages(&mut self, offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
+    let pages = self.pages.as_mut().ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("pages image missing in {}", self.dir.display()),
+      )
+    })?;
+    pages.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut pages.take(len), out)?;
+    if copied != len {
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("pages image truncated in {}", self.dir.display()),
+      ));
+    }
+    Ok(())
+  }
+}
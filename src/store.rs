@@ -0,0 +1,321 @@
+//! A directory of checkpoints, one subdirectory per checkpoint id.
+//!
+//! Nothing is global, a process can open several stores at once, for example
+//! a hot store on fast disk and an archive store next to it.
+//!
+//! A dump is written to `.staging-<id>` next to the checkpoints and renamed
+//! into place once CRIU succeeded, so a checkpoint dir is always complete.
+//! The staging dir sits at the same depth as a checkpoint dir, so relative
+//! `parent` links resolve the same from both. A dump that waits on the
+//! network without the store lock keeps its staging dir locked instead. The
+//! logs of a failed dump are moved to `.quarantine/<id>`. Entries starting
+//! with a dot are not checkpoints. An encrypted store keeps the salt of its
+//! key in `encryption.toml`, a deduplicated one its chunks in `.chunks`.
+
+use crate::config::StoreConfig;
+use crate::crypt::{Key, KeySource, PASSPHRASE_ENV};
+use crate::dedup;
+use crate::error::{Error, IoContext, Result};
+use crate::utils::{self, CheckpointMeta, CheckpointStatus};
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Shortest prefix accepted by [`CheckpointStore::get_by_prefix`].
+pub const MIN_PREFIX_LEN: usize = 4;
+
+const LOCK_FILE: &str = ".lock";
+pub(crate) const CONFIG_FILE: &str = "config.toml";
+pub(crate) const STAGING_PREFIX: &str = ".staging-";
+const QUARANTINE_DIR: &str = ".quarantine";
+
+#[derive(Debug, Clone)]
+pub struct CheckpointStore {
+  root: PathBuf,
+  /// where the key comes from when the images are encrypted
+  key_source: Option<KeySource>,
+  /// unlocked on first use, so stores that are not encrypted never ask
+  key: OnceLock<Key>,
+}
+
+/// Exclusive lock on a store, released on drop.
+#[derive(Debug)]
+pub struct StoreLock {
+  _file: File,
+}
+
+impl CheckpointStore {
+  /// Open the store at `root`, creating the directory if needed.
+  pub fn create(root: impl AsRef<Path>) -> Result<Self> {
+    let root = utils::expand_home(root.as_ref())?;
+    std::fs::create_dir_all(&root).with_path(&root)?;
+    Ok(CheckpointStore::at(root))
+  }
+
+  /// Open an existing store, `~` is expanded to the invoking user's home.
+  pub fn open(root: impl AsRef<Path>) -> Result<Self> {
+    let root = utils::expand_home(root.as_ref())?;
+    if !root.is_dir() {
+      return Err(Error::StoreNotFound(root));
+    }
+    Ok(CheckpointStore::at(root))
+  }
+
+  fn at(root: PathBuf) -> Self {
+    CheckpointStore {
+      root,
+      key_source: None,
+      key: OnceLock::new(),
+    }
+  }
+
+  /// Use the key from `source` for encrypted images instead of looking for
+  /// one in the environment and the config.
+  pub fn with_key(mut self, source: KeySource) -> Self {
+    self.key_source = Some(source);
+    self.key = OnceLock::new();
+    self
+  }
+
+  /// The key of an encrypted store, from the source given with
+  /// [`Self::with_key`], `HCRIU_PASSPHRASE`, the configured key file, or a
+  /// passphrase asked for on the terminal, in that order.
+  pub(crate) fn key(&self) -> Result<Key> {
+    if let Some(key) = self.key.get() {
+      return Ok(key.clone());
+    }
+    let source = match &self.key_source {
+      Some(source) => source.clone(),
+      None => match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => KeySource::Passphrase(passphrase),
+        Err(_) => match self.config()?.encryption.key_file {
+          Some(path) => KeySource::File(utils::expand_home(&path)?),
+          None => KeySource::Prompt,
+        },
+      },
+    };
+    let key = Key::unlock(&self.root, &source)?;
+    Ok(self.key.get_or_init(|| key).clone())
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  pub fn config_path(&self) -> PathBuf {
+    self.root.join(CONFIG_FILE)
+  }
+
+  /// Settings from `config.toml` in the store root, defaults if there is none.
+  pub fn config(&self) -> Result<StoreConfig> {
+    StoreConfig::load(&self.config_path())
+  }
+
+  pub fn checkpoint_dir(&self, checkpoint_id: &str) -> PathBuf {
+    self.root.join(checkpoint_id)
+  }
+
+  pub fn meta_path(&self, checkpoint_id: &str) -> PathBuf {
+    self.checkpoint_dir(checkpoint_id).join("meta.toml")
+  }
+
+  /// All checkpoints in the store, in directory order. Directories without
+  /// readable metadata or with an unfinished dump are skipped with a warning,
+  /// `hcriu fsck` tells what is wrong with them.
+  pub fn list(&self) -> Result<Vec<CheckpointMeta>> {
+    let mut checkpoints = Vec::new();
+    for entry in std::fs::read_dir(&self.root).with_path(&self.root)? {
+      let path = entry.with_path(&self.root)?.path();
+      // the lock and config files, staging and quarantine live next to the
+      // checkpoints
+      if !path.is_dir() || is_hidden(&path) {
+        continue;
+      }
+      match CheckpointMeta::load(&path.join("meta.toml")) {
+        Ok(meta) if meta.status == CheckpointStatus::Complete => checkpoints.push(meta),
+        Ok(_) => eprintln!(
+          "Skipping unfinished dump {}, run hcriu fsck",
+          path.display()
+        ),
+        Err(e) => eprintln!("Skipping {}, run hcriu fsck: {}", path.display(), e),
+      }
+    }
+    Ok(checkpoints)
+  }
+
+  pub fn get(&self, checkpoint_id: &str) -> Result<CheckpointMeta> {
+    let meta_path = self.meta_path(checkpoint_id);
+    if !meta_path.exists() {
+      return Err(Error::CheckpointNotFound(checkpoint_id.to_string()));
+    }
+    CheckpointMeta::load(&meta_path)
+  }
+
+  /// Find the one checkpoint whose id starts with `prefix`.
+  pub fn get_by_prefix(&self, prefix: &str) -> Result<CheckpointMeta> {
+    if prefix.len() < MIN_PREFIX_LEN {
+      return Err(Error::PrefixTooShort(prefix.to_string()));
+    }
+
+    let mut candidates = self
+      .list()?
+      .into_iter()
+      .filter(|c| c.checkpoint_id.starts_with(prefix))
+      .collect::<Vec<_>>();
+
+    match candidates.len() {
+      0 => Err(Error::CheckpointNotFound(prefix.to_string())),
+      1 => Ok(candidates.remove(0)),
+      _ => Err(Error::AmbiguousRevision {
+        revision: prefix.to_string(),
+        candidates,
+      }),
+    }
+  }
+
+  /// Where a dump is written before it is complete.
+  pub fn staging_dir(&self, checkpoint_id: &str) -> PathBuf {
+    self
+      .root
+      .join(format!("{}{}", STAGING_PREFIX, checkpoint_id))
+  }
+
+  pub fn quarantine_dir(&self, checkpoint_id: &str) -> PathBuf {
+    self.root.join(QUARANTINE_DIR).join(checkpoint_id)
+  }
+
+  /// Move a finished dump from staging into the store.
+  pub fn commit(&self, checkpoint_id: &str) -> Result<()> {
+    let staging_dir = self.staging_dir(checkpoint_id);
+    let checkpoint_dir = self.checkpoint_dir(checkpoint_id);
+    if checkpoint_dir.exists() {
+      return Err(Error::CheckpointExists(checkpoint_id.to_string()));
+    }
+    std::fs::rename(&staging_dir, &checkpoint_dir).with_path(&staging_dir)
+  }
+
+  /// Keep the logs and metadata of a failed dump in quarantine and drop its
+  /// images. Returns the quarantine dir.
+  pub fn quarantine(&self, checkpoint_id: &str) -> Result<PathBuf> {
+    let staging_dir = self.staging_dir(checkpoint_id);
+    let quarantine_dir = self.quarantine_dir(checkpoint_id);
+    std::fs::create_dir_all(&quarantine_dir).with_path(&quarantine_dir)?;
+    for entry in std::fs::read_dir(&staging_dir).with_path(&staging_dir)? {
+      let path = entry.with_path(&staging_dir)?.path();
+      let keep = path.extension().is_some_and(|ext| ext == "log")
+        || path.file_name().is_some_and(|name| name == "meta.toml");
+      if keep && path.is_file() {
+        let target = quarantine_dir.join(path.file_name().unwrap_or_default());
+        std::fs::rename(&path, &target).with_path(&path)?;
+      }
+    }
+    std::fs::remove_dir_all(&staging_dir).with_path(&staging_dir)?;
+    Ok(quarantine_dir)
+  }
+
+  /// Move any entry of the store into quarantine as it is. Returns where it
+  /// ended up.
+  pub fn quarantine_entry(&self, path: &Path) -> Result<PathBuf> {
+    let quarantine = self.root.join(QUARANTINE_DIR);
+    std::fs::create_dir_all(&quarantine).with_path(&quarantine)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut target = quarantine.join(name.as_ref());
+    let mut n = 1;
+    while target.exists() {
+      target = quarantine.join(format!("{}.{}", name, n));
+      n += 1;
+    }
+    std::fs::rename(path, &target).with_path(path)?;
+    Ok(target)
+  }
+
+  /// Bytes used on disk by a checkpoint, its parents not included.
+  pub fn checkpoint_size(&self, checkpoint_id: &str) -> Result<u64> {
+    utils::dir_size(&self.checkpoint_dir(checkpoint_id))
+  }
+
+  /// Space left for unprivileged users on the filesystem of the store.
+  pub fn free_space(&self) -> Result<u64> {
+    utils::free_space(&self.root)
+  }
+
+  /// Chunks shared by the checkpoints of a deduplicated store.
+  pub fn chunks_dir(&self) -> PathBuf {
+    self.root.join(dedup::CHUNKS_DIR)
+  }
+
+  /// Bytes used on disk by the shared chunks.
+  pub fn chunks_size(&self) -> Result<u64> {
+    let chunks_dir = self.chunks_dir();
+    if chunks_dir.is_dir() {
+      utils::dir_size(&chunks_dir)
+    } else {
+      Ok(0)
+    }
+  }
+
+  /// Remove a checkpoint and its images, and the chunks no other checkpoint
+  /// shares. The caller holds the store lock.
+  pub fn delete(&self, checkpoint_id: &str) -> Result<()> {
+    let checkpoint_dir = self.checkpoint_dir(checkpoint_id);
+    if !checkpoint_dir.is_dir() {
+      return Err(Error::CheckpointNotFound(checkpoint_id.to_string()));
+    }
+    std::fs::remove_dir_all(&checkpoint_dir).with_path(&checkpoint_dir)?;
+    dedup::collect_garbage(self)?;
+    Ok(())
+  }
+
+  /// Block until no other hcriu process is changing the store.
+  pub fn lock(&self) -> Result<StoreLock> {
+    let path = self.root.join(LOCK_FILE);
+    let file = File::create(&path).with_path(&path)?;
+    file.lock().with_path(&path)?;
+    Ok(StoreLock { _file: file })
+  }
+
+  /// Mark the staging dir of a dump written without holding the store lock
+  /// as in use, until the returned lock is dropped.
+  pub(crate) fn reserve_staging(&self, checkpoint_id: &str) -> Result<StoreLock> {
+    let path = self.staging_dir(checkpoint_id);
+    let file = File::open(&path).with_path(&path)?;
+    file.lock().with_path(&path)?;
+    Ok(StoreLock { _file: file })
+  }
+
+  /// Whether a dump still writes to the staging dir at `path`.
+  pub(crate) fn staging_in_use(path: &Path) -> Result<bool> {
+    let file = File::open(path).with_path(path)?;
+    match file.try_lock() {
+      Ok(()) => Ok(false),
+      Err(TryLockError::WouldBlock) => Ok(true),
+      Err(TryLockError::Error(e)) => Err(e).with_path(path),
+    }
+  }
+
+  /// Follow `parent_id` links from `checkpoint_id` back to the first full dump.
+  ///
+  /// The returned chain starts with the checkpoint itself and ends with the
+  /// oldest ancestor that is still present in the store.
+  pub fn parent_chain(&self, checkpoint_id: &str) -> Result<Vec<CheckpointMeta>> {
+    let all_checkpoints = self.list()?;
+    let mut chain = Vec::new();
+    let mut next = Some(checkpoint_id.to_string());
+    while let Some(id) = next {
+      match all_checkpoints.iter().find(|c| c.checkpoint_id == id) {
+        Some(checkpoint) => {
+          next = checkpoint.parent_id.clone();
+          chain.push(checkpoint.clone());
+        }
+        None => break,
+      }
+    }
+    Ok(chain)
+  }
+}
+
+fn is_hidden(path: &Path) -> bool {
+  path
+    .file_name()
+    .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
@@ -1,50 +1,149 @@
-use chrono;
+use crate::error::{Error, IoContext, Result};
+use crate::options::DumpOptions;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use comfy_table::Table;
 use dirs::home_dir;
+use nix::sys::statvfs::statvfs;
 use procfs::process::Process;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::env;
-use std::fs::File;
+use std::fs::{File, FileType};
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
 
-static HCRIU_DIR: OnceLock<PathBuf> = OnceLock::new();
+/// Expand a leading `~`, under sudo to the invoking user's home rather than root's.
+pub fn expand_home(path: &Path) -> Result<PathBuf> {
+  let Ok(rest) = path.strip_prefix("~") else {
+    return Ok(path.to_path_buf());
+  };
+  if let Ok(sudo_user) = env::var("SUDO_USER") {
+    Ok(PathBuf::from(format!("/home/{}", sudo_user)).join(rest))
+  } else {
+    Ok(home_dir().ok_or(Error::HomeNotFound)?.join(rest))
+  }
+}
 
-pub fn set_hcriu_dir(hcriu_dir: PathBuf) {
-  let path = hcriu_dir.as_path();
-  let expanded_path = if path.starts_with("~") {
-    if let Ok(sudo_user) = env::var("SUDO_USER") {
-      #[cfg(unix)]
-      let home = PathBuf::from(format!("/home/{}", sudo_user));
-      home.join(path.strip_prefix("~").unwrap())
-    } else {
-      home_dir().unwrap().join(path.strip_prefix("~").unwrap())
+/// Total size of the files under `path`, symlinks are not followed.
+pub fn dir_size(path: &Path) -> Result<u64> {
+  let mut size = 0;
+  for entry in std::fs::read_dir(path).with_path(path)? {
+    let entry = entry.with_path(path)?;
+    let file_type = entry.file_type().with_path(&entry.path())?;
+    if file_type.is_dir() {
+      size += dir_size(&entry.path())?;
+    } else if file_type.is_file() {
+      size += entry.metadata().with_path(&entry.path())?.len();
     }
-  } else {
-    path.to_path_buf()
-  };
-  HCRIU_DIR.set(PathBuf::from(expanded_path)).unwrap();
+  }
+  Ok(size)
+}
+
+/// Space left for unprivileged users on the filesystem of `path`.
+pub fn free_space(path: &Path) -> Result<u64> {
+  let stat = statvfs(path)
+    .map_err(std::io::Error::from)
+    .with_path(path)?;
+  Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Subdirectories of a checkpoint holding images.
+pub const IMAGE_DIRS: [&str; 2] = ["image", "pre-dump"];
+
+/// Regular files under the image dirs of a checkpoint, `parent` links are
+/// not followed.
+pub fn image_files(checkpoint_dir: &Path) -> Result<Vec<PathBuf>> {
+  walk_images(checkpoint_dir, FileType::is_file)
+}
+
+/// The `parent` links under the image dirs of a checkpoint.
+pub fn image_links(checkpoint_dir: &Path) -> Result<Vec<PathBuf>> {
+  walk_images(checkpoint_dir, FileType::is_symlink)
 }
 
-pub fn get_hcriu_dir() -> PathBuf {
-  HCRIU_DIR.get().unwrap().clone()
+fn walk_images(checkpoint_dir: &Path, keep: fn(&FileType) -> bool) -> Result<Vec<PathBuf>> {
+  fn walk(dir: &Path, keep: fn(&FileType) -> bool, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_path(dir)? {
+      let entry = entry.with_path(dir)?;
+      let file_type = entry.file_type().with_path(&entry.path())?;
+      if file_type.is_dir() {
+        walk(&entry.path(), keep, found)?;
+      } else if keep(&file_type) {
+        found.push(entry.path());
+      }
+    }
+    Ok(())
+  }
+
+  let mut found = Vec::new();
+  for dir in IMAGE_DIRS {
+    let path = checkpoint_dir.join(dir);
+    if path.is_dir() {
+      walk(&path, keep, &mut found)?;
+    }
+  }
+  Ok(found)
+}
+
+/// Where a checkpoint is in its life, metadata written before this field
+/// existed is from a complete dump.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckpointStatus {
+  /// CRIU is still dumping into the staging dir
+  InProgress,
+  #[default]
+  Complete,
+  /// the dump failed, only its logs are kept in quarantine
+  Failed,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
 pub struct CheckpointMeta {
   pub checkpoint_id: String,
   pub pid: i32,
   pub cmd: String,
   pub tag: String,
   pub dump_time: String,
+  /// checkpoint whose images this one was dumped on top of (incremental dump)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub parent_id: Option<String>,
+  /// time the process stayed frozen by the final dump, in microseconds
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub freeze_time_us: Option<u64>,
+  /// pages written by each pre-dump pass, followed by the final dump
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub pages_written: Vec<u64>,
+  /// CRIU options used for the dump, replayed on restore
+  #[serde(default)]
+  pub options: DumpOptions,
+  /// protected from merge, retention and quota eviction
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub pinned: bool,
+  #[serde(default)]
+  pub status: CheckpointStatus,
+  /// where an imported checkpoint came from
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub provenance: Option<Provenance>,
+}
+
+/// Origin of a checkpoint brought in with `hcriu import`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Provenance {
+  /// id in the store it was exported from, it changes on an id collision
+  pub original_id: String,
+  /// host it was exported from
+  pub host: String,
+  pub exported_at: String,
+  pub imported_at: String,
+  /// file name of the archive
+  pub archive: String,
 }
 
 impl CheckpointMeta {
-  pub fn new(pid: i32, tag: &Option<String>) -> Self {
-    let cmd = get_process_cmd(pid);
-    let dump_time = chrono::Utc::now().to_string();
+  pub fn new(pid: i32, tag: &Option<String>, parent_id: Option<String>) -> Result<Self> {
+    let cmd = get_process_cmd(pid)?;
+    let dump_time = Utc::now().to_string();
     let tag = if let Some(tag) = tag {
       tag.clone()
     } else {
@@ -57,10 +156,17 @@ impl CheckpointMeta {
       cmd,
       tag,
       dump_time,
+      parent_id,
+      freeze_time_us: None,
+      pages_written: Vec::new(),
+      options: DumpOptions::default(),
+      pinned: false,
+      status: CheckpointStatus::InProgress,
+      provenance: None,
     };
 
     meta.update_checkpoint_id();
-    meta
+    Ok(meta)
   }
 
   fn update_checkpoint_id(&mut self) {
@@ -74,48 +180,75 @@ impl CheckpointMeta {
     self.checkpoint_id = format!("{:x}", hash);
   }
 
-  pub fn save(&self, path: &Path) -> Result<(), std::io::Error> {
-    let toml = toml::to_string(self).unwrap();
-    let mut file = File::create(path)?;
-    file.write_all(toml.as_bytes())?;
+  /// `dump_time` as a timestamp, it is stored the way `Utc::now()` displays.
+  pub fn timestamp(&self) -> Result<DateTime<Utc>> {
+    let time = self.dump_time.trim_end_matches(" UTC");
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f")
+      .map(|t| t.and_utc())
+      .map_err(|_| Error::InvalidTimestamp {
+        checkpoint_id: self.checkpoint_id.clone(),
+        value: self.dump_time.clone(),
+      })
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let toml = toml::to_string(self).map_err(|e| Error::CorruptMeta {
+      path: path.to_path_buf(),
+      message: e.to_string(),
+    })?;
+    let mut file = File::create(path).with_path(path)?;
+    file.write_all(toml.as_bytes()).with_path(path)?;
     Ok(())
   }
 
-  pub fn parse(meta: String) -> CheckpointMeta {
-    let meta: CheckpointMeta = toml::from_str(&meta).unwrap();
-    meta
+  pub fn load(path: &Path) -> Result<CheckpointMeta> {
+    let meta = std::fs::read_to_string(path).with_path(path)?;
+    toml::from_str(&meta).map_err(|e| Error::CorruptMeta {
+      path: path.to_path_buf(),
+      message: e.to_string(),
+    })
   }
 }
 
-fn get_process_cmd(pid: i32) -> String {
-  let process = Process::new(pid).unwrap();
-  process.cmdline().unwrap().join(" ")
+fn get_process_cmd(pid: i32) -> Result<String> {
+  let process = Process::new(pid).map_err(|_| Error::ProcessNotFound(pid))?;
+  let cmdline = process.cmdline().map_err(|_| Error::ProcessNotFound(pid))?;
+  Ok(cmdline.join(" "))
 }
 
-pub fn get_all_checkpoints() -> Vec<CheckpointMeta> {
-  let hcriu_dir = get_hcriu_dir();
-  std::fs::read_dir(hcriu_dir)
-    .unwrap()
-    .map(|c| {
-      let checkpoint = c.unwrap();
-      let meta_file = checkpoint.path().join("meta.toml");
-      let meta = CheckpointMeta::parse(std::fs::read_to_string(meta_file).unwrap());
-      meta
-    })
-    .collect()
+/// Columns of the checkpoint tables.
+pub const CHECKPOINT_HEADER: [&str; 7] = [
+  "Checkpoint ID",
+  "Tag",
+  "PID",
+  "Command",
+  "Dump Time",
+  "Parent",
+  "Pinned",
+];
+
+/// One row of the checkpoint tables, in the order of [`CHECKPOINT_HEADER`].
+pub fn checkpoint_row(checkpoint: &CheckpointMeta) -> Vec<String> {
+  vec![
+    checkpoint.checkpoint_id[..7].to_string(),
+    checkpoint.tag.clone(),
+    checkpoint.pid.to_string(),
+    checkpoint.cmd.clone(),
+    checkpoint.dump_time.clone(),
+    checkpoint
+      .parent_id
+      .as_ref()
+      .map(|p| p[..7].to_string())
+      .unwrap_or_default(),
+    if checkpoint.pinned { "yes" } else { "" }.to_string(),
+  ]
 }
 
 pub fn get_checkpoints_table(checkpoints: Vec<&CheckpointMeta>) -> Table {
   let mut table = Table::new();
-  table.set_header(vec!["Checkpoint ID", "Tag", "PID", "Command", "Dump Time"]);
+  table.set_header(CHECKPOINT_HEADER.to_vec());
   for checkpoint in checkpoints {
-    table.add_row(vec![
-      checkpoint.checkpoint_id[..7].to_string(),
-      checkpoint.tag.clone(),
-      checkpoint.pid.to_string(),
-      checkpoint.cmd.clone(),
-      checkpoint.dump_time.clone(),
-    ]);
+    table.add_row(checkpoint_row(checkpoint));
   }
   table
 }
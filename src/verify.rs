@@ -0,0 +1,67 @@
+use crate::error::{Error, Result};
+use crate::manifest::{Manifest, Mismatch};
+use crate::revision;
+use crate::store::CheckpointStore;
+use crate::utils::CheckpointMeta;
+
+/// Recheck the image hashes of one checkpoint, or of all of them without
+/// `checkpoint_id`.
+pub fn handle_verify(store: &CheckpointStore, checkpoint_id: Option<&str>) -> Result<()> {
+  let checkpoints = match checkpoint_id {
+    Some(revision) => vec![revision::resolve(store, revision)?],
+    None => store.list()?,
+  };
+
+  let mut failed = 0;
+  for checkpoint in &checkpoints {
+    let id = &checkpoint.checkpoint_id[..7];
+    match verify_checkpoint(store, checkpoint)? {
+      None => println!("Checkpoint {}: no manifest, dumped before manifests", id),
+      Some(mismatches) if mismatches.is_empty() => println!("Checkpoint {}: OK", id),
+      Some(mismatches) => {
+        failed += 1;
+        println!("Checkpoint {}: FAILED", id);
+        for mismatch in mismatches {
+          println!("  {}", mismatch);
+        }
+      }
+    }
+  }
+  if failed > 0 {
+    return Err(Error::VerifyFailed(failed));
+  }
+  Ok(())
+}
+
+/// What differs from the manifest of `checkpoint`, `None` if it has none.
+pub fn verify_checkpoint(
+  store: &CheckpointStore,
+  checkpoint: &CheckpointMeta,
+) -> Result<Option<Vec<Mismatch>>> {
+  let checkpoint_dir = store.checkpoint_dir(&checkpoint.checkpoint_id);
+  match Manifest::load(&checkpoint_dir)? {
+    Some(manifest) => manifest.verify(&checkpoint_dir).map(Some),
+    None => Ok(None),
+  }
+}
+
+/// Fail unless every checkpoint restoring `checkpoint` reads from matches its
+/// manifest. Checkpoints without one are let through with a warning.
+pub(crate) fn verify_chain(store: &CheckpointStore, checkpoint: &CheckpointMeta) -> Result<()> {
+  for checkpoint in store.parent_chain(&checkpoint.checkpoint_id)? {
+    match verify_checkpoint(store, &checkpoint)? {
+      None => eprintln!(
+        "Checkpoint {} has no manifest, its images are not verified",
+        checkpoint.checkpoint_id
+      ),
+      Some(mismatches) if mismatches.is_empty() => {}
+      Some(mismatches) => {
+        return Err(Error::Tampered {
+          checkpoint_id: checkpoint.checkpoint_id,
+          mismatches: mismatches.iter().map(|m| m.to_string()).collect(),
+        });
+      }
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::TestStore;
+use hcriu::{CheckpointStore, Error, FakeBackend, archive, restore};
+use std::path::Path;
+
+fn restore_head(store: &CheckpointStore, id: &str) {
+  restore::handle_restore(
+    store,
+    &mut FakeBackend::new(),
+    id.to_string(),
+    None,
+    false,
+    false,
+    None,
+  )
+  .unwrap();
+}
+
+#[test]
+fn export_and_import_a_chain() {
+  let source = TestStore::new();
+  let base = source.add("a1", "web", 1, 1, None);
+  let head = source.add("b2", "web", 2, 2, Some("a1"));
+  let output = source.path().join("ckpt.tar.zst");
+  archive::handle_export(&source, &head[..7], &output).unwrap();
+
+  let target = TestStore::new();
+  archive::handle_import(&target, &output).unwrap();
+  let imported = target.get(&head).unwrap();
+  assert_eq!(imported.parent_id.as_deref(), Some(base.as_str()));
+  let provenance = imported.provenance.unwrap();
+  assert_eq!(provenance.original_id, head);
+  assert_eq!(provenance.archive, "ckpt.tar.zst");
+  restore_head(&target, &head);
+
+  // importing again reuses what is there
+  archive::handle_import(&target, &output).unwrap();
+  assert_eq!(target.list().unwrap().len(), 2);
+}
+
+#[test]
+fn import_renames_taken_ids() {
+  let source = TestStore::new();
+  let base = source.add("a1", "web", 1, 1, None);
+  let head = source.add("b2", "web", 2, 2, Some("a1"));
+  let output = source.path().join("ckpt.tar");
+  archive::handle_export(&source, &head, &output).unwrap();
+
+  // another checkpoint with the id of the base
+  let target = TestStore::new();
+  target.add("a1", "web", 3, 3, None);
+  archive::handle_import(&target, &output).unwrap();
+  assert_eq!(target.list().unwrap().len(), 3);
+  let imported = target.get(&head).unwrap();
+  let new_base = imported.parent_id.clone().unwrap();
+  assert_ne!(new_base, base);
+  assert_eq!(target.get(&new_base).unwrap().pid, 1);
+  let link = target.checkpoint_dir(&head).join("image/parent");
+  assert_eq!(
+    std::fs::read_link(link).unwrap(),
+    Path::new(&format!("../../{}/image", new_base))
+  );
+  restore_head(&target, &head);
+}
+
+#[test]
+fn import_refuses_changed_images() {
+  let source = TestStore::new();
+  let head = source.add("a1", "web", 1, 1, None);
+  let unpacked = source.path().join("unpacked");
+  let output = source.path().join("ckpt.tar");
+  archive::handle_export(&source, &head, &output).unwrap();
+
+  tar::Archive::new(std::fs::File::open(&output).unwrap())
+    .unpack(&unpacked)
+    .unwrap();
+  std::fs::write(unpacked.join(&head).join("image/pages-1.img"), b"evil").unwrap();
+  let mut builder = tar::Builder::new(std::fs::File::create(&output).unwrap());
+  builder.append_dir_all(".", &unpacked).unwrap();
+  builder.finish().unwrap();
+
+  let target = TestStore::new();
+  let result = archive::handle_import(&target, &output);
+  assert!(matches!(result, Err(Error::Tampered { .. })));
+  assert!(target.list().unwrap().is_empty());
+}
@@ -0,0 +1,518 @@
+mod common;
+
+use common::TestStore;
+use hcriu::backend::FakeCall;
+use hcriu::detach::{Detach, Detached};
+use hcriu::utils::{CheckpointMeta, CheckpointStatus};
+use hcriu::{
+  Backend, CheckpointStore, DumpOptions, Error, FakeBackend, KeySource, dump, pack, page_server,
+  restore, sign, verify,
+};
+use std::os::unix::fs::PermissionsExt;
+
+#[test]
+fn fake_backend_dump_and_restore() {
+  let store = TestStore::new();
+  let dir = store.path();
+
+  // the fake backend does not touch the process, dumping ourselves is fine
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true).pre_dump(2);
+  dump::handle_dump(
+    &store,
+    &mut backend,
+    pid,
+    None,
+    Some("test".to_string()),
+    &options,
+  )
+  .unwrap();
+
+  let checkpoints = store.list().unwrap();
+  assert_eq!(checkpoints.len(), 1);
+  let meta = &checkpoints[0];
+  assert_eq!(meta.tag, "test");
+  assert_eq!(meta.options, options);
+  // the first pre-dump writes every page, later passes only half of them
+  assert_eq!(meta.pages_written, vec![8, 4, 4]);
+
+  restore::handle_restore(
+    &store,
+    &mut backend,
+    meta.checkpoint_id[..7].to_string(),
+    None,
+    false,
+    false,
+    None,
+  )
+  .unwrap();
+  assert_eq!(
+    backend.calls.last(),
+    Some(&FakeCall::Restore {
+      image_dir: dir.join(&meta.checkpoint_id).join("image"),
+    })
+  );
+}
+
+#[test]
+fn default_backend_takes_periodic_dumps() {
+  let store = TestStore::new();
+  // stands in for criu and records how it was called
+  let criu = store.path().join("criu");
+  let calls = store.path().join("criu-calls");
+  let script = format!("#!/bin/sh\necho \"$@\" >> {}\n", calls.display());
+  std::fs::write(&criu, script).unwrap();
+  std::fs::set_permissions(&criu, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+  let mut backend = Backend::default().open(criu.display().to_string()).unwrap();
+  let pid = std::process::id() as i32;
+  let tag = Some("web".to_string());
+  let options = DumpOptions::new();
+  let first = dump::dump_periodic(&store, backend.as_mut(), pid, &tag, &options, None).unwrap();
+  std::thread::sleep(std::time::Duration::from_millis(10));
+  let second =
+    dump::dump_periodic(&store, backend.as_mut(), pid, &tag, &options, Some(&first)).unwrap();
+  assert_eq!(second.parent_id, Some(first.checkpoint_id.clone()));
+
+  // the second round is incremental on top of the first
+  let calls = std::fs::read_to_string(&calls).unwrap();
+  let calls = calls.lines().collect::<Vec<_>>();
+  assert_eq!(calls.len(), 2);
+  assert!(
+    calls
+      .iter()
+      .all(|c| c.starts_with("dump ") && c.contains("--track-mem"))
+  );
+  assert!(!calls[0].contains("--prev-images-dir"));
+  let parent_img = format!("--prev-images-dir ../../{}/image", first.checkpoint_id);
+  assert!(calls[1].contains(&parent_img));
+}
+
+#[test]
+fn failed_dump_is_quarantined() {
+  let store = TestStore::new();
+  let dir = store.path();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  backend.fail = true;
+  let result = dump::handle_dump(
+    &store,
+    &mut backend,
+    pid,
+    None,
+    Some("test".to_string()),
+    &DumpOptions::new(),
+  );
+  assert!(result.is_err());
+  assert!(store.list().unwrap().is_empty());
+
+  // only the logs and metadata of the failed dump are left
+  let quarantined = std::fs::read_dir(dir.join(".quarantine"))
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .collect::<Vec<_>>();
+  assert_eq!(quarantined.len(), 1);
+  assert!(quarantined[0].join("dump.log").is_file());
+  let meta = CheckpointMeta::load(&quarantined[0].join("meta.toml")).unwrap();
+  assert_eq!(meta.status, CheckpointStatus::Failed);
+}
+
+#[test]
+fn restore_refuses_changed_images() {
+  let store = TestStore::new();
+  let dir = store.path();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  dump::handle_dump(
+    &store,
+    &mut backend,
+    pid,
+    None,
+    Some("test".to_string()),
+    &DumpOptions::new().leave_running(true),
+  )
+  .unwrap();
+  let meta = store.list().unwrap().remove(0);
+  verify::handle_verify(&store, None).unwrap();
+
+  let pages = dir
+    .join(&meta.checkpoint_id)
+    .join("image")
+    .join("pages-1.img");
+  std::fs::write(&pages, b"bit rot").unwrap();
+  assert!(matches!(
+    verify::handle_verify(&store, Some(&meta.checkpoint_id)),
+    Err(Error::VerifyFailed(1))
+  ));
+  let restore = |force| {
+    restore::handle_restore(
+      &store,
+      &mut FakeBackend::new(),
+      meta.checkpoint_id.clone(),
+      None,
+      force,
+      false,
+      None,
+    )
+  };
+  assert!(matches!(restore(false), Err(Error::Tampered { .. })));
+  restore(true).unwrap();
+}
+
+#[test]
+fn restore_refuses_moved_parent_link() {
+  let store = TestStore::new();
+  store.add("a1", "web", 1, 1, None);
+  let child = store.add("b2", "web", 1, 2, Some("a1"));
+  let other = store.add("c3", "web", 1, 3, None);
+
+  let link = store.checkpoint_dir(&child).join("image/parent");
+  std::fs::remove_file(&link).unwrap();
+  std::os::unix::fs::symlink(format!("../../{}/image", other), &link).unwrap();
+  let Err(Error::Tampered { mismatches, .. }) = restore::handle_restore(
+    &store,
+    &mut FakeBackend::new(),
+    child,
+    None,
+    false,
+    false,
+    None,
+  ) else {
+    panic!("a moved parent link restores other pages");
+  };
+  assert_eq!(mismatches, ["changed image/parent"]);
+}
+
+#[test]
+fn compressed_chain_restores() {
+  let store = TestStore::new();
+  let dir = store.path();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new()
+    .leave_running(true)
+    .pre_dump(1)
+    .compress(3);
+  dump::handle_dump(
+    &store,
+    &mut backend,
+    pid,
+    None,
+    Some("test".to_string()),
+    &options,
+  )
+  .unwrap();
+  let meta = store.list().unwrap().remove(0);
+  let image_dir = dir.join(&meta.checkpoint_id).join("image");
+  assert!(image_dir.join("pages-1.img.zst").is_file());
+  assert!(!image_dir.join("pages-1.img").exists());
+  verify::handle_verify(&store, None).unwrap();
+
+  // pages of one pid byte compress to almost nothing
+  let checkpoint_dir = store.checkpoint_dir(&meta.checkpoint_id);
+  let logical = pack::logical_size(&checkpoint_dir).unwrap();
+  assert!(logical > store.checkpoint_size(&meta.checkpoint_id).unwrap());
+
+  // the fake backend refuses image dirs without raw pages, like CRIU
+  restore::handle_restore(
+    &store,
+    &mut backend,
+    meta.checkpoint_id.clone(),
+    None,
+    false,
+    false,
+    None,
+  )
+  .unwrap();
+  let Some(FakeCall::Restore { image_dir: staged }) = backend.calls.last() else {
+    panic!("no restore call");
+  };
+  assert!(!staged.starts_with(dir));
+  assert!(!staged.exists());
+}
+
+#[test]
+fn encrypted_images_need_the_key() {
+  let fixture = TestStore::with_config("[encryption]\nenabled = true\n");
+  let dir = fixture.path();
+  let store = CheckpointStore::open(dir)
+    .unwrap()
+    .with_key(KeySource::Passphrase("secret".to_string()));
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new()
+    .leave_running(true)
+    .pre_dump(1)
+    .compress(3);
+  dump::handle_dump(&store, &mut backend, pid, None, None, &options).unwrap();
+  let meta = store.list().unwrap().remove(0);
+  let image_dir = dir.join(&meta.checkpoint_id).join("image");
+  assert!(image_dir.join("pages-1.img.zst.enc").is_file());
+  assert!(image_dir.join(format!("pagemap-{}.img.enc", pid)).is_file());
+  assert!(!image_dir.join("inventory.img").exists());
+
+  // metadata and manifests stay readable without the key
+  let locked = CheckpointStore::open(dir).unwrap();
+  assert_eq!(locked.list().unwrap().len(), 1);
+  verify::handle_verify(&locked, None).unwrap();
+
+  let wrong = CheckpointStore::open(dir)
+    .unwrap()
+    .with_key(KeySource::Passphrase("guess".to_string()));
+  let id = meta.checkpoint_id.clone();
+  let result = restore::handle_restore(&wrong, &mut backend, id.clone(), None, false, false, None);
+  assert!(matches!(result, Err(Error::WrongKey)));
+
+  restore::handle_restore(&store, &mut backend, id, None, false, false, None).unwrap();
+  let Some(FakeCall::Restore { image_dir: staged }) = backend.calls.last() else {
+    panic!("no restore call");
+  };
+  assert!(!staged.starts_with(dir));
+  assert!(!staged.exists());
+}
+
+#[test]
+fn dedup_shares_chunks_between_dumps() {
+  let store = TestStore::with_config("[dedup]\nenabled = true\n");
+  let count_chunks = || {
+    std::fs::read_dir(store.chunks_dir()).map_or(0, |dirs| {
+      dirs
+        .flatten()
+        .map(|d| std::fs::read_dir(d.path()).unwrap().count())
+        .sum::<usize>()
+    })
+  };
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true);
+  dump::handle_dump(&store, &mut backend, pid, None, None, &options).unwrap();
+  let chunks = count_chunks();
+  assert!(chunks > 0);
+  // the same memory again adds no chunk
+  std::thread::sleep(std::time::Duration::from_millis(10));
+  dump::handle_dump(&store, &mut backend, pid, None, None, &options).unwrap();
+  assert_eq!(count_chunks(), chunks);
+
+  let mut checkpoints = store.list().unwrap();
+  checkpoints.sort_by(|a, b| a.dump_time.cmp(&b.dump_time));
+  let image_dir = store
+    .checkpoint_dir(&checkpoints[1].checkpoint_id)
+    .join("image");
+  assert!(image_dir.join("pages-1.img.chunks").is_file());
+
+  // chunks still in use survive a delete
+  store.delete(&checkpoints[0].checkpoint_id).unwrap();
+  assert_eq!(count_chunks(), chunks);
+  let id = checkpoints[1].checkpoint_id.clone();
+  restore::handle_restore(&store, &mut backend, id.clone(), None, false, false, None).unwrap();
+
+  // entries name files under the chunk dir, anything but a hash is refused
+  let list = image_dir.join("pages-1.img.chunks");
+  let good = std::fs::read_to_string(&list).unwrap();
+  std::fs::write(&list, "size = 4096\nchunks = [\"a\"]\n").unwrap();
+  assert!(matches!(
+    restore::handle_restore(&store, &mut backend, id.clone(), None, true, false, None),
+    Err(Error::CorruptMeta { .. })
+  ));
+  std::fs::write(&list, good).unwrap();
+
+  store.delete(&id).unwrap();
+  assert_eq!(count_chunks(), 0);
+}
+
+#[test]
+fn restore_checks_signatures() {
+  let store = TestStore::new();
+  let dir = store.path();
+  let config = format!(
+    "[signing]\nunsigned = \"deny\"\nkey_file = \"{}\"\n",
+    dir.join("signing.key").display()
+  );
+  std::fs::write(store.config_path(), config).unwrap();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true).sign(true);
+  dump::handle_dump(&store, &mut backend, pid, None, None, &options).unwrap();
+  let meta = store.list().unwrap().remove(0);
+  let id = meta.checkpoint_id.clone();
+  let mut restore = |store: &CheckpointStore| {
+    restore::handle_restore(store, &mut backend, id.clone(), None, false, false, None)
+  };
+
+  // signed, but by no trusted key yet
+  assert!(matches!(restore(&store), Err(Error::Untrusted { .. })));
+  sign::handle_trust(&store, None).unwrap();
+  restore(&store).unwrap();
+
+  // the command restore would replay is covered by the signature
+  let mut forged = meta.clone();
+  forged.cmd = "sh -c evil".to_string();
+  forged.save(&store.meta_path(&id)).unwrap();
+  assert!(matches!(restore(&store), Err(Error::BadSignature(_))));
+}
+
+#[test]
+fn stream_dump_restores_from_stream() {
+  let store = TestStore::new();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true).pre_dump(1);
+  let mut stream = Vec::new();
+  dump::handle_dump_stream(&store, &mut backend, pid, None, &options, &mut stream).unwrap();
+  // nothing lands in the store
+  assert!(store.list().unwrap().is_empty());
+
+  // piped through zstd on the way
+  let compressed = zstd::encode_all(stream.as_slice(), 3).unwrap();
+  restore::handle_restore_stream(
+    &store,
+    &mut backend,
+    &mut compressed.as_slice(),
+    None,
+    false,
+    false,
+    None,
+  )
+  .unwrap();
+  let Some(FakeCall::Restore { image_dir }) = backend.calls.last() else {
+    panic!("no restore call");
+  };
+  assert!(!image_dir.exists());
+
+  // a truncated stream is refused
+  let mut truncated = &stream[..stream.len() / 2];
+  assert!(
+    restore::handle_restore_stream(
+      &store,
+      &mut backend,
+      &mut truncated,
+      None,
+      false,
+      false,
+      None
+    )
+    .is_err()
+  );
+}
+
+#[test]
+fn dump_to_page_server_over_loopback() {
+  let server_store = TestStore::new();
+  let dir = server_store.path();
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap().to_string();
+  let server_dir = dir.to_path_buf();
+  let server = std::thread::spawn(move || {
+    let store = CheckpointStore::open(&server_dir).unwrap();
+    let mut backend = FakeBackend::new();
+    let meta = page_server::serve_dump(&store, &mut backend, &listener).unwrap();
+    (meta, backend.calls)
+  });
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true);
+  dump::handle_dump_remote(&mut backend, pid, None, &options, &address).unwrap();
+  let (meta, calls) = server.join().unwrap();
+  assert!(matches!(calls[..], [FakeCall::PageServer { .. }]));
+
+  // the pages came through the page server, the rest with the archive
+  let store = CheckpointStore::open(dir).unwrap();
+  let image_dir = store.checkpoint_dir(&meta.checkpoint_id).join("image");
+  assert!(image_dir.join("pages-1.img").is_file());
+  assert!(image_dir.join(format!("pagemap-{}.img", pid)).is_file());
+  assert_eq!(store.get(&meta.checkpoint_id).unwrap().pid, pid);
+  restore::handle_restore(
+    &store,
+    &mut backend,
+    meta.checkpoint_id,
+    None,
+    false,
+    false,
+    None,
+  )
+  .unwrap();
+}
+
+#[test]
+fn lazy_restore_starts_lazy_pages_first() {
+  let store = TestStore::new();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true).compress(3);
+  dump::handle_dump(&store, &mut backend, pid, None, None, &options).unwrap();
+  let id = store.list().unwrap().remove(0).checkpoint_id;
+  restore::handle_restore(&store, &mut backend, id.clone(), None, false, true, None).unwrap();
+
+  // the daemon serves the same staged images the restore reads
+  let [
+    ..,
+    FakeCall::LazyPages { image_dir: served },
+    FakeCall::Restore { image_dir },
+  ] = &backend.calls[..]
+  else {
+    panic!("no lazy restore: {:?}", backend.calls);
+  };
+  assert_eq!(served, image_dir);
+  // the daemon ran in a run dir that is gone, the checkpoint is untouched
+  assert!(!store.checkpoint_dir(&id).join("lazy-pages.log").exists());
+  assert_eq!(lazy_run_dirs(&store), 0);
+
+  // a failed one keeps its logs
+  backend.fail = true;
+  assert!(restore::handle_restore(&store, &mut backend, id, None, false, true, None).is_err());
+  assert_eq!(lazy_run_dirs(&store), 1);
+}
+
+fn lazy_run_dirs(store: &CheckpointStore) -> usize {
+  std::fs::read_dir(store.root())
+    .unwrap()
+    .filter(|e| {
+      let name = e.as_ref().unwrap().file_name();
+      name.to_string_lossy().starts_with(".lazy-restore-")
+    })
+    .count()
+}
+
+#[test]
+fn detached_restore_keeps_the_terminal() {
+  let store = TestStore::new();
+
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true);
+  dump::handle_dump(&store, &mut backend, pid, None, None, &options).unwrap();
+  let id = store.list().unwrap().remove(0).checkpoint_id;
+  let mode = Some(Detach::Pty);
+  restore::handle_restore(&store, &mut backend, id.clone(), None, false, false, mode).unwrap();
+
+  let detached = Detached::load(&store, pid).unwrap();
+  assert_eq!(detached.checkpoint_id, id);
+  assert!(detached.tty.starts_with("/dev/pts"));
+  assert!(detached.input.unwrap().exists());
+  // the holder copies what the fake process printed, then exits with it
+  let output = detached.output.unwrap();
+  let started = std::time::Instant::now();
+  while !std::fs::read_to_string(&output)
+    .unwrap()
+    .contains("fake process")
+  {
+    assert!(
+      started.elapsed().as_secs() < 10,
+      "no output in {}",
+      output.display()
+    );
+    std::thread::sleep(std::time::Duration::from_millis(10));
+  }
+  // the run dir is not taken for a checkpoint
+  assert_eq!(store.list().unwrap().len(), 1);
+}
@@ -0,0 +1,86 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+use hcriu::backend::DumpRequest;
+use hcriu::manifest::Manifest;
+use hcriu::utils::CheckpointMeta;
+use hcriu::{CheckpointStore, CriuBackend, DumpOptions, FakeBackend};
+use std::ops::Deref;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// A store in a temporary dir, removed on drop even when the test fails.
+pub struct TestStore {
+  store: CheckpointStore,
+  dir: TempDir,
+}
+
+impl TestStore {
+  pub fn new() -> Self {
+    let dir = tempfile::Builder::new()
+      .prefix("hcriu-test-")
+      .tempdir()
+      .unwrap();
+    let store = CheckpointStore::create(dir.path()).unwrap();
+    TestStore { store, dir }
+  }
+
+  pub fn with_config(config: &str) -> Self {
+    let store = Self::new();
+    std::fs::write(store.config_path(), config).unwrap();
+    store
+  }
+
+  pub fn path(&self) -> &Path {
+    self.dir.path()
+  }
+
+  /// Write a checkpoint with fake images and a manifest, dumped at `hour` on
+  /// 2025-05-07. `id` is padded to look like a hash.
+  pub fn add(&self, id: &str, tag: &str, pid: i32, hour: u32, parent: Option<&str>) -> String {
+    let checkpoint_id = format!("{:0<64}", id);
+    let parent_id = parent.map(|p| format!("{:0<64}", p));
+    let checkpoint_dir = self.checkpoint_dir(&checkpoint_id);
+    let image_dir = checkpoint_dir.join("image");
+    std::fs::create_dir_all(&image_dir).unwrap();
+
+    let parent_img = parent_id.as_ref().map(|p| format!("../../{}/image", p));
+    FakeBackend::new()
+      .dump(&DumpRequest {
+        pid,
+        work_dir: &checkpoint_dir,
+        image_dir: &image_dir,
+        parent_img: parent_img.as_deref(),
+        log_file: "dump.log",
+        leave_running: true,
+        track_mem: true,
+        page_server: None,
+        options: &DumpOptions::default(),
+      })
+      .unwrap();
+    Manifest::build(&checkpoint_dir)
+      .unwrap()
+      .save(&checkpoint_dir)
+      .unwrap();
+
+    let meta = CheckpointMeta {
+      checkpoint_id: checkpoint_id.clone(),
+      pid,
+      cmd: "sleep 1000".to_string(),
+      tag: tag.to_string(),
+      dump_time: format!("2025-05-07 {:02}:00:00.000000000 UTC", hour),
+      parent_id,
+      ..Default::default()
+    };
+    meta.save(&self.meta_path(&checkpoint_id)).unwrap();
+    checkpoint_id
+  }
+}
+
+impl Deref for TestStore {
+  type Target = CheckpointStore;
+
+  fn deref(&self) -> &CheckpointStore {
+    &self.store
+  }
+}
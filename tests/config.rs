@@ -0,0 +1,45 @@
+mod common;
+
+use common::TestStore;
+use hcriu::{RetentionPolicy, StoreConfig, Timezone};
+use std::time::Duration;
+
+#[test]
+fn retention_by_tag_pattern() {
+  let store = TestStore::new();
+  assert_eq!(store.config().unwrap(), StoreConfig::default());
+
+  std::fs::write(
+    store.config_path(),
+    r#"
+[[retention]]
+tag = "db-prod"
+keep_last = 3
+squash = false
+
+[[retention]]
+tag = "db-*"
+keep_hourly = 24
+keep_within = "2d"
+timezone = "utc"
+"#,
+  )
+  .unwrap();
+  let config = store.config().unwrap();
+
+  let exact = config.retention_for("db-prod").unwrap();
+  assert_eq!(exact.policy, RetentionPolicy::new().keep_last(3));
+  assert!(!exact.squash);
+
+  let pattern = config.retention_for("db-staging").unwrap();
+  assert_eq!(
+    pattern.policy,
+    RetentionPolicy::new()
+      .keep_hourly(24)
+      .keep_within(Duration::from_secs(2 * 24 * 3600))
+      .timezone(Timezone::Utc)
+  );
+  assert!(pattern.squash);
+
+  assert!(config.retention_for("web").is_none());
+}
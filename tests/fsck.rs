@@ -0,0 +1,96 @@
+mod common;
+
+use common::TestStore;
+use hcriu::fsck::{self, Problem};
+use hcriu::{DumpOptions, FakeBackend, Sort, dump, list};
+use std::path::Path;
+
+fn copy_images(from: &Path, to: &Path) {
+  std::fs::create_dir_all(to.join("image")).unwrap();
+  for entry in std::fs::read_dir(from.join("image")).unwrap() {
+    let path = entry.unwrap().path();
+    std::fs::copy(&path, to.join("image").join(path.file_name().unwrap())).unwrap();
+  }
+}
+
+#[test]
+fn fsck_finds_and_repairs_problems() {
+  let store = TestStore::new();
+  let dir = store.path();
+
+  let pid = std::process::id() as i32;
+  dump::handle_dump(
+    &store,
+    &mut FakeBackend::new(),
+    pid,
+    None,
+    Some("test".to_string()),
+    &DumpOptions::new().leave_running(true),
+  )
+  .unwrap();
+  let good = store.list().unwrap().remove(0);
+  let good_dir = store.checkpoint_dir(&good.checkpoint_id);
+  assert!(fsck::check_store(&store).unwrap().is_empty());
+
+  // images whose metadata got mangled
+  let corrupt_id = "f".repeat(64);
+  copy_images(&good_dir, &store.checkpoint_dir(&corrupt_id));
+  std::fs::write(store.meta_path(&corrupt_id), "not = [toml").unwrap();
+
+  // a child of a checkpoint that is gone
+  let child_id = "e".repeat(64);
+  copy_images(&good_dir, &store.checkpoint_dir(&child_id));
+  let mut child = good.clone();
+  child.checkpoint_id = child_id.clone();
+  child.parent_id = Some("d".repeat(64));
+  child.save(&store.meta_path(&child_id)).unwrap();
+
+  std::fs::write(dir.join("stray.txt"), "").unwrap();
+  let staging_dir = store.staging_dir(&"c".repeat(64));
+  std::fs::create_dir_all(&staging_dir).unwrap();
+
+  // listing skips what it can't read
+  let ids = store
+    .list()
+    .unwrap()
+    .into_iter()
+    .map(|c| c.checkpoint_id)
+    .collect::<Vec<_>>();
+  assert_eq!(ids.len(), 2);
+  assert!(!ids.contains(&corrupt_id));
+
+  let problems = fsck::check_store(&store).unwrap();
+  assert_eq!(problems.len(), 4);
+  assert!(problems.contains(&Problem::HalfWritten(staging_dir)));
+  assert!(problems.contains(&Problem::Orphan(dir.join("stray.txt"))));
+  assert!(
+    problems
+      .iter()
+      .any(|p| matches!(p, Problem::CorruptMeta { .. }))
+  );
+  assert!(problems.contains(&Problem::BrokenChain {
+    checkpoint_id: child_id.clone(),
+    parent_id: "d".repeat(64),
+    path: store.checkpoint_dir(&child_id),
+  }));
+
+  fsck::handle_fsck(&store, true).unwrap();
+  assert!(fsck::check_store(&store).unwrap().is_empty());
+  let recovered = store.get(&corrupt_id).unwrap();
+  assert_eq!(recovered.tag, fsck::RECOVERED_TAG);
+  assert_eq!(recovered.pid, pid);
+  assert!(store.get(&child_id).is_err());
+  assert!(dir.join(".quarantine").join(&child_id).is_dir());
+}
+
+#[test]
+fn list_shows_damaged_checkpoints_without_a_size() {
+  let store = TestStore::new();
+  let damaged = store.add("a", "test", 1, 1, None);
+  store.add("b", "test", 1, 2, None);
+  let image_dir = store.checkpoint_dir(&damaged).join("image");
+  std::fs::write(image_dir.join("pages-1.img.chunks"), "not a chunk list").unwrap();
+
+  list::handle_list(&store, Sort::Time).unwrap();
+  list::handle_inspect(&store, &damaged).unwrap();
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use common::TestStore;
+use hcriu::merge::MergeOptions;
+use hcriu::{CheckpointStore, Error, RetentionPolicy, merge};
+
+fn ids(store: &CheckpointStore) -> Vec<String> {
+  let mut ids = store
+    .list()
+    .unwrap()
+    .into_iter()
+    .map(|c| c.checkpoint_id[..3].to_string())
+    .collect::<Vec<_>>();
+  ids.sort();
+  ids
+}
+
+fn run_merge(
+  store: &CheckpointStore,
+  tag: &str,
+  pid: Option<i32>,
+  policy: RetentionPolicy,
+  options: MergeOptions,
+) {
+  merge::handle_merge(store, tag, pid, &policy, &options.yes(true)).unwrap();
+}
+
+#[test]
+fn other_tags_are_untouched() {
+  let store = TestStore::new();
+  store.add("f01", "foo", 10, 1, None);
+  store.add("f02", "foo", 10, 2, None);
+  store.add("f03", "foo", 10, 3, None);
+  store.add("b01", "bar", 10, 1, None);
+  store.add("b02", "bar", 10, 4, None);
+
+  run_merge(
+    &store,
+    "foo",
+    None,
+    RetentionPolicy::new(),
+    MergeOptions::new(),
+  );
+  assert_eq!(ids(&store), ["b01", "b02", "f03"]);
+}
+
+#[test]
+fn other_pids_are_untouched() {
+  let store = TestStore::new();
+  store.add("a01", "foo", 10, 1, None);
+  store.add("a02", "foo", 10, 2, None);
+  store.add("b01", "foo", 20, 1, None);
+  store.add("b02", "foo", 20, 3, None);
+
+  run_merge(
+    &store,
+    "foo",
+    Some(10),
+    RetentionPolicy::new(),
+    MergeOptions::new(),
+  );
+  assert_eq!(ids(&store), ["a02", "b01", "b02"]);
+}
+
+#[test]
+fn dry_run_deletes_nothing() {
+  let store = TestStore::new();
+  store.add("f01", "foo", 10, 1, None);
+  store.add("f02", "foo", 10, 2, None);
+
+  let options = MergeOptions::new().dry_run(true);
+  merge::handle_merge(&store, "foo", None, &RetentionPolicy::new(), &options).unwrap();
+  assert_eq!(ids(&store), ["f01", "f02"]);
+}
+
+#[test]
+fn unmatched_filter_is_an_error() {
+  let store = TestStore::new();
+  store.add("b01", "bar", 10, 1, None);
+
+  let options = MergeOptions::new().yes(true);
+  let result = merge::handle_merge(&store, "foo", None, &RetentionPolicy::new(), &options);
+  assert!(matches!(result, Err(Error::NothingToMerge)));
+  assert_eq!(ids(&store), ["b01"]);
+}
+
+#[test]
+fn parent_of_other_tag_is_kept() {
+  let store = TestStore::new();
+  store.add("f01", "foo", 10, 1, None);
+  store.add("b01", "bar", 10, 2, Some("f01"));
+  store.add("f02", "foo", 10, 3, None);
+
+  // even squashing must not rewrite a checkpoint outside the filter
+  run_merge(
+    &store,
+    "foo",
+    None,
+    RetentionPolicy::new(),
+    MergeOptions::new().squash(true),
+  );
+  assert_eq!(ids(&store), ["b01", "f01", "f02"]);
+  let child = store.list().unwrap();
+  let child = child.iter().find(|c| c.tag == "bar").unwrap();
+  assert!(child.parent_id.as_ref().unwrap().starts_with("f01"));
+}
+
+#[test]
+fn squash_stays_within_chain() {
+  let store = TestStore::new();
+  store.add("f01", "foo", 10, 1, None);
+  store.add("f02", "foo", 10, 2, Some("f01"));
+  let latest = store.add("f03", "foo", 10, 3, Some("f02"));
+  store.add("b01", "bar", 10, 1, None);
+
+  run_merge(
+    &store,
+    "foo",
+    None,
+    RetentionPolicy::new(),
+    MergeOptions::new().squash(true),
+  );
+  assert_eq!(ids(&store), ["b01", "f03"]);
+  let meta = store.get(&latest).unwrap();
+  assert_eq!(meta.parent_id, None);
+  assert!(!store.checkpoint_dir(&latest).join("image/parent").exists());
+}
+
+#[test]
+fn squash_refuses_tampered_parent() {
+  let store = TestStore::new();
+  store.add("f01", "foo", 10, 1, None);
+  let parent = store.add("f02", "foo", 10, 2, Some("f01"));
+  store.add("f03", "foo", 10, 3, Some("f02"));
+  let pages = store.checkpoint_dir(&parent).join("image/pages-1.img");
+  std::fs::write(&pages, "tampered").unwrap();
+
+  // folding the pages in would hash and sign them as the child's own
+  let options = MergeOptions::new().squash(true).yes(true);
+  assert!(matches!(
+    merge::handle_merge(&store, "foo", None, &RetentionPolicy::new(), &options),
+    Err(Error::Tampered { .. })
+  ));
+  assert_eq!(ids(&store), ["f01", "f02", "f03"]);
+}
+
+#[test]
+fn pinned_checkpoints_need_force() {
+  let store = TestStore::new();
+  store.add("f01", "foo", 10, 1, None);
+  store.add("f02", "foo", 10, 2, None);
+  store.add("f03", "foo", 10, 3, None);
+  hcriu::pin::handle_pin(&store, "f0100", true).unwrap();
+
+  run_merge(
+    &store,
+    "foo",
+    None,
+    RetentionPolicy::new(),
+    MergeOptions::new(),
+  );
+  assert_eq!(ids(&store), ["f01", "f03"]);
+
+  run_merge(
+    &store,
+    "foo",
+    None,
+    RetentionPolicy::new(),
+    MergeOptions::new().force(true),
+  );
+  assert_eq!(ids(&store), ["f03"]);
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::TestStore;
+use hcriu::{CheckpointStore, DumpOptions, Error, FakeBackend, dump, restore};
+
+fn dump(store: &CheckpointStore, tag: &str) -> hcriu::Result<()> {
+  let pid = std::process::id() as i32;
+  let options = DumpOptions::new().leave_running(true);
+  dump::handle_dump(
+    store,
+    &mut FakeBackend::new(),
+    pid,
+    None,
+    Some(tag.to_string()),
+    &options,
+  )
+}
+
+fn oldest_first(store: &CheckpointStore) -> Vec<String> {
+  let mut checkpoints = store.list().unwrap();
+  checkpoints.sort_by_key(|c| c.timestamp().unwrap());
+  checkpoints.into_iter().map(|c| c.checkpoint_id).collect()
+}
+
+#[test]
+fn evicts_oldest_to_stay_under_count() {
+  let store = TestStore::with_config("[quota]\nmax_count = 2\n");
+  dump(&store, "a").unwrap();
+  dump(&store, "a").unwrap();
+  let before = oldest_first(&store);
+
+  dump(&store, "a").unwrap();
+  let after = oldest_first(&store);
+  assert_eq!(after.len(), 2);
+  assert_eq!(after[0], before[1]);
+}
+
+#[test]
+fn tag_quota_only_counts_its_tag() {
+  let config = "[[tag_quota]]\ntag = \"a*\"\nmax_count = 1\non_exceed = \"refuse\"\n";
+  let store = TestStore::with_config(config);
+  dump(&store, "b").unwrap();
+  dump(&store, "b").unwrap();
+  dump(&store, "a1").unwrap();
+
+  let result = dump(&store, "a2");
+  assert!(matches!(result, Err(Error::QuotaExceeded(_))));
+  assert_eq!(store.list().unwrap().len(), 3);
+}
+
+#[test]
+fn periodic_dump_prunes_its_tag() {
+  let config = "[[retention]]\ntag = \"a\"\nkeep_last = 2\nsquash = true\n";
+  let store = TestStore::with_config(config);
+  dump(&store, "b").unwrap();
+  let other = oldest_first(&store);
+
+  let pid = std::process::id() as i32;
+  let tag = Some("a".to_string());
+  let mut parent = None;
+  let mut dumped = Vec::new();
+  for _ in 0..4 {
+    let meta = dump::dump_periodic(
+      &store,
+      &mut FakeBackend::new(),
+      pid,
+      &tag,
+      &DumpOptions::new(),
+      parent.as_ref(),
+    )
+    .unwrap();
+    dumped.push(meta.checkpoint_id.clone());
+    parent = Some(meta);
+  }
+
+  // only the last two of the tag are left, the other tag is untouched
+  let kept = oldest_first(&store);
+  assert_eq!(kept, [other, dumped[2..].to_vec()].concat());
+  assert_eq!(store.get(&kept[1]).unwrap().parent_id, None);
+  for id in &kept {
+    restore::handle_restore(
+      &store,
+      &mut FakeBackend::new(),
+      id.clone(),
+      None,
+      false,
+      false,
+      None,
+    )
+    .unwrap();
+  }
+}
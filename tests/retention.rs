@@ -0,0 +1,81 @@
+use hcriu::retention::KeepReason;
+use hcriu::utils::CheckpointMeta;
+use hcriu::{RetentionPolicy, Timezone};
+use std::time::Duration;
+
+fn checkpoint(id: &str, dump_time: &str) -> CheckpointMeta {
+  CheckpointMeta {
+    checkpoint_id: id.to_string(),
+    pid: 1,
+    cmd: "sleep".to_string(),
+    tag: "test".to_string(),
+    dump_time: format!("{}.000000000 UTC", dump_time),
+    ..Default::default()
+  }
+}
+
+fn series() -> Vec<CheckpointMeta> {
+  vec![
+    checkpoint("a", "2025-04-28 09:00:00"),
+    checkpoint("b", "2025-05-05 09:00:00"),
+    checkpoint("c", "2025-05-06 09:00:00"),
+    checkpoint("d", "2025-05-06 23:30:00"),
+    checkpoint("e", "2025-05-07 08:10:00"),
+    checkpoint("f", "2025-05-07 08:50:00"),
+    checkpoint("g", "2025-05-07 09:20:00"),
+  ]
+}
+
+fn kept(policy: &RetentionPolicy, checkpoints: &[CheckpointMeta]) -> Vec<String> {
+  let reasons = policy
+    .apply(&checkpoints.iter().collect::<Vec<_>>())
+    .unwrap();
+  let mut ids = reasons.into_keys().collect::<Vec<_>>();
+  ids.sort();
+  ids
+}
+
+#[test]
+fn calendar_rules_keep_latest_of_each_bucket() {
+  let checkpoints = series();
+  let policy = RetentionPolicy::new().timezone(Timezone::Utc);
+  assert_eq!(kept(&policy.clone().keep_last(2), &checkpoints), ["f", "g"]);
+  assert_eq!(
+    kept(&policy.clone().keep_hourly(2), &checkpoints),
+    ["f", "g"]
+  );
+  assert_eq!(
+    kept(&policy.clone().keep_daily(3), &checkpoints),
+    ["b", "d", "g"]
+  );
+  // 2025-05-05 starts an ISO week
+  assert_eq!(
+    kept(&policy.clone().keep_weekly(5), &checkpoints),
+    ["a", "g"]
+  );
+  assert_eq!(
+    kept(&policy.clone().keep_monthly(5), &checkpoints),
+    ["a", "g"]
+  );
+}
+
+#[test]
+fn keep_within_is_relative_to_latest() {
+  let checkpoints = series();
+  let policy = RetentionPolicy::new().keep_within(Duration::from_secs(12 * 3600));
+  assert_eq!(kept(&policy, &checkpoints), ["d", "e", "f", "g"]);
+}
+
+#[test]
+fn reasons_accumulate() {
+  let checkpoints = series();
+  let policy = RetentionPolicy::new()
+    .keep_last(1)
+    .keep_daily(1)
+    .timezone(Timezone::Utc);
+  let reasons = policy
+    .apply(&checkpoints.iter().collect::<Vec<_>>())
+    .unwrap();
+  assert_eq!(reasons["g"], [KeepReason::Last, KeepReason::Daily]);
+  assert_eq!(reasons.len(), 1);
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::TestStore;
+use hcriu::{CheckpointStore, Error, revision};
+
+fn resolve(store: &CheckpointStore, revision: &str) -> String {
+  let id = revision::resolve(store, revision).unwrap().checkpoint_id;
+  id.trim_end_matches('0').to_string()
+}
+
+#[test]
+fn resolves_series_and_ids() {
+  let store = TestStore::new();
+  store.add("a1", "web", 1, 1, None);
+  store.add("b2", "web", 1, 3, Some("a1"));
+  store.add("c3", "web", 2, 2, None);
+  store.add("d4", "db", 2, 4, None);
+
+  assert_eq!(resolve(&store, "web"), "b2");
+  assert_eq!(resolve(&store, "web@latest"), "b2");
+  assert_eq!(resolve(&store, "web~1"), "c3");
+  assert_eq!(resolve(&store, "web~2"), "a1");
+  assert_eq!(resolve(&store, "pid:2@latest"), "d4");
+  assert_eq!(resolve(&store, "pid:1~"), "a1");
+  assert_eq!(resolve(&store, "@latest"), "d4");
+  assert_eq!(resolve(&store, "@{1 hour ago}"), "d4");
+  assert_eq!(resolve(&store, "@{2025-05-07 02:30:00}"), "c3");
+  assert_eq!(resolve(&store, "web@{2025-05-07 02:30:00}~1"), "a1");
+  assert_eq!(resolve(&store, "b200"), "b2");
+  assert_eq!(resolve(&store, "b200~1"), "a1");
+
+  assert!(matches!(
+    revision::resolve(&store, "web~3"),
+    Err(Error::CheckpointNotFound(_))
+  ));
+  assert!(matches!(
+    revision::resolve(&store, "a100~1"),
+    Err(Error::CheckpointNotFound(_))
+  ));
+  assert!(matches!(
+    revision::resolve(&store, "b2"),
+    Err(Error::PrefixTooShort(_))
+  ));
+  assert!(matches!(
+    revision::resolve(&store, "web@{yesterday}"),
+    Err(Error::InvalidRevision { .. })
+  ));
+  assert!(matches!(
+    revision::resolve(&store, "pid:web"),
+    Err(Error::InvalidRevision { .. })
+  ));
+}
+
+#[test]
+fn ambiguous_names_list_candidates() {
+  let store = TestStore::new();
+  store.add("cafe1", "web", 1, 1, None);
+  store.add("cafe2", "web", 1, 2, None);
+  store.add("beef", "cafe", 2, 3, None);
+
+  let Err(error) = revision::resolve(&store, "cafe") else {
+    panic!("'cafe' is a tag and an id prefix");
+  };
+  let Error::AmbiguousRevision { candidates, .. } = &error else {
+    panic!("unexpected error {}", error);
+  };
+  assert_eq!(candidates.len(), 3);
+  assert!(error.to_string().contains("2025-05-07 03:00:00"));
+
+  // a selector makes it a tag
+  assert_eq!(resolve(&store, "cafe@latest"), "beef");
+  assert_eq!(resolve(&store, "cafe1"), "cafe1");
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::TestStore;
+use hcriu::{CheckpointStore, DumpOptions, Error, FakeBackend, dump};
+
+#[test]
+fn stores_are_independent() {
+  let hot = TestStore::new();
+  let archive = TestStore::new();
+  let pid = std::process::id() as i32;
+  let mut backend = FakeBackend::new();
+  let options = DumpOptions::new().leave_running(true);
+  dump::handle_dump(&hot, &mut backend, pid, None, None, &options).unwrap();
+
+  assert_eq!(hot.list().unwrap().len(), 1);
+  assert!(archive.list().unwrap().is_empty());
+
+  // the lock file next to the checkpoints is not listed
+  drop(hot.lock().unwrap());
+  let id = hot.list().unwrap()[0].checkpoint_id.clone();
+  assert_eq!(hot.get_by_prefix(&id[..8]).unwrap().checkpoint_id, id);
+  assert!(matches!(
+    hot.get_by_prefix("abc"),
+    Err(Error::PrefixTooShort(_))
+  ));
+  assert!(matches!(
+    archive.get_by_prefix(&id[..8]),
+    Err(Error::CheckpointNotFound(_))
+  ));
+
+  hot.delete(&id).unwrap();
+  assert!(hot.list().unwrap().is_empty());
+  assert!(matches!(hot.delete(&id), Err(Error::CheckpointNotFound(_))));
+}
+
+#[test]
+fn open_requires_existing_dir() {
+  let store = TestStore::new();
+  let dir = store.path().join("missing");
+  assert!(matches!(
+    CheckpointStore::open(&dir),
+    Err(Error::StoreNotFound(_))
+  ));
+}